@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS import_jobs
+(
+    file_path TEXT    NOT NULL PRIMARY KEY,
+    state     TEXT    NOT NULL,
+    song_id   INTEGER NULLABLE,
+    reason    TEXT NULLABLE
+);
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS fingerprint_profile
+(
+    id      INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+    profile TEXT    NOT NULL
+);
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE IF NOT EXISTS triplet_fingerprints
+(
+    hash           INTEGER NOT NULL,
+    anchor_time_ms INTEGER NOT NULL,
+    span_ms        INTEGER NOT NULL,
+    song_id        INTEGER NOT NULL,
+    PRIMARY KEY (hash, anchor_time_ms, span_ms, song_id)
+);
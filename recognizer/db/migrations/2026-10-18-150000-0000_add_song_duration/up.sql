@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE songs ADD COLUMN duration_ms INTEGER NULL;
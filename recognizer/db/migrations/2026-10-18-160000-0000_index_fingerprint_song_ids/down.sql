@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS triplet_fingerprints_song_id;
+DROP INDEX IF EXISTS fingerprints_song_id;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- A song's fingerprints are deleted by song ID when a failed import is retried; the primary keys
+-- lead with the hash, so they cannot be used to find them
+CREATE INDEX IF NOT EXISTS fingerprints_song_id ON fingerprints (song_id);
+CREATE INDEX IF NOT EXISTS triplet_fingerprints_song_id ON triplet_fingerprints (song_id);
@@ -2,21 +2,38 @@
 file: /src/db/db_utils.rs
 provides common functions to interact with the database of songs
 */
-use crate::db::models::{Fingerprint, NewFingerprint, NewSong, Song};
+use crate::db::models::{
+    Fingerprint, NewFingerprint, NewSong, NewTripletFingerprint, TripletFingerprint,
+};
 use crate::recognizer::declarations::{
     DATABASE_INSERT_ERROR, DATABASE_QUERY_ERROR, NO_SONG_MATCH_ERROR,
 };
 use crate::recognizer::fingerprint::KeyAudioPoint;
+use crate::recognizer::triplet::TripletPoint;
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
 use dotenvy::dotenv;
 use std::collections::HashMap;
 use std::env;
+use std::sync::Once;
 
-/// Store all the values in the HashMap into the database's Fingerprints table based on its hash.
-pub fn store_fingerprints(fingerprint_map: HashMap<u32, KeyAudioPoint>) -> Result<(), u8> {
-    use crate::db::schema::fingerprints;
+pub use crate::db::models::Song;
 
-    let connection = &mut establish_connection();
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("db/migrations");
+static RUN_MIGRATIONS: Once = Once::new();
+
+// Rows inserted, or hashes or song IDs looked up, per statement. Each takes a handful of bound
+// parameters, well within SQLite's limit per statement.
+const BATCH_SIZE: usize = 1000;
+
+// Store all the values in the HashMap into the database's Fingerprints table based on its hash,
+// using an existing connection so callers can group the inserts in a transaction.
+pub(crate) fn insert_fingerprints(
+    connection: &mut SqliteConnection,
+    fingerprint_map: HashMap<u32, KeyAudioPoint>,
+) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::fingerprints;
 
     for (hash, point) in fingerprint_map {
         let (anchor_time_ms, song_id) = (point.anchor_time_ms, point.song_id);
@@ -27,18 +44,99 @@ pub fn store_fingerprints(fingerprint_map: HashMap<u32, KeyAudioPoint>) -> Resul
             song_id,
         };
 
-        match diesel::insert_into(fingerprints::table)
+        diesel::insert_into(fingerprints::table)
             .values(&new_fingerprint)
-            .execute(connection)
+            .execute(connection)?;
+    }
+
+    Ok(())
+}
+
+// Store every triplet hash into the database's Triplet Fingerprints table, using an existing
+// connection like `insert_fingerprints`. A recording can repeat a triplet at the same time and
+// span (e.g. in both channels); the repeats are stored once.
+pub(crate) fn insert_triplet_fingerprints(
+    connection: &mut SqliteConnection,
+    triplets: Vec<(u32, TripletPoint)>,
+) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::triplet_fingerprints;
+
+    let new_triplets: Vec<NewTripletFingerprint> = triplets
+        .into_iter()
+        .map(|(hash, point)| NewTripletFingerprint {
+            hash: hash as i32,
+            anchor_time_ms: point.anchor_time_ms,
+            span_ms: point.span_ms,
+            song_id: point.song_id,
+        })
+        .collect();
+
+    for batch in new_triplets.chunks(BATCH_SIZE) {
+        diesel::insert_or_ignore_into(triplet_fingerprints::table)
+            .values(batch)
+            .execute(connection)?;
+    }
+
+    Ok(())
+}
+
+/// With a vec of triplet hashes, retrieve every occurrence of each in the database, returning a
+/// HashMap.
+pub fn get_triplet_points(hashes: Vec<i32>) -> Result<HashMap<u32, Vec<TripletPoint>>, u8> {
+    use crate::db::schema::triplet_fingerprints;
+    let mut triplet_points = HashMap::<u32, Vec<TripletPoint>>::new();
+    let connection = &mut establish_connection();
+
+    for batch in hashes.chunks(BATCH_SIZE) {
+        let matching_triplets: Vec<TripletFingerprint> = match triplet_fingerprints::table
+            .filter(triplet_fingerprints::hash.eq_any(batch))
+            .load::<TripletFingerprint>(connection)
         {
-            Ok(_) => {}
-            Err(_) => {
-                eprintln!("Error saving new fingerprint");
-                return Err(DATABASE_INSERT_ERROR);
-            }
+            Ok(v) => v,
+            Err(_) => return Err(DATABASE_QUERY_ERROR),
+        };
+
+        for triplet in matching_triplets {
+            triplet_points
+                .entry(triplet.hash as u32)
+                .or_default()
+                .push(TripletPoint {
+                    anchor_time_ms: triplet.anchor_time_ms,
+                    span_ms: triplet.span_ms,
+                    song_id: triplet.song_id,
+                });
         }
     }
-    Ok(())
+
+    Ok(triplet_points)
+}
+
+/// Retrieve every triplet hash in the database with where it occurs, for an index to hold in
+/// memory.
+pub fn get_all_triplet_points() -> Result<Vec<(u32, TripletPoint)>, u8> {
+    use crate::db::schema::triplet_fingerprints;
+
+    let connection = &mut establish_connection();
+
+    let triplets: Vec<TripletFingerprint> =
+        match triplet_fingerprints::table.load::<TripletFingerprint>(connection) {
+            Ok(v) => v,
+            Err(_) => return Err(DATABASE_QUERY_ERROR),
+        };
+
+    Ok(triplets
+        .into_iter()
+        .map(|triplet: TripletFingerprint| {
+            (
+                triplet.hash as u32,
+                TripletPoint {
+                    anchor_time_ms: triplet.anchor_time_ms,
+                    span_ms: triplet.span_ms,
+                    song_id: triplet.song_id,
+                },
+            )
+        })
+        .collect())
 }
 
 /// With a vec of hashes, retrieve the corresponding KeyAudioPoints in the database, returning a
@@ -71,18 +169,64 @@ pub fn get_key_audio_points(hashes: Vec<i32>) -> Result<HashMap<u32, Vec<KeyAudi
     Ok(key_audio_points)
 }
 
-/// Adds a Tracks metadata (title, artist, album, Spotify URI) to the database's Songs table.
-pub fn store_song(
-    title: &String,
-    artist: &String,
-    album: &String,
-    spotify_uri: Option<String>,
-) -> Result<u32, u8> {
-    use crate::db::schema::songs;
+/// Retrieve every fingerprint in the database as a hash and its KeyAudioPoint.
+pub fn get_all_key_audio_points() -> Result<Vec<(u32, KeyAudioPoint)>, u8> {
+    use crate::db::schema::fingerprints;
+
+    let connection = &mut establish_connection();
+
+    match fingerprints::table.load::<Fingerprint>(connection) {
+        Ok(fingerprints) => Ok(fingerprints.into_iter().map(hash_and_point).collect()),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// Retrieve every fingerprint of the songs with the given IDs as a hash and its KeyAudioPoint.
+pub fn get_key_audio_points_of(song_ids: &[u32]) -> Result<Vec<(u32, KeyAudioPoint)>, u8> {
+    use crate::db::schema::fingerprints;
 
     let connection = &mut establish_connection();
+    let mut key_audio_points = Vec::<(u32, KeyAudioPoint)>::new();
+
+    for batch in song_ids.chunks(BATCH_SIZE) {
+        let batch: Vec<i32> = batch.iter().map(|song_id| *song_id as i32).collect();
 
-    let song_key: String = title.to_owned() + "---" + &artist;
+        match fingerprints::table
+            .filter(fingerprints::song_id.eq_any(batch))
+            .load::<Fingerprint>(connection)
+        {
+            Ok(fingerprints) => {
+                key_audio_points.extend(fingerprints.into_iter().map(hash_and_point))
+            }
+            Err(_) => return Err(DATABASE_QUERY_ERROR),
+        }
+    }
+
+    Ok(key_audio_points)
+}
+
+fn hash_and_point(fingerprint: Fingerprint) -> (u32, KeyAudioPoint) {
+    (
+        fingerprint.hash as u32,
+        KeyAudioPoint {
+            anchor_time_ms: fingerprint.anchor_time_ms,
+            song_id: fingerprint.song_id,
+        },
+    )
+}
+
+// Adds a Tracks metadata (title, artist, album, Spotify URI) to the database's Songs table using
+// an existing connection, returning the new song's ID.
+pub(crate) fn insert_song(
+    connection: &mut SqliteConnection,
+    title: &str,
+    artist: &str,
+    album: &str,
+    spotify_uri: Option<String>,
+) -> Result<u32, diesel::result::Error> {
+    use crate::db::schema::songs;
+
+    let song_key: String = title.to_owned() + "---" + artist;
 
     let new_post = NewSong {
         title: title.to_owned(),
@@ -92,17 +236,10 @@ pub fn store_song(
         song_key,
     };
 
-    let song: Song = match diesel::insert_into(songs::table)
+    let song: Song = diesel::insert_into(songs::table)
         .values(&new_post)
         .returning(Song::as_returning())
-        .get_result(connection)
-    {
-        Ok(inserted_song) => inserted_song,
-        Err(_) => {
-            eprintln!("Error saving `{}` metadata to database!", title);
-            return Err(DATABASE_INSERT_ERROR);
-        }
-    };
+        .get_result(connection)?;
 
     Ok(song.id as u32)
 }
@@ -121,16 +258,83 @@ pub fn get_song_by_id(song_id: u32) -> Result<Song, u8> {
         Err(_) => return Err(DATABASE_QUERY_ERROR),
     };
 
-    if matching_songs.len() < 1 {
+    if matching_songs.is_empty() {
         return Err(NO_SONG_MATCH_ERROR);
     }
 
     Ok(matching_songs[0].clone())
 }
 
+/// Every song in the Songs table, ordered by artist, album and title.
+pub fn get_all_songs() -> Result<Vec<Song>, u8> {
+    use crate::db::schema::songs;
+
+    let connection = &mut establish_connection();
+
+    match songs::table
+        .order((songs::artist, songs::album, songs::title))
+        .load::<Song>(connection)
+    {
+        Ok(songs) => Ok(songs),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// The number of songs in the Songs table.
+pub fn count_songs() -> Result<i64, u8> {
+    use crate::db::schema::songs;
+
+    let connection = &mut establish_connection();
+
+    match songs::table.count().get_result::<i64>(connection) {
+        Ok(count) => Ok(count),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// The length of a song in milliseconds: as recorded when it was added, or, for songs added
+/// before durations were recorded, the time of its last fingerprint.
+pub fn song_duration_ms(song_id: u32) -> Result<i32, u8> {
+    use crate::db::schema::fingerprints;
+    use diesel::dsl::max;
+
+    if let Some(duration_ms) = get_song_by_id(song_id)?.duration_ms {
+        return Ok(duration_ms);
+    }
+
+    let connection = &mut establish_connection();
+
+    match fingerprints::table
+        .filter(fingerprints::song_id.eq(song_id as i32))
+        .select(max(fingerprints::anchor_time_ms))
+        .first::<Option<i32>>(connection)
+    {
+        Ok(anchor_time_ms) => Ok(anchor_time_ms.unwrap_or(0)),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// Whether a song with the given title and artist is already stored in the Songs table.
+pub fn song_exists(song_title: &str, song_artist: &str) -> Result<bool, u8> {
+    use crate::db::schema::songs;
+
+    let connection = &mut establish_connection();
+
+    let song_key: String = song_title.to_owned() + "---" + song_artist;
+
+    match songs::table
+        .filter(songs::song_key.eq(song_key))
+        .count()
+        .get_result::<i64>(connection)
+    {
+        Ok(count) => Ok(count > 0),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
 /// Using a song_title and song_artist, finds the corresponding database entry and updates the
 /// Spotify URI.
-pub fn update_song_uri(song_title: &String, song_artist: &String, uri: String) -> Result<(), u8> {
+pub fn update_song_uri(song_title: &str, song_artist: &str, uri: String) -> Result<(), u8> {
     use crate::db::schema::songs;
 
     let connection = &mut establish_connection();
@@ -150,12 +354,32 @@ pub fn update_song_uri(song_title: &String, song_artist: &String, uri: String) -
     }
 }
 
-// Helper function that returns a struct for querying and modifying the database.
-fn establish_connection() -> SqliteConnection {
-    dotenv().ok();
+// Helper function that returns a struct for querying and modifying the database. The first
+// connection made by the program also brings the database schema up to date.
+pub(crate) fn establish_connection() -> SqliteConnection {
+    let database_url: String = database_url();
 
-    let database_url: String = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let mut connection = SqliteConnection::establish(database_url.as_str())
+        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url));
+
+    // Songs are ingested by several workers at once, each with its own connection. Wait for a
+    // competing writer to finish rather than failing immediately with `SQLITE_BUSY`.
+    connection
+        .batch_execute("PRAGMA busy_timeout = 5000;")
+        .unwrap_or_else(|_| panic!("Error configuring connection to {}", database_url));
+
+    RUN_MIGRATIONS.call_once(|| {
+        if let Err(error) = connection.run_pending_migrations(MIGRATIONS) {
+            panic!("Error migrating {}: {}", database_url, error);
+        }
+    });
+
+    connection
+}
+
+// The location of the database, from the `DATABASE_URL` environment variable or `.env` file.
+pub(crate) fn database_url() -> String {
+    dotenv().ok();
 
-    SqliteConnection::establish(database_url.as_str())
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
 }
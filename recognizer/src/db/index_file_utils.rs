@@ -0,0 +1,756 @@
+/*
+file: /src/db/index_file_utils.rs
+stores the database's fingerprints in a compact binary index file, built from the Fingerprints
+table, brought up to date as songs are added, and memory-mapped to answer lookups
+*/
+use crate::db::index_utils::{FingerprintIndex, pack_posting, unpack_posting};
+use crate::db::{db_utils, journal_utils};
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::INDEX_FILE_ERROR;
+use crate::recognizer::fingerprint::KeyAudioPoint;
+use crate::recognizer::triplet::TripletPoint;
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+// An index file is laid out as follows, every integer little-endian:
+//   header (HEADER_LEN bytes): MAGIC, FORMAT_VERSION, then the fields of `Header` in order, then
+//     the CRC-32 of the header's preceding bytes
+//   songs: the ID (u32) of every song whose fingerprints it holds, in ascending order
+//   hash table: for every distinct hash in ascending order, the hash (u32) and the offset (u64) of
+//     its postings, which run up to the next hash's
+//   postings: every hash's occurrences, packed as in `pack_posting` and in ascending order, each
+//     stored as the LEB128 varint of its difference from the one before (the first from 0). A
+//     hash's occurrences in one song differ only by time, so most take one to three bytes.
+const MAGIC: [u8; 8] = *b"RECOGIDX";
+const FORMAT_VERSION: u32 = 2;
+const HEADER_LEN: usize = 64;
+const SONG_ENTRY_LEN: usize = 4;
+const TABLE_ENTRY_LEN: usize = 12;
+
+/// What an index file covers, and the checksums of its sections.
+struct Header {
+    profile_checksum: u32, // CRC-32 of the TOML of the profile its fingerprints were made with
+    song_count: u64,
+    // The songs section's CRC-32, which also tells whether the file holds the songs the database
+    // has: a song whose import failed keeps its ID, so a later one may be committed below others
+    songs_checksum: u32,
+    hash_count: u64,
+    posting_count: u64,
+    postings_len: u64, // in bytes
+    table_checksum: u32,
+    postings_checksum: u32,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.profile_checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.song_count.to_le_bytes());
+        bytes.extend_from_slice(&self.songs_checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.hash_count.to_le_bytes());
+        bytes.extend_from_slice(&self.posting_count.to_le_bytes());
+        bytes.extend_from_slice(&self.postings_len.to_le_bytes());
+        bytes.extend_from_slice(&self.table_checksum.to_le_bytes());
+        bytes.extend_from_slice(&self.postings_checksum.to_le_bytes());
+
+        let checksum: u32 = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], file_path: &Path) -> Result<Header, u8> {
+        if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+            eprintln!("ERROR: `{}` is not an index file!", file_path.display());
+            return Err(INDEX_FILE_ERROR);
+        }
+
+        let version: u32 = read_u32(bytes, 8);
+        if version != FORMAT_VERSION {
+            eprintln!(
+                "ERROR: `{}` is an index file of version {}, but only version {} can be read; \
+                rebuild it with `build-index`",
+                file_path.display(),
+                version,
+                FORMAT_VERSION
+            );
+            return Err(INDEX_FILE_ERROR);
+        }
+
+        if read_u32(bytes, HEADER_LEN - 4) != crc32fast::hash(&bytes[..HEADER_LEN - 4]) {
+            eprintln!("ERROR: the header of `{}` is corrupt!", file_path.display());
+            return Err(INDEX_FILE_ERROR);
+        }
+
+        Ok(Header {
+            profile_checksum: read_u32(bytes, 12),
+            song_count: read_u64(bytes, 16),
+            songs_checksum: read_u32(bytes, 24),
+            hash_count: read_u64(bytes, 28),
+            posting_count: read_u64(bytes, 36),
+            postings_len: read_u64(bytes, 44),
+            table_checksum: read_u32(bytes, 52),
+            postings_checksum: read_u32(bytes, 56),
+        })
+    }
+}
+
+/// An index file, memory-mapped rather than read into memory. Lookups binary search its hash
+/// table and decode only the postings of the hashes they find.
+pub struct FileIndex {
+    mmap: Mmap,
+    header: Header,
+}
+
+impl FileIndex {
+    /// Open the index file at `file_path`, failing if it is corrupt, was made with another
+    /// profile than `config`, or does not hold the fingerprints of exactly the songs the
+    /// database has.
+    pub fn open(file_path: &Path, config: &FingerprintConfig) -> Result<FileIndex, u8> {
+        let index = FileIndex::open_unchecked(file_path, config)?;
+        let song_ids: Vec<u32> = journal_utils::committed_song_ids()?;
+
+        if index.header.song_count != song_ids.len() as u64
+            || index.header.songs_checksum != crc32fast::hash(&song_bytes(&song_ids))
+        {
+            eprintln!(
+                "ERROR: `{}` does not cover the database's songs; rebuild it with `build-index`",
+                file_path.display()
+            );
+            return Err(INDEX_FILE_ERROR);
+        }
+
+        Ok(index)
+    }
+
+    // Open and verify an index file, whichever songs it covers.
+    fn open_unchecked(file_path: &Path, config: &FingerprintConfig) -> Result<FileIndex, u8> {
+        reject_triplet_hashes(config)?;
+
+        let file: File = match File::open(file_path) {
+            Ok(file) => file,
+            Err(_) => {
+                eprintln!(
+                    "ERROR: Cannot open index file `{}`; build it with `build-index`",
+                    file_path.display()
+                );
+                return Err(INDEX_FILE_ERROR);
+            }
+        };
+
+        // SAFETY: the index is only ever replaced by renaming a new file over it, never written in
+        // place, so the mapped file does not change while it is mapped
+        let mmap: Mmap = match unsafe { Mmap::map(&file) } {
+            Ok(mmap) => mmap,
+            Err(_) => {
+                eprintln!("ERROR: Cannot map index file `{}`", file_path.display());
+                return Err(INDEX_FILE_ERROR);
+            }
+        };
+
+        let header: Header = Header::from_bytes(&mmap, file_path)?;
+
+        // The section lengths are checked against the file's before any is used, without
+        // overflowing on a header that lies about them
+        let expected_len: Option<u64> = (header.song_count)
+            .checked_mul(SONG_ENTRY_LEN as u64)
+            .zip(header.hash_count.checked_mul(TABLE_ENTRY_LEN as u64))
+            .and_then(|(songs_len, table_len)| {
+                (HEADER_LEN as u64)
+                    .checked_add(songs_len)?
+                    .checked_add(table_len)?
+                    .checked_add(header.postings_len)
+            });
+        if expected_len != Some(mmap.len() as u64) {
+            eprintln!("ERROR: `{}` is truncated or corrupt!", file_path.display());
+            return Err(INDEX_FILE_ERROR);
+        }
+
+        let index = FileIndex { mmap, header };
+
+        if crc32fast::hash(index.songs()) != index.header.songs_checksum
+            || crc32fast::hash(index.table()) != index.header.table_checksum
+            || crc32fast::hash(index.postings()) != index.header.postings_checksum
+            || !index.is_ordered()
+        {
+            eprintln!("ERROR: `{}` is corrupt!", file_path.display());
+            return Err(INDEX_FILE_ERROR);
+        }
+
+        if index.header.profile_checksum != crc32fast::hash(config.to_toml().as_bytes()) {
+            eprintln!(
+                "ERROR: `{}` was built with another fingerprint profile; rebuild it with \
+                `build-index`",
+                file_path.display()
+            );
+            return Err(INDEX_FILE_ERROR);
+        }
+
+        Ok(index)
+    }
+
+    fn songs(&self) -> &[u8] {
+        let songs_len = self.header.song_count as usize * SONG_ENTRY_LEN;
+        &self.mmap[HEADER_LEN..HEADER_LEN + songs_len]
+    }
+
+    fn table(&self) -> &[u8] {
+        let table_start = HEADER_LEN + self.songs().len();
+        let table_len = self.header.hash_count as usize * TABLE_ENTRY_LEN;
+        &self.mmap[table_start..table_start + table_len]
+    }
+
+    fn postings(&self) -> &[u8] {
+        &self.mmap[HEADER_LEN + self.songs().len() + self.table().len()..]
+    }
+
+    // The IDs of the songs whose fingerprints the index holds, in ascending order.
+    fn song_ids(&self) -> Vec<u32> {
+        self.songs()
+            .chunks_exact(SONG_ENTRY_LEN)
+            .map(|entry| read_u32(entry, 0))
+            .collect()
+    }
+
+    // Whether song IDs and hashes ascend and each hash's postings lie within the postings
+    // section, as lookups assume.
+    fn is_ordered(&self) -> bool {
+        let song_ids: Vec<u32> = self.song_ids();
+        let hash_count = self.header.hash_count as usize;
+
+        song_ids.windows(2).all(|pair| pair[0] < pair[1])
+            && (1..hash_count).all(|idx| self.hash_at(idx - 1) < self.hash_at(idx))
+            && (0..hash_count).all(|idx| self.offset_at(idx) <= self.offset_at(idx + 1))
+            && (hash_count == 0 || self.offset_at(0) == 0)
+    }
+
+    fn hash_at(&self, idx: usize) -> u32 {
+        read_u32(self.table(), idx * TABLE_ENTRY_LEN)
+    }
+
+    // Where the postings of the hash at `idx` in the hash table start in the postings section;
+    // one past the last hash, where they end.
+    fn offset_at(&self, idx: usize) -> u64 {
+        if idx as u64 == self.header.hash_count {
+            self.header.postings_len
+        } else {
+            read_u64(self.table(), idx * TABLE_ENTRY_LEN + 4)
+        }
+    }
+
+    // The postings of the hash at `idx` in the hash table, still encoded.
+    fn postings_at(&self, idx: usize) -> &[u8] {
+        &self.postings()[self.offset_at(idx) as usize..self.offset_at(idx + 1) as usize]
+    }
+
+    // Every hash in the index and its postings, in ascending order.
+    fn all_postings(&self) -> Vec<(u32, u64)> {
+        let mut all_postings = Vec::<(u32, u64)>::with_capacity(self.header.posting_count as usize);
+
+        for idx in 0..self.header.hash_count as usize {
+            let hash = self.hash_at(idx);
+            all_postings.extend(
+                decode_postings(self.postings_at(idx))
+                    .into_iter()
+                    .map(|posting| (hash, posting)),
+            );
+        }
+
+        all_postings
+    }
+}
+
+impl FingerprintIndex for FileIndex {
+    fn key_audio_points(&self, hashes: &[u32]) -> Result<HashMap<u32, Vec<KeyAudioPoint>>, u8> {
+        let mut key_audio_points = HashMap::<u32, Vec<KeyAudioPoint>>::new();
+
+        for hash in hashes {
+            // Binary search of the hash table
+            let (mut low, mut high) = (0, self.header.hash_count as usize);
+            while low < high {
+                let middle = (low + high) / 2;
+                if self.hash_at(middle) < *hash {
+                    low = middle + 1;
+                } else {
+                    high = middle;
+                }
+            }
+
+            if low < self.header.hash_count as usize && self.hash_at(low) == *hash {
+                let points: Vec<KeyAudioPoint> = decode_postings(self.postings_at(low))
+                    .iter()
+                    .map(unpack_posting)
+                    .collect();
+
+                key_audio_points.insert(*hash, points);
+            }
+        }
+
+        Ok(key_audio_points)
+    }
+
+    // An index file holds no triplet hashes, and is never opened for a profile that has them
+    fn triplet_points(&self, _hashes: &[u32]) -> Result<HashMap<u32, Vec<TripletPoint>>, u8> {
+        Ok(HashMap::new())
+    }
+
+    fn song_count(&self) -> Result<i64, u8> {
+        Ok(self.header.song_count as i64)
+    }
+}
+
+/// Build an index file of every fingerprint in the database at `file_path`, replacing any
+/// index already there.
+pub fn build_index_file(file_path: &Path, config: &FingerprintConfig) -> Result<(), u8> {
+    reject_triplet_hashes(config)?;
+
+    let song_ids: Vec<u32> = journal_utils::committed_song_ids()?;
+    let postings: Vec<(u32, u64)> = sorted_postings(db_utils::get_key_audio_points_of(&song_ids)?);
+
+    write_index_file(file_path, &postings, &song_ids, config)?;
+    print_summary(file_path, "Built")
+}
+
+/// Add the songs added to the database since the index file at `file_path` was last built or
+/// updated to it, if there is an index file there. Only the new songs' fingerprints are read
+/// from the database; those already indexed are read from the index file.
+pub fn update_index_file(file_path: &Path, config: &FingerprintConfig) -> Result<(), u8> {
+    if !file_path.exists() {
+        return Ok(());
+    }
+
+    let song_ids: Vec<u32> = journal_utils::committed_song_ids()?;
+    if add_songs_to_index_file(
+        file_path,
+        config,
+        &song_ids,
+        db_utils::get_key_audio_points_of,
+    )? {
+        print_summary(file_path, "Updated")?;
+    }
+
+    Ok(())
+}
+
+/// Update the index file at `file_path` as `update_index_file` does, after songs have been
+/// committed to the database. Those songs stay added if it fails, so that is only warned about.
+/// Returns whether the index file is up to date.
+pub fn update_index_file_after_adding(file_path: &Path, config: &FingerprintConfig) -> bool {
+    let updated: bool = update_index_file(file_path, config).is_ok();
+    if !updated {
+        eprintln!(
+            "WARNING: Could not update index file `{}`; rebuild it with `build-index`",
+            file_path.display()
+        );
+    }
+
+    updated
+}
+
+// Add to the index file at `file_path` the fingerprints of the songs of `song_ids` (every song
+// the database has, in ascending order) it does not hold yet, as `fingerprints_of` retrieves them.
+// Returns whether any were missing.
+fn add_songs_to_index_file(
+    file_path: &Path,
+    config: &FingerprintConfig,
+    song_ids: &[u32],
+    fingerprints_of: impl FnOnce(&[u32]) -> Result<Vec<(u32, KeyAudioPoint)>, u8>,
+) -> Result<bool, u8> {
+    let index = FileIndex::open_unchecked(file_path, config)?;
+    let indexed_song_ids: Vec<u32> = index.song_ids();
+    if indexed_song_ids == song_ids {
+        return Ok(false);
+    }
+
+    // Songs are never removed from the database, so one the index holds and the database does not
+    // means the index is of another database
+    if let Some(song_id) = indexed_song_ids
+        .iter()
+        .find(|song_id| song_ids.binary_search(song_id).is_err())
+    {
+        eprintln!(
+            "ERROR: `{}` holds song {}, which the database does not have; rebuild it with \
+            `build-index`",
+            file_path.display(),
+            song_id
+        );
+        return Err(INDEX_FILE_ERROR);
+    }
+
+    let new_song_ids: Vec<u32> = song_ids
+        .iter()
+        .filter(|song_id| indexed_song_ids.binary_search(song_id).is_err())
+        .copied()
+        .collect();
+    let new_postings: Vec<(u32, u64)> = sorted_postings(fingerprints_of(&new_song_ids)?);
+
+    // Both are in ascending order, so they merge in one pass
+    let old_postings: Vec<(u32, u64)> = index.all_postings();
+    drop(index);
+    let mut postings = Vec::<(u32, u64)>::with_capacity(old_postings.len() + new_postings.len());
+    let (mut old, mut new) = (
+        old_postings.iter().peekable(),
+        new_postings.iter().peekable(),
+    );
+    while let (Some(old_posting), Some(new_posting)) = (old.peek(), new.peek()) {
+        if old_posting <= new_posting {
+            postings.push(*old.next().unwrap());
+        } else {
+            postings.push(*new.next().unwrap());
+        }
+    }
+    postings.extend(old.chain(new));
+
+    write_index_file(file_path, &postings, song_ids, config)?;
+
+    Ok(true)
+}
+
+// Every fingerprint as its hash and packed occurrence, in ascending order.
+fn sorted_postings(fingerprints: Vec<(u32, KeyAudioPoint)>) -> Vec<(u32, u64)> {
+    let mut postings: Vec<(u32, u64)> = fingerprints
+        .iter()
+        .map(|(hash, point)| (*hash, pack_posting(point)))
+        .collect();
+    postings.sort_unstable();
+
+    postings
+}
+
+// Write `postings` (hashes and packed occurrences, in ascending order) as an index file of the
+// songs of `song_ids` (in ascending order) at `file_path`. The file is written next to it, then
+// renamed over it, so a reader sees either the old index or the new one in full.
+fn write_index_file(
+    file_path: &Path,
+    postings: &[(u32, u64)],
+    song_ids: &[u32],
+    config: &FingerprintConfig,
+) -> Result<(), u8> {
+    let songs: Vec<u8> = song_bytes(song_ids);
+    let mut table = Vec::<u8>::new();
+    let mut encoded = Vec::<u8>::new();
+    let mut hash_count: u64 = 0;
+    let mut previous: Option<(u32, u64)> = None;
+
+    for &(hash, posting) in postings {
+        let delta = match previous {
+            Some((previous_hash, previous_posting)) if previous_hash == hash => {
+                posting - previous_posting
+            }
+            _ => {
+                table.extend_from_slice(&hash.to_le_bytes());
+                table.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+                hash_count += 1;
+                posting
+            }
+        };
+        write_varint(&mut encoded, delta);
+        previous = Some((hash, posting));
+    }
+
+    let header = Header {
+        profile_checksum: crc32fast::hash(config.to_toml().as_bytes()),
+        song_count: song_ids.len() as u64,
+        songs_checksum: crc32fast::hash(&songs),
+        hash_count,
+        posting_count: postings.len() as u64,
+        postings_len: encoded.len() as u64,
+        table_checksum: crc32fast::hash(&table),
+        postings_checksum: crc32fast::hash(&encoded),
+    };
+
+    let temporary_path = file_path.with_extension("idx.tmp");
+    let written = File::create(&temporary_path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&header.to_bytes())?;
+        writer.write_all(&songs)?;
+        writer.write_all(&table)?;
+        writer.write_all(&encoded)?;
+        writer.into_inner()?.sync_all()
+    });
+
+    if written
+        .and_then(|_| fs::rename(&temporary_path, file_path))
+        .is_err()
+    {
+        eprintln!("ERROR: Cannot write index file `{}`", file_path.display());
+        let _ = fs::remove_file(&temporary_path);
+        return Err(INDEX_FILE_ERROR);
+    }
+
+    Ok(())
+}
+
+// An index file holds only pair hashes, so a profile with triplet hashes, whose songs are also
+// scored by their triplets, cannot be matched through one.
+fn reject_triplet_hashes(config: &FingerprintConfig) -> Result<(), u8> {
+    if config.triplet_hashes {
+        eprintln!(
+            "ERROR: Index files hold no triplet hashes, but the fingerprint profile has \
+            `triplet_hashes`; use `--index sqlite` or `--index memory`"
+        );
+        return Err(INDEX_FILE_ERROR);
+    }
+
+    Ok(())
+}
+
+// Print what the index file at `file_path` holds and how large it is next to the database.
+fn print_summary(file_path: &Path, action: &str) -> Result<(), u8> {
+    let file_len = |path: &Path| {
+        fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    };
+
+    let mut header_bytes = [0u8; HEADER_LEN];
+    if let Ok(mut file) = File::open(file_path) {
+        let _ = file.read_exact(&mut header_bytes);
+    }
+    let header = Header::from_bytes(&header_bytes, file_path)?;
+
+    println!(
+        "{} `{}`: {} occurrence(s) of {} hash(es) from {} song(s) in {} bytes (database: {} bytes)",
+        action,
+        file_path.display(),
+        header.posting_count,
+        header.hash_count,
+        header.song_count,
+        file_len(file_path),
+        file_len(Path::new(&db_utils::database_url()))
+    );
+
+    Ok(())
+}
+
+// The songs section of an index file of the songs of `song_ids`.
+fn song_bytes(song_ids: &[u32]) -> Vec<u8> {
+    song_ids
+        .iter()
+        .flat_map(|song_id| song_id.to_le_bytes())
+        .collect()
+}
+
+// Decode a hash's postings from their varint-encoded differences. Bits past the 64th of an
+// overlong varint are dropped rather than overflowing, though checksums keep those out.
+fn decode_postings(bytes: &[u8]) -> Vec<u64> {
+    let mut postings = Vec::<u64>::new();
+    let mut posting: u64 = 0;
+    let (mut delta, mut shift) = (0u64, 0);
+
+    for byte in bytes {
+        if shift < u64::BITS {
+            delta |= ((byte & 0x7f) as u64) << shift;
+        }
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            posting = posting.wrapping_add(delta);
+            postings.push(posting);
+            (delta, shift) = (0, 0);
+        }
+    }
+
+    postings
+}
+
+// Append `value` as an LEB128 varint: seven bits a byte, least significant first, with the top
+// bit set on every byte but the last.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::spectrogram::PeakMethod;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use tempfile::TempDir;
+
+    // Songs the indexes are made of, and how many fingerprints each has
+    const SONG_COUNT: u32 = 6;
+    const FINGERPRINTS_PER_SONG: usize = 400;
+    // Fewer hashes than fingerprints, so songs share hashes and hashes have many postings
+    const HASH_COUNT: u32 = 300;
+
+    // The fingerprints of `song_id`, made up from it.
+    fn fingerprints_of_song(song_id: u32) -> Vec<(u32, KeyAudioPoint)> {
+        let mut rng = StdRng::seed_from_u64(song_id as u64);
+
+        (0..FINGERPRINTS_PER_SONG)
+            .map(|_| {
+                let point = KeyAudioPoint {
+                    anchor_time_ms: rng.gen_range(0, 240_000),
+                    song_id: song_id as i32,
+                };
+                (rng.gen_range(0, HASH_COUNT), point)
+            })
+            .collect()
+    }
+
+    fn fingerprints_of(song_ids: &[u32]) -> Result<Vec<(u32, KeyAudioPoint)>, u8> {
+        Ok(song_ids
+            .iter()
+            .flat_map(|&id| fingerprints_of_song(id))
+            .collect())
+    }
+
+    // Build an index file of the songs of `song_ids` at `file_path`, as `build_index_file` does.
+    fn build(file_path: &Path, song_ids: &[u32], config: &FingerprintConfig) {
+        let postings: Vec<(u32, u64)> = sorted_postings(fingerprints_of(song_ids).unwrap());
+        write_index_file(file_path, &postings, song_ids, config).unwrap();
+    }
+
+    #[test]
+    fn postings_survive_encoding() {
+        let postings: Vec<u64> = vec![
+            0,
+            1,
+            127,
+            128,
+            16_383,
+            16_384,
+            u32::MAX as u64,
+            1 << 32,
+            (1 << 63) - 1,
+            u64::MAX,
+        ];
+
+        let mut encoded = Vec::<u8>::new();
+        let mut previous: u64 = 0;
+        for &posting in &postings {
+            write_varint(&mut encoded, posting - previous);
+            previous = posting;
+        }
+
+        assert_eq!(decode_postings(&encoded), postings);
+        // Neither a varint cut short nor one running past 64 bits panics
+        decode_postings(&encoded[..encoded.len() - 1]);
+        decode_postings(&[0xff; 24]);
+
+        let point = KeyAudioPoint {
+            anchor_time_ms: 183_416,
+            song_id: 4_000_001,
+        };
+        let unpacked: KeyAudioPoint = unpack_posting(&pack_posting(&point));
+        assert_eq!(unpacked.anchor_time_ms, point.anchor_time_ms);
+        assert_eq!(unpacked.song_id, point.song_id);
+    }
+
+    #[test]
+    fn updates_after_each_added_song_match_a_full_build() {
+        let config = FingerprintConfig::default();
+        let dir = TempDir::new().unwrap();
+        let updated_path = dir.path().join("updated.idx");
+        let built_path = dir.path().join("built.idx");
+
+        // Song 2 is committed after 3, as a retried import would be, and song 4 after 5
+        let committed_in_turn: [&[u32]; 5] = [
+            &[1, 2, 3],
+            &[1, 2, 3, 5],
+            &[1, 2, 3, 4, 5],
+            &[1, 2, 3, 4, 5],
+            &[1, 2, 3, 4, 5, 6],
+        ];
+        build(&updated_path, &[1, 3], &config);
+        let mut indexed: Vec<u32> = vec![1, 3];
+
+        for song_ids in committed_in_turn {
+            let added = add_songs_to_index_file(&updated_path, &config, song_ids, |new_ids| {
+                let expected: Vec<u32> = song_ids
+                    .iter()
+                    .filter(|id| !indexed.contains(id))
+                    .copied()
+                    .collect();
+                assert_eq!(new_ids, expected, "only songs not indexed are fetched");
+                fingerprints_of(new_ids)
+            })
+            .unwrap();
+            assert_eq!(added, indexed != song_ids);
+            indexed = song_ids.to_vec();
+
+            build(&built_path, song_ids, &config);
+            let updated = FileIndex::open_unchecked(&updated_path, &config).unwrap();
+            let built = FileIndex::open_unchecked(&built_path, &config).unwrap();
+
+            assert_eq!(updated.song_ids(), song_ids);
+            assert_eq!(updated.all_postings(), built.all_postings());
+            assert_eq!(updated.header.hash_count, built.header.hash_count);
+        }
+
+        assert_eq!(
+            fs::read(&updated_path).unwrap(),
+            fs::read(&built_path).unwrap()
+        );
+
+        // An index holding a song the database does not have is of another database
+        assert!(
+            add_songs_to_index_file(&updated_path, &config, &[1, 2, 3], fingerprints_of).is_err()
+        );
+    }
+
+    #[test]
+    fn damaged_or_foreign_index_files_are_rejected() {
+        let config = FingerprintConfig::default();
+        let dir = TempDir::new().unwrap();
+        let file_path = dir.path().join("index.idx");
+        let damaged_path = dir.path().join("damaged.idx");
+
+        let song_ids: Vec<u32> = (1..=SONG_COUNT).collect();
+        build(&file_path, &song_ids, &config);
+        assert!(FileIndex::open_unchecked(&file_path, &config).is_ok());
+        let bytes: Vec<u8> = fs::read(&file_path).unwrap();
+
+        let opens = |damaged: &[u8]| {
+            fs::write(&damaged_path, damaged).unwrap();
+            FileIndex::open_unchecked(&damaged_path, &config).is_ok()
+        };
+
+        for len in [
+            0,
+            8,
+            HEADER_LEN - 1,
+            HEADER_LEN,
+            HEADER_LEN + 5,
+            bytes.len() - 1,
+        ] {
+            assert!(!opens(&bytes[..len]), "truncated to {} bytes", len);
+        }
+        assert!(
+            !opens(&[bytes.as_slice(), &[0]].concat()),
+            "with a byte more"
+        );
+
+        // Every bit of the header, and bits throughout the songs, table and postings sections
+        let flipped_bits = (0..HEADER_LEN * 8).chain((HEADER_LEN * 8..bytes.len() * 8).step_by(61));
+        for bit in flipped_bits {
+            let mut damaged = bytes.clone();
+            damaged[bit / 8] ^= 1 << (bit % 8);
+            assert!(!opens(&damaged), "with bit {} flipped", bit);
+        }
+
+        let other_config = FingerprintConfig {
+            peak_method: PeakMethod::Constellation,
+            ..FingerprintConfig::default()
+        };
+        assert!(FileIndex::open_unchecked(&file_path, &other_config).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+/*
+file: /src/db/index_utils.rs
+looks up the songs a snippet's hashes occur in, by querying the Fingerprints table, from an index
+of it loaded into memory or from a memory-mapped index file, behind a trait the matcher queries
+*/
+use crate::db::db_utils;
+use crate::db::index_file_utils::FileIndex;
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::fingerprint::KeyAudioPoint;
+use crate::recognizer::triplet::TripletPoint;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Where a snippet's hashes are looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IndexKind {
+    /// Query the Fingerprints table for every snippet
+    Sqlite,
+    /// Load the Fingerprints table into memory once, then answer every lookup from memory
+    Memory,
+    /// Memory-map the index file built by `build-index`, which holds no triplet hashes
+    File,
+}
+
+/// The fingerprints of every song in the database, by hash. An index is shared by a server's
+/// requests, so it must be safe to use from several threads.
+pub trait FingerprintIndex: Send + Sync {
+    /// Every occurrence of each of `hashes` in the database's songs.
+    fn key_audio_points(&self, hashes: &[u32]) -> Result<HashMap<u32, Vec<KeyAudioPoint>>, u8>;
+
+    /// Every occurrence of each of the triplet hashes `hashes` in the database's songs.
+    fn triplet_points(&self, hashes: &[u32]) -> Result<HashMap<u32, Vec<TripletPoint>>, u8>;
+
+    /// The number of songs in the database.
+    fn song_count(&self) -> Result<i64, u8>;
+}
+
+/// Open the index of the given kind over the database's fingerprints; a `File` index is read
+/// from `index_file`.
+pub fn open_index(
+    kind: IndexKind,
+    index_file: &Path,
+    config: &FingerprintConfig,
+) -> Result<Box<dyn FingerprintIndex>, u8> {
+    match kind {
+        IndexKind::Sqlite => Ok(Box::new(SqliteIndex)),
+        IndexKind::Memory => Ok(Box::new(MemoryIndex::load()?)),
+        IndexKind::File => Ok(Box::new(FileIndex::open(index_file, config)?)),
+    }
+}
+
+/// Where the index file of the database is kept unless `--index-file` says otherwise: next to
+/// the database, with `.idx` appended to its name.
+pub fn default_index_file() -> PathBuf {
+    PathBuf::from(db_utils::database_url() + ".idx")
+}
+
+/// Looks every hash up in the Fingerprints table.
+pub struct SqliteIndex;
+
+impl FingerprintIndex for SqliteIndex {
+    fn key_audio_points(&self, hashes: &[u32]) -> Result<HashMap<u32, Vec<KeyAudioPoint>>, u8> {
+        db_utils::get_key_audio_points(hashes.iter().map(|hash| *hash as i32).collect())
+    }
+
+    fn triplet_points(&self, hashes: &[u32]) -> Result<HashMap<u32, Vec<TripletPoint>>, u8> {
+        db_utils::get_triplet_points(hashes.iter().map(|hash| *hash as i32).collect())
+    }
+
+    fn song_count(&self) -> Result<i64, u8> {
+        db_utils::count_songs()
+    }
+}
+
+/// The Fingerprints table held in memory: every distinct hash in ascending order, and for each
+/// the range of `postings` holding its occurrences, each a song ID and anchor time packed into
+/// one integer (see `pack_posting`), along with the Triplet Fingerprints table by hash.
+pub struct MemoryIndex {
+    hashes: Vec<u32>,
+    starts: Vec<usize>, // `postings[starts[i]..starts[i + 1]]` are the occurrences of `hashes[i]`
+    postings: Vec<u64>,
+    triplets: HashMap<u32, Vec<TripletPoint>>,
+    song_count: i64,
+}
+
+impl MemoryIndex {
+    /// Read every fingerprint in the database into a new index.
+    pub fn load() -> Result<MemoryIndex, u8> {
+        let fingerprints: Vec<(u32, KeyAudioPoint)> = db_utils::get_all_key_audio_points()?;
+
+        Ok(MemoryIndex::from_points(
+            fingerprints,
+            db_utils::get_all_triplet_points()?,
+            db_utils::count_songs()?,
+        ))
+    }
+
+    /// An index of the given occurrences of pair and triplet hashes, in any order, over a
+    /// database of `song_count` songs.
+    pub fn from_points(
+        mut points: Vec<(u32, KeyAudioPoint)>,
+        triplet_points: Vec<(u32, TripletPoint)>,
+        song_count: i64,
+    ) -> MemoryIndex {
+        points.sort_unstable_by_key(|(hash, point)| (*hash, pack_posting(point)));
+
+        let mut hashes = Vec::<u32>::new();
+        let mut starts = Vec::<usize>::new();
+        let mut postings = Vec::<u64>::with_capacity(points.len());
+
+        for (hash, point) in points {
+            if hashes.last() != Some(&hash) {
+                hashes.push(hash);
+                starts.push(postings.len());
+            }
+            postings.push(pack_posting(&point));
+        }
+        starts.push(postings.len());
+
+        let mut triplets = HashMap::<u32, Vec<TripletPoint>>::new();
+        for (hash, point) in triplet_points {
+            triplets.entry(hash).or_default().push(point);
+        }
+
+        MemoryIndex {
+            hashes,
+            starts,
+            postings,
+            triplets,
+            song_count,
+        }
+    }
+}
+
+impl FingerprintIndex for MemoryIndex {
+    fn key_audio_points(&self, hashes: &[u32]) -> Result<HashMap<u32, Vec<KeyAudioPoint>>, u8> {
+        let mut key_audio_points = HashMap::<u32, Vec<KeyAudioPoint>>::new();
+
+        for hash in hashes {
+            if let Ok(idx) = self.hashes.binary_search(hash) {
+                let postings = &self.postings[self.starts[idx]..self.starts[idx + 1]];
+
+                key_audio_points.insert(*hash, postings.iter().map(unpack_posting).collect());
+            }
+        }
+
+        Ok(key_audio_points)
+    }
+
+    fn triplet_points(&self, hashes: &[u32]) -> Result<HashMap<u32, Vec<TripletPoint>>, u8> {
+        Ok(hashes
+            .iter()
+            .filter_map(|hash| Some((*hash, self.triplets.get(hash)?.clone())))
+            .collect())
+    }
+
+    fn song_count(&self) -> Result<i64, u8> {
+        Ok(self.song_count)
+    }
+}
+
+// An occurrence of a hash as one integer: the song ID in the high 32 bits and the anchor time in
+// the low 32, so postings sort by song, then by time.
+pub(crate) fn pack_posting(point: &KeyAudioPoint) -> u64 {
+    ((point.song_id as u32 as u64) << 32) | point.anchor_time_ms as u32 as u64
+}
+
+pub(crate) fn unpack_posting(posting: &u64) -> KeyAudioPoint {
+    KeyAudioPoint {
+        song_id: (posting >> 32) as u32 as i32,
+        anchor_time_ms: *posting as u32 as i32,
+    }
+}
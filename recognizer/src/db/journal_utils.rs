@@ -0,0 +1,238 @@
+/*
+file: /src/db/journal_utils.rs
+keeps the import journal: the state of every audio file an ingestion run has started on, so an
+interrupted run can resume and failed files can be retried on their own
+*/
+use crate::db::db_utils::{
+    establish_connection, insert_fingerprints, insert_song, insert_triplet_fingerprints,
+};
+use crate::db::models::NewImportJob;
+use crate::recognizer::declarations::{DATABASE_INSERT_ERROR, DATABASE_QUERY_ERROR};
+use crate::recognizer::fingerprint::AudioFingerprint;
+use diesel::prelude::*;
+use diesel::result::DatabaseErrorKind;
+
+pub use crate::db::models::ImportJob;
+
+/// The stages a file moves through while being imported, in order. Any stage can move to
+/// `Failed`, which keeps the song ID (if one was assigned) so a retry does not store it twice.
+/// A file stays `MetadataStored` while it is fingerprinted: its fingerprints only exist once the
+/// transaction storing them is committed, so there is no state between the two to resume from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportState {
+    Queued,
+    MetadataStored,
+    Committed,
+    Failed,
+}
+
+impl ImportState {
+    pub const ALL: [ImportState; 4] = [
+        ImportState::Queued,
+        ImportState::MetadataStored,
+        ImportState::Committed,
+        ImportState::Failed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportState::Queued => "queued",
+            ImportState::MetadataStored => "metadata_stored",
+            ImportState::Committed => "committed",
+            ImportState::Failed => "failed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ImportState> {
+        ImportState::ALL
+            .into_iter()
+            .find(|state| state.as_str() == name)
+    }
+}
+
+/// Returns the journal entry of a file, if an import of it was ever started.
+pub fn get_import_job(file_path: &str) -> Result<Option<ImportJob>, u8> {
+    use crate::db::schema::import_jobs;
+
+    let connection = &mut establish_connection();
+
+    match import_jobs::table
+        .find(file_path)
+        .first::<ImportJob>(connection)
+        .optional()
+    {
+        Ok(job) => Ok(job),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// Returns every journal entry, or only those in `state`, ordered by file path.
+pub fn list_import_jobs(state: Option<ImportState>) -> Result<Vec<ImportJob>, u8> {
+    use crate::db::schema::import_jobs;
+
+    let connection = &mut establish_connection();
+
+    let mut query = import_jobs::table
+        .order(import_jobs::file_path)
+        .into_boxed();
+
+    if let Some(state) = state {
+        query = query.filter(import_jobs::state.eq(state.as_str()));
+    }
+
+    match query.load::<ImportJob>(connection) {
+        Ok(jobs) => Ok(jobs),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// The IDs of every song whose fingerprints are stored, in ascending order: every song but those
+/// of files whose import has not been committed.
+pub fn committed_song_ids() -> Result<Vec<u32>, u8> {
+    use crate::db::schema::{import_jobs, songs};
+    use diesel::dsl::{exists, not};
+
+    let connection = &mut establish_connection();
+
+    let uncommitted_import = import_jobs::table
+        .filter(import_jobs::song_id.eq(songs::id.nullable()))
+        .filter(import_jobs::state.ne(ImportState::Committed.as_str()));
+
+    match songs::table
+        .select(songs::id)
+        .filter(not(exists(uncommitted_import)))
+        .order(songs::id)
+        .load::<i32>(connection)
+    {
+        Ok(song_ids) => Ok(song_ids.into_iter().map(|song_id| song_id as u32).collect()),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// Record that `file_path` reached `state`, creating its journal entry if needed.
+pub fn set_import_state(
+    file_path: &str,
+    state: ImportState,
+    song_id: Option<u32>,
+    reason: Option<String>,
+) -> Result<(), u8> {
+    let connection = &mut establish_connection();
+
+    upsert_import_job(connection, file_path, state, song_id, reason)
+}
+
+/// Store a song's metadata and move its journal entry to `MetadataStored` in one transaction,
+/// so a song row never exists without the journal knowing its ID. Returns `None`, storing
+/// nothing, if a song with the same title and artist is already in the database, as happens when
+/// another run stores it between this one checking and inserting.
+pub fn store_song_for_import(
+    file_path: &str,
+    title: &str,
+    artist: &str,
+    album: &str,
+    spotify_uri: Option<String>,
+) -> Result<Option<u32>, u8> {
+    let connection = &mut establish_connection();
+
+    let stored = connection.transaction::<u32, diesel::result::Error, _>(|connection| {
+        let song_id = insert_song(connection, title, artist, album, spotify_uri)?;
+
+        upsert_import_job(
+            connection,
+            file_path,
+            ImportState::MetadataStored,
+            Some(song_id),
+            None,
+        )
+        .map_err(|_| diesel::result::Error::RollbackTransaction)?;
+
+        Ok(song_id)
+    });
+
+    match stored {
+        Ok(song_id) => Ok(Some(song_id)),
+        Err(diesel::result::Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+            Ok(None)
+        }
+        Err(_) => {
+            eprintln!("Error saving `{}` metadata to database!", title);
+            Err(DATABASE_INSERT_ERROR)
+        }
+    }
+}
+
+/// Replace any fingerprints of `song_id` (pairs and triplets) with `fingerprint`, record the
+/// song's duration and mark the file `Committed`, all in one transaction: either every
+/// fingerprint of the song is stored or none are.
+pub fn commit_fingerprints_for_import(
+    file_path: &str,
+    song_id: u32,
+    fingerprint: AudioFingerprint,
+    duration_ms: i32,
+) -> Result<(), u8> {
+    use crate::db::schema::{fingerprints, songs, triplet_fingerprints};
+
+    let connection = &mut establish_connection();
+
+    connection
+        .transaction::<(), diesel::result::Error, _>(|connection| {
+            diesel::delete(fingerprints::table.filter(fingerprints::song_id.eq(song_id as i32)))
+                .execute(connection)?;
+            diesel::delete(
+                triplet_fingerprints::table
+                    .filter(triplet_fingerprints::song_id.eq(song_id as i32)),
+            )
+            .execute(connection)?;
+
+            insert_fingerprints(connection, fingerprint.pairs)?;
+            insert_triplet_fingerprints(connection, fingerprint.triplets)?;
+
+            diesel::update(songs::table.filter(songs::id.eq(song_id as i32)))
+                .set(songs::duration_ms.eq(duration_ms))
+                .execute(connection)?;
+
+            upsert_import_job(
+                connection,
+                file_path,
+                ImportState::Committed,
+                Some(song_id),
+                None,
+            )
+            .map_err(|_| diesel::result::Error::RollbackTransaction)
+        })
+        .map_err(|_| {
+            eprintln!("Error saving fingerprints for `{}` to database!", file_path);
+            DATABASE_INSERT_ERROR
+        })
+}
+
+fn upsert_import_job(
+    connection: &mut SqliteConnection,
+    file_path: &str,
+    state: ImportState,
+    song_id: Option<u32>,
+    reason: Option<String>,
+) -> Result<(), u8> {
+    use crate::db::schema::import_jobs;
+
+    let job = NewImportJob {
+        file_path: file_path.to_owned(),
+        state: state.as_str().to_owned(),
+        song_id: song_id.map(|song_id| song_id as i32),
+        reason,
+    };
+
+    match diesel::insert_into(import_jobs::table)
+        .values(&job)
+        .on_conflict(import_jobs::file_path)
+        .do_update()
+        .set(&job)
+        .execute(connection)
+    {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            eprintln!("Error updating import journal for `{}`!", file_path);
+            Err(DATABASE_INSERT_ERROR)
+        }
+    }
+}
@@ -1,5 +1,9 @@
 // file: recognizer/src/db/mod.rs
 
+pub mod db_utils;
+pub mod index_file_utils;
+pub mod index_utils;
+pub mod journal_utils;
 mod models;
+pub mod profile_utils;
 mod schema;
-pub mod db_utils;
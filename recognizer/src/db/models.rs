@@ -1,11 +1,11 @@
 // file: recognizer/src/db/models.rs
 use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
 
 #[derive(Queryable, Selectable)]
 #[diesel(table_name = crate::db::schema::fingerprints)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct Fingerprint {
-    #[allow(unused)]
     pub hash: i32,
     pub anchor_time_ms: i32,
     pub song_id: i32,
@@ -19,7 +19,26 @@ pub struct NewFingerprint {
     pub song_id: i32,
 }
 
-#[derive(Queryable, Selectable, Clone)]
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = crate::db::schema::triplet_fingerprints)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct TripletFingerprint {
+    pub hash: i32,
+    pub anchor_time_ms: i32,
+    pub span_ms: i32,
+    pub song_id: i32,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = crate::db::schema::triplet_fingerprints)]
+pub struct NewTripletFingerprint {
+    pub hash: i32,
+    pub anchor_time_ms: i32,
+    pub span_ms: i32,
+    pub song_id: i32,
+}
+
+#[derive(Queryable, Selectable, Clone, Serialize, Deserialize)]
 #[diesel(table_name = crate::db::schema::songs)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
 pub struct Song {
@@ -29,7 +48,9 @@ pub struct Song {
     pub album: String,
     pub spotify_uri: Option<String>,
     #[allow(unused)]
+    #[serde(skip)]
     pub song_key: String, // form: song--artist, for easy non-ID lookup
+    pub duration_ms: Option<i32>, // unknown for songs added before durations were recorded
 }
 
 #[derive(Insertable)]
@@ -41,3 +62,23 @@ pub struct NewSong {
     pub spotify_uri: Option<String>,
     pub song_key: String,
 }
+
+#[derive(Queryable, Selectable, Clone)]
+#[diesel(table_name = crate::db::schema::import_jobs)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ImportJob {
+    pub file_path: String,
+    pub state: String, // one of the `ImportState` names
+    pub song_id: Option<i32>,
+    pub reason: Option<String>,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = crate::db::schema::import_jobs)]
+#[diesel(treat_none_as_null = true)]
+pub struct NewImportJob {
+    pub file_path: String,
+    pub state: String,
+    pub song_id: Option<i32>,
+    pub reason: Option<String>,
+}
@@ -0,0 +1,83 @@
+/*
+file: /src/db/profile_utils.rs
+records the fingerprint profile a database was built with, so snippets are fingerprinted the same
+way as its songs and songs fingerprinted with another profile are never mixed in
+*/
+use crate::db::db_utils::establish_connection;
+use crate::recognizer::config::{self, FingerprintConfig};
+use crate::recognizer::declarations::{
+    DATABASE_INSERT_ERROR, DATABASE_QUERY_ERROR, PROFILE_MISMATCH_ERROR,
+};
+use diesel::prelude::*;
+
+const DATABASE: &str = "The database";
+
+/// Returns the profile the database's fingerprints were made with, or `None` for an empty
+/// database that has no profile yet. Fails if the fingerprints were made under another scheme
+/// version, including a database with songs but no recorded profile, which was built before
+/// profiles existed.
+pub fn database_profile() -> Result<Option<FingerprintConfig>, u8> {
+    use crate::db::schema::{fingerprint_profile, songs};
+
+    let connection = &mut establish_connection();
+
+    let stored_profile: Option<String> = match fingerprint_profile::table
+        .select(fingerprint_profile::profile)
+        .first::<String>(connection)
+        .optional()
+    {
+        Ok(stored_profile) => stored_profile,
+        Err(_) => return Err(DATABASE_QUERY_ERROR),
+    };
+
+    if let Some(stored_profile) = stored_profile {
+        return FingerprintConfig::from_saved_toml(&stored_profile, DATABASE).map(Some);
+    }
+
+    match songs::table.count().get_result::<i64>(connection) {
+        Ok(0) => Ok(None),
+        // Built with the default profile, before scheme versions were recorded
+        Ok(_) => config::check_scheme_version(0, DATABASE).map(|()| Some(Default::default())),
+        Err(_) => Err(DATABASE_QUERY_ERROR),
+    }
+}
+
+/// Check that fingerprints made with `config` can be compared with the database's.
+pub fn check_profile(config: &FingerprintConfig) -> Result<(), u8> {
+    match database_profile()? {
+        Some(database_config) if database_config != *config => {
+            eprintln!(
+                "ERROR: The database was built with a different fingerprint profile. Run \
+                 `profile` to print it"
+            );
+            Err(PROFILE_MISMATCH_ERROR)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check `config` against the database's profile as `check_profile` does, recording it as the
+/// database's profile if the database does not have one yet.
+pub fn record_profile(config: &FingerprintConfig) -> Result<(), u8> {
+    use crate::db::schema::fingerprint_profile;
+
+    check_profile(config)?;
+
+    let connection = &mut establish_connection();
+
+    // Another process may record its profile first; the check below then compares against it
+    if diesel::insert_into(fingerprint_profile::table)
+        .values((
+            fingerprint_profile::id.eq(1),
+            fingerprint_profile::profile.eq(config.to_toml()),
+        ))
+        .on_conflict_do_nothing()
+        .execute(connection)
+        .is_err()
+    {
+        eprintln!("Error saving the fingerprint profile to database!");
+        return Err(DATABASE_INSERT_ERROR);
+    }
+
+    check_profile(config)
+}
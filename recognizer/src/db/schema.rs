@@ -8,6 +8,22 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    fingerprint_profile (id) {
+        id -> Integer,
+        profile -> Text,
+    }
+}
+
+diesel::table! {
+    import_jobs (file_path) {
+        file_path -> Text,
+        state -> Text,
+        song_id -> Nullable<Integer>,
+        reason -> Nullable<Text>,
+    }
+}
+
 diesel::table! {
     songs (id) {
         id -> Integer,
@@ -16,7 +32,23 @@ diesel::table! {
         album -> Text,
         spotify_uri -> Nullable<Text>,
         song_key -> Text,
+        duration_ms -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    triplet_fingerprints (hash, anchor_time_ms, span_ms, song_id) {
+        hash -> Integer,
+        anchor_time_ms -> Integer,
+        span_ms -> Integer,
+        song_id -> Integer,
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(fingerprints, songs,);
+diesel::allow_tables_to_appear_in_same_query!(
+    fingerprint_profile,
+    fingerprints,
+    import_jobs,
+    songs,
+    triplet_fingerprints,
+);
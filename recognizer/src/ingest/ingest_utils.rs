@@ -0,0 +1,649 @@
+/* file: src/ingest/ingest_utils.rs
+Purpose: finds audio files on disk and adds them to the database of songs, processing a bounded
+ number of files at a time and reporting progress as each one finishes.
+*/
+
+use crate::db::db_utils;
+use crate::db::journal_utils;
+use crate::db::journal_utils::{ImportJob, ImportState};
+use crate::db::profile_utils;
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::{
+    DATABASE_INSERT_ERROR, FILE_NOT_FOUND, FINGERPRINT_FILE_ERROR, INCOMPATIBLE_FILE_ERROR,
+    SUPPORTED_AUDIO_EXTENSIONS,
+};
+use crate::recognizer::fingerprint::{self, AudioFingerprint};
+use crate::recognizer::fingerprint_file::{FINGERPRINT_FILE_EXTENSION, FingerprintFile, SongInfo};
+use crate::recognizer::loudness::{TimeRange, format_ranges};
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::wav::WavStream;
+use crate::spotify::spotify_utils;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use walkdir::WalkDir;
+
+/// What happened to a single file during ingestion. An added file lists the stretches of silence
+/// its fingerprints skipped.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "outcome", content = "detail", rename_all = "lowercase")]
+pub enum FileOutcome {
+    Added(Vec<TimeRange>),
+    Skipped(String),
+    Failed(String),
+}
+
+impl FileOutcome {
+    /// How the file's line of the progress report starts.
+    pub fn status(&self) -> String {
+        match self {
+            FileOutcome::Added(skipped_ranges) if !skipped_ranges.is_empty() => format!(
+                "added   (skipped silence at {})",
+                format_ranges(skipped_ranges)
+            ),
+            FileOutcome::Added(_) => "added  ".to_string(),
+            FileOutcome::Skipped(reason) => format!("skipped ({})", reason),
+            FileOutcome::Failed(reason) => format!("failed ({})", reason),
+        }
+    }
+}
+
+/// Tally of an ingestion run, with the reason recorded for every file that was not added.
+#[derive(Default)]
+pub struct IngestSummary {
+    pub added: Vec<PathBuf>,
+    pub skipped: Vec<(PathBuf, String)>,
+    pub failed: Vec<(PathBuf, String)>,
+    pub elapsed: Duration,
+}
+
+impl IngestSummary {
+    pub fn record(&mut self, file_path: PathBuf, outcome: FileOutcome) {
+        match outcome {
+            FileOutcome::Added(_) => self.added.push(file_path),
+            FileOutcome::Skipped(reason) => self.skipped.push((file_path, reason)),
+            FileOutcome::Failed(reason) => self.failed.push((file_path, reason)),
+        }
+    }
+
+    /// Print the totals followed by the files that were skipped or failed.
+    pub fn print(&self) {
+        println!(
+            "Added: {}, Skipped: {}, Failed: {}",
+            self.added.len(),
+            self.skipped.len(),
+            self.failed.len()
+        );
+
+        // Throughput over the whole run, so worker counts can be compared on a given machine.
+        let elapsed_sec = self.elapsed.as_secs_f64();
+        if elapsed_sec > 0.0 && !self.added.is_empty() {
+            println!(
+                "Finished in {:.1}s ({:.2} songs added per minute)",
+                elapsed_sec,
+                self.added.len() as f64 * 60.0 / elapsed_sec
+            );
+        }
+
+        for (file_path, reason) in &self.skipped {
+            println!("  skipped `{}`: {}", file_path.display(), reason);
+        }
+
+        for (file_path, reason) in &self.failed {
+            println!("  failed  `{}`: {}", file_path.display(), reason);
+        }
+    }
+
+    /// Ingestion fails as a whole only when nothing was added and at least one file failed.
+    pub fn as_result(&self) -> Result<(), u8> {
+        if self.added.is_empty() && !self.failed.is_empty() {
+            return Err(DATABASE_INSERT_ERROR);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns every file with a supported audio extension at or below `root`, sorted by path. If
+/// `root` is itself a file, it is returned as long as its extension is supported.
+pub fn collect_audio_files(root: &Path) -> Result<Vec<PathBuf>, u8> {
+    collect_files(root, &SUPPORTED_AUDIO_EXTENSIONS)
+}
+
+/// Returns every fingerprint file at or below `root`, as `collect_audio_files` does.
+pub fn collect_fingerprint_files(root: &Path) -> Result<Vec<PathBuf>, u8> {
+    collect_files(root, &[FINGERPRINT_FILE_EXTENSION])
+}
+
+fn collect_files(root: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>, u8> {
+    if !root.exists() {
+        eprintln!("ERROR: `{}` does not exist!", root.display());
+        return Err(FILE_NOT_FOUND);
+    }
+
+    let mut audio_files: Vec<PathBuf> = WalkDir::new(root)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(error) => {
+                eprintln!("WARNING: Could not read directory entry: {}", error);
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file() && has_extension(entry.path(), extensions))
+        .map(|entry| entry.into_path())
+        .collect();
+
+    audio_files.sort();
+
+    Ok(audio_files)
+}
+
+fn has_extension(file_path: &Path, extensions: &[&str]) -> bool {
+    match file_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some(extension) => extensions
+            .iter()
+            .any(|supported| supported.eq_ignore_ascii_case(extension)),
+        None => false,
+    }
+}
+
+/// Add each file to the database, processing at most `worker_count` files at the same time.
+/// A progress bar tracks the run and a status line is printed as each file finishes; the workers
+/// print nothing themselves, so the user is signed in to Spotify before the bar is drawn. Each file
+/// is preprocessed as `preprocessing` says before it is fingerprinted.
+pub async fn add_song_files(
+    audio_files: Vec<PathBuf>,
+    worker_count: usize,
+    config: Arc<FingerprintConfig>,
+    preprocessing: Preprocessing,
+) -> IngestSummary {
+    let mut summary = IngestSummary::default();
+    let start_time = Instant::now();
+    let look_up_uri: bool = !audio_files.is_empty() && sign_in_for_lookups().await;
+
+    let progress_bar = ProgressBar::new(audio_files.len() as u64);
+    progress_bar.set_style(
+        ProgressStyle::with_template("[{elapsed_precise}] {bar:40} {pos}/{len} {wide_msg}")
+            .unwrap_or_else(|_| ProgressStyle::default_bar()),
+    );
+
+    let workers = Arc::new(Semaphore::new(worker_count.max(1)));
+    let mut tasks = JoinSet::new();
+
+    for file_path in audio_files {
+        let workers = Arc::clone(&workers);
+        let progress_bar = progress_bar.clone();
+        let config = Arc::clone(&config);
+
+        tasks.spawn(async move {
+            // The semaphore is never closed, so acquiring a permit cannot fail.
+            let _permit = workers.acquire_owned().await.ok();
+
+            progress_bar.set_message(file_name(&file_path));
+            let outcome = add_song_file(
+                &file_path,
+                config,
+                preprocessing,
+                look_up_uri,
+                &progress_bar,
+            )
+            .await;
+
+            (file_path, outcome)
+        });
+    }
+
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((file_path, outcome)) => {
+                progress_bar.println(format!("{} {}", outcome.status(), file_path.display()));
+
+                summary.record(file_path, outcome);
+            }
+            Err(_) => {
+                progress_bar.println("ERROR: Could not join an ingestion task");
+            }
+        }
+
+        progress_bar.inc(1);
+    }
+
+    progress_bar.finish_and_clear();
+    summary.elapsed = start_time.elapsed();
+
+    summary
+}
+
+/// Fetch the Spotify track URI, store the song's metadata, fingerprint the audio, and store the
+/// fingerprints for a single file. Each step is recorded in the import journal; a file whose
+/// metadata was stored by an earlier, interrupted run resumes at fingerprinting. Warnings go
+/// through `progress_bar` so they are not drawn over.
+async fn add_song_file(
+    file_path: &Path,
+    config: Arc<FingerprintConfig>,
+    preprocessing: Preprocessing,
+    look_up_uri: bool,
+    progress_bar: &ProgressBar,
+) -> FileOutcome {
+    let journal_key: String = journal_key(file_path);
+
+    let Some((name, artist, album)) = song_info_from_name(file_path) else {
+        return FileOutcome::Skipped("could not parse title, artist and album".to_string());
+    };
+
+    let song_id: u32 = match song_id_for_import(
+        &journal_key,
+        &name,
+        &artist,
+        &album,
+        look_up_uri,
+        |warning| progress_bar.println(warning),
+    )
+    .await
+    {
+        Ok(song_id) => song_id,
+        Err(outcome) => return outcome,
+    };
+
+    // Fingerprinting is CPU-bound, so it runs on the blocking thread pool; running it on a
+    // runtime worker would stall the other files' Spotify requests and database writes.
+    let audio_path: String = journal_key.to_string();
+    let (fingerprint, duration_ms) = match tokio::task::spawn_blocking(move || {
+        let duration_sec: f64 = WavStream::open(&audio_path)?.info.duration_sec;
+        let fingerprint =
+            fingerprint::fingerprint_audio(audio_path, song_id, &config, &preprocessing)?;
+
+        Ok::<_, u8>((fingerprint, (duration_sec * 1000.).round() as i32))
+    })
+    .await
+    {
+        Ok(Ok(fingerprinted)) => fingerprinted,
+        Ok(Err(_)) | Err(_) => {
+            return record_failure(
+                &journal_key,
+                Some(song_id),
+                "could not generate fingerprint",
+            );
+        }
+    };
+
+    let skipped_ranges: Vec<TimeRange> = fingerprint.skipped_ranges.clone();
+
+    match journal_utils::commit_fingerprints_for_import(
+        &journal_key,
+        song_id,
+        fingerprint,
+        duration_ms,
+    ) {
+        Ok(()) => FileOutcome::Added(skipped_ranges),
+        Err(_) => record_failure(&journal_key, Some(song_id), "could not store fingerprints"),
+    }
+}
+
+/// Fingerprint each of `audio_files` (found at or below `root`) into a fingerprint file in
+/// `output_dir`, at the same path relative to it as the audio file has to `root`, working on at
+/// most `worker_count` files at a time. Returns the number of files written; a file that cannot
+/// be fingerprinted is reported and skipped.
+pub fn write_fingerprint_files(
+    audio_files: &[PathBuf],
+    root: &Path,
+    output_dir: &Path,
+    worker_count: usize,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+) -> Result<usize, u8> {
+    fingerprint_files(
+        audio_files,
+        worker_count,
+        config,
+        preprocessing,
+        |audio_file, fingerprinted| {
+            let relative_path: &Path = match audio_file.strip_prefix(root) {
+                Ok(relative_path) if relative_path != Path::new("") => relative_path,
+                _ => Path::new(audio_file.file_name().unwrap_or_default()),
+            };
+            let output_file: PathBuf = output_dir
+                .join(relative_path)
+                .with_extension(FINGERPRINT_FILE_EXTENSION);
+
+            let written = fingerprinted.and_then(|fingerprint_file| {
+                if let Some(parent) = output_file.parent() {
+                    if std::fs::create_dir_all(parent).is_err() {
+                        eprintln!("ERROR: Cannot create `{}`", parent.display());
+                        return Err(FILE_NOT_FOUND);
+                    }
+                }
+
+                fingerprint_file.write(&output_file)?;
+                Ok(fingerprint_file.song.is_some())
+            });
+
+            match written {
+                Ok(true) => println!("wrote   {}", output_file.display()),
+                Ok(false) => println!("wrote   (no song in file name) {}", output_file.display()),
+                Err(_) => println!("failed  {}", audio_file.display()),
+            }
+
+            written.is_ok()
+        },
+    )
+}
+
+/// Fingerprint each of `audio_files`, working on at most `worker_count` files at a time, and
+/// hand each one's fingerprint file (or the error it failed with) to `fingerprinted` as soon as
+/// it is made. Files named `title_artist_album.wav` record that song, so their fingerprints can
+/// be imported into a library; others can only be identified. Returns the number of files
+/// `fingerprinted` accepted.
+pub fn fingerprint_files(
+    audio_files: &[PathBuf],
+    worker_count: usize,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+    fingerprinted: impl Fn(&Path, Result<FingerprintFile, u8>) -> bool + Sync,
+) -> Result<usize, u8> {
+    let Ok(workers) = rayon::ThreadPoolBuilder::new()
+        .num_threads(worker_count.max(1))
+        .build()
+    else {
+        eprintln!("ERROR: Cannot start the fingerprinting threads");
+        return Err(FINGERPRINT_FILE_ERROR);
+    };
+
+    let accepted_count: usize = workers.install(|| {
+        audio_files
+            .par_iter()
+            .filter(|audio_file| {
+                let song: Option<SongInfo> =
+                    song_info_from_name(audio_file).map(|(title, artist, album)| SongInfo {
+                        title,
+                        artist,
+                        album,
+                    });
+
+                fingerprinted(
+                    audio_file,
+                    FingerprintFile::of_recording(
+                        &audio_file.to_string_lossy(),
+                        song,
+                        config,
+                        preprocessing,
+                    ),
+                )
+            })
+            .count()
+    });
+
+    Ok(accepted_count)
+}
+
+/// Add the song of each fingerprint file to the database, without the audio it was made from.
+/// Each file goes through the import journal as an audio file does, keyed by its own path. Its
+/// profile must be the database's; a database without songs takes the profile of the first
+/// file imported into it.
+pub async fn import_fingerprint_files(fingerprint_files: Vec<PathBuf>) -> IngestSummary {
+    let mut summary = IngestSummary::default();
+    let start_time = Instant::now();
+    let look_up_uri: bool = !fingerprint_files.is_empty() && sign_in_for_lookups().await;
+
+    for file_path in fingerprint_files {
+        let outcome: FileOutcome = import_fingerprint_file(&file_path, look_up_uri).await;
+        println!("{} {}", outcome.status(), file_path.display());
+
+        summary.record(file_path, outcome);
+    }
+
+    summary.elapsed = start_time.elapsed();
+
+    summary
+}
+
+// Store the song of a single fingerprint file and its fingerprints, as `add_song_file` does for an
+// audio file.
+async fn import_fingerprint_file(file_path: &Path, look_up_uri: bool) -> FileOutcome {
+    match FingerprintFile::read(file_path) {
+        Ok(fingerprint_file) => {
+            import_fingerprint(&journal_key(file_path), fingerprint_file, look_up_uri).await
+        }
+        Err(_) => FileOutcome::Failed("could not read fingerprint file".to_string()),
+    }
+}
+
+/// Store the song of a fingerprint file and its fingerprints, recording its progress in the
+/// import journal under `journal_key`, which names where it came from. Its profile must be the
+/// database's, unless the database has no songs yet. Its Spotify track URI is only looked up if
+/// `look_up_uri`, and never asks the user to sign in.
+pub async fn import_fingerprint(
+    journal_key: &str,
+    fingerprint_file: FingerprintFile,
+    look_up_uri: bool,
+) -> FileOutcome {
+    let Some(song) = fingerprint_file.song.clone() else {
+        return FileOutcome::Skipped("no song recorded in the file".to_string());
+    };
+
+    if profile_utils::record_profile(&fingerprint_file.profile).is_err() {
+        return FileOutcome::Failed("made with another fingerprint profile".to_string());
+    }
+
+    let song_id: u32 = match song_id_for_import(
+        journal_key,
+        &song.title,
+        &song.artist,
+        &song.album,
+        look_up_uri,
+        |warning| eprintln!("{}", warning),
+    )
+    .await
+    {
+        Ok(song_id) => song_id,
+        Err(outcome) => return outcome,
+    };
+
+    let duration_ms: i32 = fingerprint_file.duration_ms;
+    let fingerprint: AudioFingerprint = fingerprint_file.into_song_fingerprint(song_id);
+    let skipped_ranges: Vec<TimeRange> = fingerprint.skipped_ranges.clone();
+
+    match journal_utils::commit_fingerprints_for_import(
+        journal_key,
+        song_id,
+        fingerprint,
+        duration_ms,
+    ) {
+        Ok(()) => FileOutcome::Added(skipped_ranges),
+        Err(_) => record_failure(journal_key, Some(song_id), "could not store fingerprints"),
+    }
+}
+
+// The ID of the song the file at `journal_key` is being imported as: the one stored by an earlier,
+// interrupted import of it, or a new one stored with its metadata and, if `look_up_uri`, its
+// Spotify track URI; a URI that could not be found is passed to `warn` as a warning. Fails with
+// the file's outcome if it was imported already or its song is already in the database.
+async fn song_id_for_import(
+    journal_key: &str,
+    name: &str,
+    artist: &str,
+    album: &str,
+    look_up_uri: bool,
+    warn: impl Fn(String),
+) -> Result<u32, FileOutcome> {
+    let job = match journal_utils::get_import_job(journal_key) {
+        Ok(job) => job,
+        Err(_) => {
+            return Err(FileOutcome::Failed(
+                "could not read import journal".to_string(),
+            ));
+        }
+    };
+
+    match job {
+        Some(job) if ImportState::from_name(&job.state) == Some(ImportState::Committed) => {
+            Err(FileOutcome::Skipped("already imported".to_string()))
+        }
+        Some(ImportJob {
+            song_id: Some(song_id),
+            ..
+        }) => Ok(song_id as u32),
+        _ => {
+            match db_utils::song_exists(name, artist) {
+                Ok(true) => return Err(FileOutcome::Skipped("already in database".to_string())),
+                Ok(false) => {}
+                Err(_) => return Err(FileOutcome::Failed("could not query database".to_string())),
+            }
+
+            if journal_utils::set_import_state(journal_key, ImportState::Queued, None, None)
+                .is_err()
+            {
+                return Err(FileOutcome::Failed(
+                    "could not update import journal".to_string(),
+                ));
+            }
+
+            let uri: Option<String> = if look_up_uri {
+                match spotify_utils::find_track_uri(name, artist, album).await {
+                    Ok(uri) => Some(uri),
+                    Err(reason) => {
+                        warn(format!(
+                            "WARNING: No Spotify URI for `{}`: {}",
+                            name, reason
+                        ));
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            match journal_utils::store_song_for_import(journal_key, name, artist, album, uri) {
+                Ok(Some(song_id)) => Ok(song_id),
+                Ok(None) => Err(FileOutcome::Skipped("already in database".to_string())),
+                Err(_) => Err(record_failure(
+                    journal_key,
+                    None,
+                    "could not store metadata",
+                )),
+            }
+        }
+    }
+}
+
+// Sign the user in to Spotify before any file is added, as the workers cannot ask. Returns
+// whether track URIs can be looked up.
+async fn sign_in_for_lookups() -> bool {
+    let signed_in: bool = spotify_utils::sign_in().await.is_ok();
+
+    if !signed_in {
+        eprintln!("WARNING: Could not sign in to Spotify, so songs are stored without track URIs");
+    }
+
+    signed_in
+}
+
+// Mark a file as failed in the import journal, keeping its song ID so a retry does not store
+// the song's metadata a second time.
+fn record_failure(journal_key: &str, song_id: Option<u32>, reason: &str) -> FileOutcome {
+    if journal_utils::set_import_state(
+        journal_key,
+        ImportState::Failed,
+        song_id,
+        Some(reason.to_string()),
+    )
+    .is_err()
+    {
+        eprintln!("WARNING: Could not record failure of `{}`", journal_key);
+    }
+
+    FileOutcome::Failed(reason.to_string())
+}
+
+// Files are journaled by absolute path so a rerun from another directory finds the same entry.
+fn journal_key(file_path: &Path) -> String {
+    match std::fs::canonicalize(file_path) {
+        Ok(absolute_path) => absolute_path.to_string_lossy().to_string(),
+        Err(_) => file_path.to_string_lossy().to_string(),
+    }
+}
+
+/// Returns the files whose last import attempt failed, so they can be retried on their own.
+pub fn failed_import_files() -> Result<Vec<PathBuf>, u8> {
+    let failed_jobs = journal_utils::list_import_jobs(Some(ImportState::Failed))?;
+
+    Ok(failed_jobs
+        .into_iter()
+        .map(|job| PathBuf::from(job.file_path))
+        .collect())
+}
+
+/// Print the import journal, optionally limited to files in one state.
+pub fn print_import_jobs(state: Option<ImportState>) -> Result<(), u8> {
+    let jobs = journal_utils::list_import_jobs(state)?;
+
+    if jobs.is_empty() {
+        println!("No imports recorded");
+        return Ok(());
+    }
+
+    for job in jobs {
+        match job.reason {
+            Some(reason) => println!("{:<16} {} ({})", job.state, job.file_path, reason),
+            None => println!("{:<16} {}", job.state, job.file_path),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns (song_title, artist, album)
+pub fn get_song_info(file_path: &str) -> Result<(String, String, String), u8> {
+    match File::open(file_path) {
+        Err(_) => {
+            eprintln!("ERROR: Cannot open file: `{}`", file_path);
+            return Err(FILE_NOT_FOUND);
+        }
+        Ok(_file) => {}
+    }
+
+    match song_info_from_name(Path::new(file_path)) {
+        Some(song_info) => Ok(song_info),
+        None => {
+            eprintln!(
+                "ERROR: `{}` does not have sufficient underscore-delimited parts!",
+                file_path
+            );
+            println!("Example: `title_artist_album.wav`");
+            Err(INCOMPATIBLE_FILE_ERROR)
+        }
+    }
+}
+
+/// Returns (song_title, artist, album) from a file named `title_artist_album.wav`, or `None` if
+/// its name has too few parts.
+pub fn song_info_from_name(file_path: &Path) -> Option<(String, String, String)> {
+    let file_name: &str = file_path.file_stem()?.to_str()?;
+    let vec_names: Vec<&str> = file_name.split('_').collect();
+
+    if vec_names.len() < 3 {
+        return None;
+    }
+
+    let (song, artist, album) = (vec_names[0], vec_names[1], vec_names[2]);
+
+    Some((song.to_string(), artist.to_string(), album.to_string()))
+}
+
+fn file_name(file_path: &Path) -> String {
+    match file_path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => file_path.display().to_string(),
+    }
+}
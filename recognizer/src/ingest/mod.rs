@@ -0,0 +1,2 @@
+// file: src/ingest/mod.rs
+pub mod ingest_utils;
@@ -4,38 +4,93 @@ Purpose: provides the application's external interface, allowing users to add so
 */
 
 mod db;
+mod ingest;
 mod recognizer;
+mod remote;
 mod spotify;
 
-use crate::db::db_utils;
+use crate::db::db_utils::{self, Song};
+use crate::db::index_file_utils;
+use crate::db::index_utils::{self, FingerprintIndex, IndexKind};
+use crate::db::journal_utils::ImportState;
+use crate::db::profile_utils;
+use crate::ingest::ingest_utils;
+use crate::recognizer::config::FingerprintConfig;
 use crate::recognizer::declarations::{
-    DATABASE_INSERT_ERROR, FILE_NOT_FOUND, INCOMPATIBLE_FILE_ERROR, MATCH_SCORE_THRESHOLD,
-    NO_SONG_MATCH_ERROR,
+    FILE_NOT_FOUND, FINGERPRINT_FILE_ERROR, MATCH_SCORE_THRESHOLD, NO_SONG_MATCH_ERROR,
 };
+use crate::recognizer::evaluation;
+use crate::recognizer::explain::{self, PlotFormat};
 use crate::recognizer::fingerprint;
-use crate::recognizer::fingerprint::KeyAudioPoint;
+use crate::recognizer::fingerprint_file::FingerprintFile;
+use crate::recognizer::loudness::{LevelAnalysis, format_ranges};
+use crate::recognizer::preprocess::{PreprocessStep, Preprocessing};
+use crate::recognizer::segmentation::{self, Segment};
 use crate::recognizer::shazam;
 use crate::recognizer::shazam::Match;
+use crate::recognizer::spectrogram::{Peak, PeakMethod};
+use crate::recognizer::spectrogram_export::{DataFormat, SpectrogramExport};
+use crate::recognizer::split::{self, SplitFormat};
+use crate::recognizer::tracklist::{self, TracklistFormat};
+use crate::recognizer::wav::WavStream;
+use crate::remote::client_utils::LibraryClient;
+use crate::remote::server_utils;
 use crate::spotify::spotify_utils;
-use clap::Parser;
-use std::collections::HashMap;
-use std::fs::File;
-use std::path::Path;
+use clap::{CommandFactory, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 // Struct for `clap` crate to handle command-line arguments.
 #[derive(Parser, Debug)]
 #[command(about = "Compares audio snippet against songs in a database to determine the snippet's \
 song title and artist", long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Audio file(s) [.wav] to add to the database. Repeat flag for each additional file
     #[arg(short, long, value_name = "FILE")]
     add_song: Vec<String>,
 
-    // The following three commands are mutually exclusive!
+    // The following four commands are mutually exclusive!
     /// Audio file [.wav] to compare against songs in the database
     #[arg(short, long, value_name = "FILE", group = "input")]
     id_song: Option<String>,
 
+    /// Fingerprint file [.fp] of a snippet to compare against songs in the database, written by
+    /// `fingerprint`
+    #[arg(long, value_name = "FILE", group = "input")]
+    id_fingerprint: Option<String>,
+
+    /// Also search for the snippet played up to this many percent (at most 20) too fast or too
+    /// slow, e.g. a record on a turntable running off speed; every extra speed is another chance
+    /// of a false match
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 0.0,
+        value_parser = speed_tolerance_percent,
+        requires = "id_song"
+    )]
+    speed_tolerance: f64,
+
+    /// Also search for a 33⅓ RPM record played at 45 RPM, and vice versa
+    #[arg(long, requires = "id_song")]
+    rpm_conversion: bool,
+
+    /// Explain how the snippet matched instead of playing the match: its hashes found in the
+    /// index, the songs they were found in, the best song's hits by offset and the runner-up gap
+    #[arg(long, requires = "id_song", conflicts_with = "server")]
+    explain: bool,
+
+    /// Also draw the best song's hits, snippet time against song time
+    #[arg(long, value_enum, value_name = "FORMAT", requires = "explain")]
+    plot: Option<PlotFormat>,
+
+    /// Write the plot to this file instead of printing it
+    #[arg(long, value_name = "FILE", requires = "plot")]
+    plot_output: Option<String>,
+
     // TODO: add option to listen live from microphone or interface
     /// Listen on the device's default microphone
     #[arg(long, group = "input")]
@@ -44,244 +99,767 @@ struct Args {
     /// Specify a particular interface capable of audio-capture
     #[arg(long, value_name = "INTERFACE", group = "input")]
     microphone_interface: Option<String>,
+
+    /// Fingerprint profile [.toml] of analysis parameters [default: the database's profile]
+    #[arg(long, value_name = "FILE", global = true)]
+    profile: Option<String>,
+
+    /// How spectrogram peaks are picked, overriding the profile's `peak_method`
+    #[arg(long, value_enum, global = true)]
+    peak_method: Option<PeakMethod>,
+
+    /// Clean up audio recorded from a worn record before fingerprinting it, e.g.
+    /// `--preprocess dc,rumble,declick,denoise`
+    #[arg(
+        long,
+        value_enum,
+        value_name = "STEPS",
+        value_delimiter = ',',
+        global = true
+    )]
+    preprocess: Vec<PreprocessStep>,
+
+    /// Where a snippet's hashes are looked up: the database itself, an index of it loaded into
+    /// memory first, or the index file built by `build-index`, which holds no triplet hashes
+    #[arg(long, value_enum, default_value = "sqlite", global = true)]
+    index: IndexKind,
+
+    /// Index file for `--index file`, built by `build-index` and kept up to date as songs are
+    /// added [default: the database's path with `.idx` appended]
+    #[arg(long, value_name = "FILE", global = true)]
+    index_file: Option<String>,
+
+    /// Use the library served by `serve` at this URL (e.g. `http://127.0.0.1:7878`) instead of
+    /// the local database. Recordings are fingerprinted here with the server's profile and only
+    /// their fingerprints are sent
+    #[arg(
+        long,
+        value_name = "URL",
+        global = true,
+        conflicts_with_all = ["profile", "peak_method"]
+    )]
+    server: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Add every supported audio file in a directory (searched recursively) to the database
+    Add {
+        /// Directory to search, or a single audio file
+        #[arg(value_name = "DIR", required_unless_present = "retry_failed")]
+        path: Option<String>,
+
+        /// Number of files to process at the same time [default: number of CPUs]
+        #[arg(short, long, value_name = "COUNT")]
+        workers: Option<usize>,
+
+        /// Retry only the files whose last import failed, as recorded in the import journal
+        #[arg(long, conflicts_with = "path")]
+        retry_failed: bool,
+    },
+
+    /// Fingerprint audio files without adding them to the database, writing each fingerprint to
+    /// a file that `import` can add to a database elsewhere or `--id-fingerprint` can identify
+    Fingerprint {
+        /// Directory to search, or a single audio file
+        #[arg(value_name = "DIR")]
+        path: String,
+
+        /// Directory the fingerprint files are written to, keeping the layout of `DIR`
+        output_dir: String,
+
+        /// Number of files to process at the same time [default: number of CPUs]
+        #[arg(short, long, value_name = "COUNT")]
+        workers: Option<usize>,
+    },
+
+    /// Add the songs of every fingerprint file [.fp] in a directory (searched recursively) to
+    /// the database, with the fingerprints they hold
+    Import {
+        /// Directory to search, or a single fingerprint file
+        #[arg(value_name = "DIR")]
+        path: String,
+    },
+
+    /// List the import journal: every file an import was started on and how far it got
+    Imports {
+        /// Only list files in this state
+        #[arg(long, value_parser = ImportState::ALL.map(|state| state.as_str()))]
+        state: Option<String>,
+    },
+
+    /// List the songs in the database, or in the library at `--server`
+    Songs,
+
+    /// Print the fingerprint profile in use: the database's unless `--profile` is given
+    Profile,
+
+    /// Measure a recording's loudness and level, and warn if it is clipped, too quiet or mostly
+    /// silence
+    Level {
+        /// Audio file [.wav] to measure
+        file: String,
+    },
+
+    /// Show what the fingerprinter sees in a recording: draw its spectrogram with the peaks
+    /// picked from it, or export the magnitudes and peaks for analysis elsewhere
+    Spectrogram {
+        /// Audio file [.wav] to analyse
+        file: String,
+
+        /// Channel to analyse; 0 is the left or only channel
+        #[arg(long, default_value_t = 0)]
+        channel: usize,
+
+        /// Draw the spectrogram as a PNG heatmap with the peaks marked, to this file
+        #[arg(long, value_name = "FILE")]
+        png: Option<String>,
+
+        /// Also draw a line from each anchor peak to each target it is hashed with
+        #[arg(long, requires = "png")]
+        pairs: bool,
+
+        /// Write the magnitude of every FFT bin in every frame to this file
+        #[arg(long, value_name = "FILE")]
+        magnitudes: Option<String>,
+
+        /// Write the time and frequency of every peak to this file
+        #[arg(long, value_name = "FILE")]
+        peaks: Option<String>,
+
+        /// How the magnitudes and peaks are written
+        #[arg(long, value_enum, default_value = "csv")]
+        format: DataFormat,
+    },
+
+    /// Score a directory of labelled snippets against the database with the matcher's scorer
+    /// and the one it replaced, and report how well each separates true matches from false ones
+    Evaluate {
+        /// Directory of snippets named `title_artist_anything.wav` after the song they come
+        /// from, or `none_anything.wav` if they come from no song in the database
+        dir: String,
+    },
+
+    /// Find every song in a long recording, such as a whole record side or a DJ set, and print
+    /// a timestamped tracklist
+    Tracklist {
+        /// The recording [.wav]
+        file: String,
+
+        /// Length of the windows the recording is identified in
+        #[arg(long, value_name = "SECONDS", default_value_t = 10.0, value_parser = positive_seconds)]
+        window: f64,
+
+        /// Time between the starts of consecutive windows
+        #[arg(long, value_name = "SECONDS", default_value_t = 5.0, value_parser = positive_seconds)]
+        hop: f64,
+
+        /// How the tracklist is written
+        #[arg(long, value_enum, default_value = "text")]
+        format: TracklistFormat,
+
+        /// Write the tracklist to this file instead of printing it
+        #[arg(long, value_name = "FILE")]
+        output: Option<String>,
+    },
+
+    /// Find every song in a long recording, as `tracklist` does, and write each to its own file
+    /// named and tagged from the database
+    Split {
+        /// The recording [.wav]
+        file: String,
+
+        /// Directory the tracks are written to, created if needed
+        output_dir: String,
+
+        /// The format the tracks are written in
+        #[arg(long, value_enum, default_value = "wav")]
+        format: SplitFormat,
+
+        /// Length of the windows the recording is identified in
+        #[arg(long, value_name = "SECONDS", default_value_t = 10.0, value_parser = positive_seconds)]
+        window: f64,
+
+        /// Time between the starts of consecutive windows
+        #[arg(long, value_name = "SECONDS", default_value_t = 5.0, value_parser = positive_seconds)]
+        hop: f64,
+    },
+
+    /// Build a compact index file of the database's fingerprints for `--index file`, replacing
+    /// any existing one
+    BuildIndex,
+
+    /// Serve the database over HTTP, so other machines can identify snippets, add songs and
+    /// list the library with `--server`
+    Serve {
+        /// Address and port to listen on
+        #[arg(long, value_name = "ADDRESS", default_value = "127.0.0.1:7878")]
+        bind: String,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<(), u8> {
-    let _file = "../songs/White Teeth_Ryan Beatty_Calico.wav".to_string();
-    let _song: String = "White Teeth".to_string();
-    let _artist: String = "Ryan Beatty".to_string();
-    let _album: String = "Calico".to_string();
-
-    assert_eq!(get_song_info(&_file), Ok((_song, _artist, _album)));
-
     // Parse command line arguments.
     let args = Args::parse();
 
-    // Always add any new songs to the database before trying to identify a snippet.
-    // If there is any failures, `?` will percolate up to be main()'s return
-    let add_song_files = args.add_song;
+    // The library is remote, so nothing below may touch the local database
+    if let Some(server_url) = args.server.clone() {
+        return use_server(args, &server_url).await;
+    }
 
-    // If there is more than one song, attempt to process them concurrently.
-    if add_song_files.len() > 1 {
-        // `.await` will pause execution of the `main()` task if `add_song...` is not ready,
-        // which is desired since we do not want to try to identify songs until the database
-        // is fully set up
-        add_song_files_concurrently(&add_song_files).await?
+    let config: Arc<FingerprintConfig> = Arc::new(fingerprint_config(&args)?);
+    let preprocessing = Preprocessing::from_steps(&args.preprocess);
+    let index_file: PathBuf = args
+        .index_file
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(index_utils::default_index_file);
+
+    match args.command {
+        Some(Command::Add {
+            path,
+            workers,
+            retry_failed,
+        }) => {
+            let audio_files: Vec<PathBuf> = match path {
+                Some(path) if !retry_failed => {
+                    let audio_files = ingest_utils::collect_audio_files(Path::new(&path))?;
+                    println!("Found {} audio file(s) in `{}`", audio_files.len(), path);
+                    audio_files
+                }
+                _ => {
+                    let audio_files = ingest_utils::failed_import_files()?;
+                    println!("Retrying {} failed import(s)", audio_files.len());
+                    audio_files
+                }
+            };
 
-    // Regular, sequential processing of one song.
-    } else if add_song_files.len() > 0 {
-        let song_file_path: &String = &add_song_files[0];
+            profile_utils::record_profile(&config)?;
 
-        let (name, artist, album): (String, String, String) = get_song_info(song_file_path)?;
+            let summary = ingest_utils::add_song_files(
+                audio_files,
+                worker_count(workers),
+                Arc::clone(&config),
+                preprocessing,
+            )
+            .await;
+            summary.print();
+            index_file_utils::update_index_file_after_adding(&index_file, &config);
 
-        let uri =
-            spotify_utils::get_track_uri(name.to_string(), artist.to_string(), album.to_string())
-                .await;
+            return summary.as_result();
+        }
+        Some(Command::Fingerprint {
+            path,
+            output_dir,
+            workers,
+        }) => {
+            let audio_files = ingest_utils::collect_audio_files(Path::new(&path))?;
+            println!("Found {} audio file(s) in `{}`", audio_files.len(), path);
+
+            let written_count: usize = ingest_utils::write_fingerprint_files(
+                &audio_files,
+                Path::new(&path),
+                Path::new(&output_dir),
+                worker_count(workers),
+                &config,
+                &preprocessing,
+            )?;
+            println!(
+                "Wrote {} of {} fingerprint file(s) to `{}`",
+                written_count,
+                audio_files.len(),
+                output_dir
+            );
+
+            if written_count < audio_files.len() {
+                return Err(FINGERPRINT_FILE_ERROR);
+            }
 
-        let song_id = db_utils::store_song(&name, &artist, &album, uri)?;
+            return Ok(());
+        }
+        Some(Command::Import { path }) => {
+            let fingerprint_files = ingest_utils::collect_fingerprint_files(Path::new(&path))?;
+            println!(
+                "Found {} fingerprint file(s) in `{}`",
+                fingerprint_files.len(),
+                path
+            );
+
+            let summary = ingest_utils::import_fingerprint_files(fingerprint_files).await;
+            summary.print();
+
+            // The database takes the first file's profile if it had none
+            if let Some(database_config) = profile_utils::database_profile()? {
+                index_file_utils::update_index_file_after_adding(&index_file, &database_config);
+            }
 
-        let fingerprint =
-            fingerprint::fingerprint_audio(song_file_path.to_string(), song_id).await?;
+            return summary.as_result();
+        }
+        Some(Command::Imports { state }) => {
+            let state: Option<ImportState> = state.as_deref().and_then(ImportState::from_name);
 
-        db_utils::store_fingerprints(fingerprint)?
-    }
+            return ingest_utils::print_import_jobs(state);
+        }
+        Some(Command::Songs) => {
+            print_songs(&db_utils::get_all_songs()?);
 
-    // Identify a song based on a .wav file
-    if let Some(id_song_file) = args.id_song {
-        let song_id = rand::random::<u32>();
+            return Ok(());
+        }
+        Some(Command::Profile) => {
+            print!("{}", config.to_toml());
 
-        let sample_fingerprint_map: HashMap<u32, KeyAudioPoint> =
-            fingerprint::fingerprint_audio(id_song_file.to_string(), song_id).await?;
+            return Ok(());
+        }
+        Some(Command::Level { file }) => {
+            let level: LevelAnalysis = WavStream::open(&file)?.analyse_level()?;
+            level.print();
+            warn_about_level(&file, &level);
 
-        let fingerprint: HashMap<u32, u32> = sample_fingerprint_map
-            .into_iter()
-            .map(|(hash, key_audio_point)| (hash, key_audio_point.anchor_time_ms as u32))
-            .collect();
+            return Ok(());
+        }
+        Some(Command::Spectrogram {
+            file,
+            channel,
+            png,
+            pairs,
+            magnitudes,
+            peaks,
+            format,
+        }) => {
+            let export = SpectrogramExport::of_recording(&file, &config, &preprocessing, channel)?;
+            export.print_summary();
+
+            if let Some(png_path) = png {
+                export.write_png(&png_path, pairs)?;
+                println!("Wrote `{}`", png_path);
+            }
+            if let Some(magnitudes_path) = magnitudes {
+                export.write_magnitudes(&magnitudes_path, format)?;
+                println!("Wrote `{}`", magnitudes_path);
+            }
+            if let Some(peaks_path) = peaks {
+                export.write_peaks(&peaks_path, format)?;
+                println!("Wrote `{}`", peaks_path);
+            }
 
-        let matches: Vec<Match> = shazam::find_matches_from_fingerprint(fingerprint)?;
+            return Ok(());
+        }
+        Some(Command::Evaluate { dir }) => {
+            profile_utils::check_profile(&config)?;
+            let index: Box<dyn FingerprintIndex> =
+                index_utils::open_index(args.index, &index_file, &config)?;
 
-        if matches.len() < 1 {
-            eprintln!("No matches found for `{}`!", id_song_file);
-            return Err(NO_SONG_MATCH_ERROR);
+            return evaluation::evaluate_scorers(&dir, &config, &preprocessing, index.as_ref());
         }
+        Some(Command::Tracklist {
+            file,
+            window,
+            hop,
+            format,
+            output,
+        }) => {
+            profile_utils::check_profile(&config)?;
+            let index: Box<dyn FingerprintIndex> =
+                index_utils::open_index(args.index, &index_file, &config)?;
+
+            let segments: Vec<Segment> = segmentation::segment_recording(
+                &file,
+                &config,
+                &preprocessing,
+                index.as_ref(),
+                window,
+                hop,
+            )?;
+
+            if segments.is_empty() {
+                eprintln!("No songs found in `{}`!", file);
+                return Err(NO_SONG_MATCH_ERROR);
+            }
+
+            let tracklist: String = tracklist::format_tracklist(&segments, &file, format);
+
+            match output {
+                Some(output) => {
+                    if std::fs::write(&output, tracklist).is_err() {
+                        eprintln!("ERROR: Cannot write `{}`", output);
+                        return Err(FILE_NOT_FOUND);
+                    }
+                    println!("Wrote {} track(s) to `{}`", segments.len(), output);
+                }
+                None => print!("{}", tracklist),
+            }
 
-        #[cfg(debug_assertions)]
-        for a_match in &matches {
-            println!("{:?}", a_match);
+            return Ok(());
         }
+        Some(Command::Split {
+            file,
+            output_dir,
+            format,
+            window,
+            hop,
+        }) => {
+            profile_utils::check_profile(&config)?;
+            let index: Box<dyn FingerprintIndex> =
+                index_utils::open_index(args.index, &index_file, &config)?;
+
+            let segments: Vec<Segment> = segmentation::segment_recording(
+                &file,
+                &config,
+                &preprocessing,
+                index.as_ref(),
+                window,
+                hop,
+            )?;
+
+            if segments.is_empty() {
+                eprintln!("No songs found in `{}`!", file);
+                return Err(NO_SONG_MATCH_ERROR);
+            }
 
-        let best_match: Match = matches[0].clone();
+            let track_files: Vec<PathBuf> =
+                split::split_recording(&file, &segments, Path::new(&output_dir), format)?;
+            println!("Wrote {} track(s) to `{}`", track_files.len(), output_dir);
 
-        if best_match.score < MATCH_SCORE_THRESHOLD {
-            eprintln!("No LIKELY match found for `{}`!", id_song_file);
-            println!("Best match was: {:?}", best_match);
-            return Err(NO_SONG_MATCH_ERROR);
+            return Ok(());
         }
+        Some(Command::BuildIndex) => {
+            profile_utils::check_profile(&config)?;
 
-        if let Some(uri) = best_match.spotify_uri {
-            if uri.is_empty() {
-                let uri: String = spotify_utils::play_song(
-                    &best_match.name,
-                    &best_match.artist,
-                    &best_match.album,
-                )
-                .await?;
+            return index_file_utils::build_index_file(&index_file, &config);
+        }
+        Some(Command::Serve { bind }) => {
+            return server_utils::serve(
+                &bind,
+                (*config).clone(),
+                preprocessing,
+                args.index,
+                index_file,
+            )
+            .await;
+        }
+        None => {}
+    }
 
-                db_utils::update_song_uri(&best_match.name, &best_match.artist, uri)?;
-            } else {
-                spotify_utils::play_song_from_uri(&uri).await?;
+    // Always add any new songs to the database before trying to identify a snippet.
+    // If there is any failures, `?` will percolate up to be main()'s return
+    if !args.add_song.is_empty() {
+        // `.await` will pause execution of the `main()` task until every file is processed,
+        // which is desired since we do not want to try to identify songs until the database
+        // is fully set up
+        let audio_files: Vec<PathBuf> = args.add_song.iter().map(PathBuf::from).collect();
+
+        profile_utils::record_profile(&config)?;
+
+        let summary = ingest_utils::add_song_files(
+            audio_files,
+            worker_count(None),
+            Arc::clone(&config),
+            preprocessing,
+        )
+        .await;
+        summary.print();
+        index_file_utils::update_index_file_after_adding(&index_file, &config);
+        summary.as_result()?
+    }
+
+    // Identify a song based on a .wav file
+    if let Some(id_song_file) = args.id_song {
+        profile_utils::check_profile(&config)?;
+
+        let mut wav_stream = WavStream::open(&id_song_file)?;
+        let level: LevelAnalysis = wav_stream.analyse_level()?;
+        warn_about_level(&id_song_file, &level);
+
+        if preprocessing.silence_trimming && !level.silent_ranges.is_empty() {
+            println!(
+                "Skipping silence in `{}`: {}",
+                id_song_file,
+                format_ranges(&level.silent_ranges)
+            );
+        }
+
+        // The snippet's peaks are found once, then fingerprinted at each speed searched
+        let peaks: Vec<Vec<Peak>> =
+            fingerprint::audio_peaks(&mut wav_stream, &config, &preprocessing)?;
+        let playback_speeds: Vec<f64> =
+            shazam::playback_speeds(args.speed_tolerance / 100., args.rpm_conversion);
+
+        let index: Box<dyn FingerprintIndex> =
+            index_utils::open_index(args.index, &index_file, &config)?;
+        let matches: Vec<Match> =
+            shazam::find_matches_at_speeds(&peaks, &config, &playback_speeds, index.as_ref())?;
+
+        if args.explain {
+            let plot: Option<String> =
+                explain::explain_match(&peaks, &config, &matches, index.as_ref(), args.plot)?;
+
+            match (plot, args.plot_output) {
+                (Some(plot), Some(plot_output)) => {
+                    if std::fs::write(&plot_output, plot).is_err() {
+                        eprintln!("ERROR: Cannot write `{}`", plot_output);
+                        return Err(FILE_NOT_FOUND);
+                    }
+                    println!("Wrote the plot to `{}`", plot_output);
+                }
+                (Some(plot), None) => print!("{}", plot),
+                (None, _) => {}
             }
-        } else {
-            let uri: String =
-                spotify_utils::play_song(&best_match.name, &best_match.artist, &best_match.album)
-                    .await?;
 
-            db_utils::update_song_uri(&best_match.name, &best_match.artist, uri)?;
+            return match matches.first() {
+                Some(best_match) if best_match.score >= MATCH_SCORE_THRESHOLD => Ok(()),
+                _ => Err(NO_SONG_MATCH_ERROR),
+            };
         }
+
+        play_best_match(&id_song_file, matches, true).await?;
+    }
+
+    // Identify a song from a fingerprint file made elsewhere, with the profile it was made with
+    if let Some(id_fingerprint_file) = args.id_fingerprint {
+        let fingerprint_file = FingerprintFile::read(Path::new(&id_fingerprint_file))?;
+        profile_utils::check_profile(&fingerprint_file.profile)?;
+
+        let index: Box<dyn FingerprintIndex> =
+            index_utils::open_index(args.index, &index_file, &fingerprint_file.profile)?;
+        let matches: Vec<Match> =
+            shazam::find_matches_in_fingerprint(fingerprint_file.fingerprint, index.as_ref())?;
+
+        play_best_match(&id_fingerprint_file, matches, true).await?;
     }
 
     Ok(())
 }
 
-/// Concurrently process a vector of Strings that are paths to .wav files, appropriately fetching
-/// Spotify track URIs, fingerprinting the audio, and storing to database.
-async fn add_song_files_concurrently(songs_to_add: &Vec<String>) -> Result<(), u8> {
-    let mut get_uri_tasks = Vec::with_capacity(songs_to_add.len());
-    let mut audio_details = Vec::<(String, String, String, String)>::new();
-
-    for song_file_path in songs_to_add {
-        // Parsing the song title and arist is low-work => process sequentially
-        let (name, artist, album) = match get_song_info(song_file_path) {
-            Ok((name, artist, album)) => {
-                audio_details.push((
-                    name.to_string(),
-                    artist.to_string(),
-                    album.to_string(),
-                    song_file_path.to_string(),
-                ));
-
-                (name, artist, album)
-            }
-            Err(_) => {
-                eprintln!(
-                    "Could not parse title and/or artist for `{}`! Skipping...",
-                    song_file_path
-                );
-                continue;
-            }
-        };
-
-        // `get_track_uri` relies on the Spotify API's response time => process concurrently.
-        // Calling `tokio::spawn` immediately begins running in background
-        get_uri_tasks.push((
-            name.to_string(),
-            tokio::spawn(spotify_utils::get_track_uri(name, artist, album)),
-        ))
+/// Run the command against the library served at `server_url` rather than the local database.
+/// Only the commands that add songs, list them or identify snippets can be run this way.
+async fn use_server(args: Args, server_url: &str) -> Result<(), u8> {
+    let client = LibraryClient::new(server_url);
+    let preprocessing = Preprocessing::from_steps(&args.preprocess);
+
+    match &args.command {
+        Some(Command::Add {
+            path: Some(_),
+            retry_failed: false,
+            ..
+        })
+        | Some(Command::Songs)
+        | Some(Command::Profile)
+        | None => {}
+        Some(_) => Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "only `add`, `songs`, `profile`, `--add-song`, `--id-song` and `--id-fingerprint` \
+                 can be used with `--server`",
+            )
+            .exit(),
     }
 
-    // Storing song metadata to happen sequentially to prevent data races.
-    // So, join the threads before
-    let mut uris = Vec::<Option<String>>::new();
-    for (song_title, task) in get_uri_tasks {
-        match task.await {
-            Ok(option) => uris.push(option),
-            Err(_) => {
-                eprintln!(
-                    "ERROR: Could not join spotify_utils::get_track_uri() task for song \
-                `{}`",
-                    song_title
-                );
-            }
+    // Every fingerprint sent must be made with the server's profile
+    let config: FingerprintConfig = client.profile().await?;
+
+    match args.command {
+        Some(Command::Add {
+            path: Some(path),
+            workers,
+            ..
+        }) => {
+            let audio_files = ingest_utils::collect_audio_files(Path::new(&path))?;
+            println!("Found {} audio file(s) in `{}`", audio_files.len(), path);
+
+            let summary = client
+                .add_songs(audio_files, worker_count(workers), config, preprocessing)
+                .await;
+            summary.print();
+
+            return summary.as_result();
         }
-    }
+        Some(Command::Songs) => {
+            print_songs(&client.songs().await?);
 
-    let mut song_ids_file_vec = Vec::<(u32, String)>::new();
-    for (uri, (title, artist, album, file)) in uris.into_iter().zip(&audio_details) {
-        match db_utils::store_song(&title, &artist, &album, uri) {
-            Ok(song_id) => {
-                song_ids_file_vec.push((song_id, file.to_string()));
-            }
-            Err(_) => {}
+            return Ok(());
         }
+        Some(Command::Profile) => {
+            print!("{}", config.to_toml());
+
+            return Ok(());
+        }
+        _ => {}
     }
 
-    // Pause to handle errors here.
-    if song_ids_file_vec.is_empty() {
-        return Err(DATABASE_INSERT_ERROR);
+    if !args.add_song.is_empty() {
+        let audio_files: Vec<PathBuf> = args.add_song.iter().map(PathBuf::from).collect();
+
+        let summary = client
+            .add_songs(
+                audio_files,
+                worker_count(None),
+                config.clone(),
+                preprocessing,
+            )
+            .await;
+        summary.print();
+        summary.as_result()?
     }
 
-    // Fingerprinting does not interact with database; safe to be concurrent.
-    let mut fingerprinting_tasks = Vec::new();
-    for (song_id, song_file_path) in song_ids_file_vec {
-        fingerprinting_tasks.push((
-            song_file_path.to_string(),
-            tokio::spawn(fingerprint::fingerprint_audio(song_file_path, song_id)),
-        ))
+    if let Some(id_song_file) = args.id_song {
+        let matches: Vec<Match> = if args.speed_tolerance != 0.0 || args.rpm_conversion {
+            // A fingerprint is only made at one speed, so the server searches the audio itself
+            println!("Sending `{}` to search other playback speeds", id_song_file);
+            client
+                .identify_audio(&id_song_file, args.speed_tolerance, args.rpm_conversion)
+                .await?
+        } else {
+            let fingerprint_file =
+                FingerprintFile::of_recording(&id_song_file, None, &config, &preprocessing)?;
+            client.identify_fingerprint(&fingerprint_file).await?
+        };
+
+        play_best_match(&id_song_file, matches, false).await?;
     }
 
-    for (song_file_path, fingerprint_join_handle) in fingerprinting_tasks {
-        match fingerprint_join_handle.await {
-            Ok(fingerprint_result) => match fingerprint_result {
-                Ok(fingerprint) => db_utils::store_fingerprints(fingerprint).unwrap_or_else(|_| {
-                    eprintln!(
-                        "ERROR: Could not save fingerprints for `{}` to database!",
-                        song_file_path
-                    );
-                }),
-                Err(_) => {
-                    eprintln!(
-                        "ERROR: Could not generate fingerprint for `{}`",
-                        song_file_path
-                    );
-                }
-            },
+    if let Some(id_fingerprint_file) = args.id_fingerprint {
+        let fingerprint_file = FingerprintFile::read(Path::new(&id_fingerprint_file))?;
+        let matches: Vec<Match> = client.identify_fingerprint(&fingerprint_file).await?;
 
-            Err(_) => {
-                eprintln!(
-                    "ERROR: Could not join fingerprint_audio() task for `{}`",
-                    song_file_path
-                );
-            }
-        }
+        play_best_match(&id_fingerprint_file, matches, false).await?;
     }
 
     Ok(())
 }
 
-/// Returns (song_title, artist, album)
-fn get_song_info(file_path: &String) -> Result<(String, String, String), u8> {
-    match File::open(file_path) {
-        Err(_) => {
-            eprintln!("ERROR: Cannot open file: `{}`", file_path);
-            return Err(FILE_NOT_FOUND);
-        }
-        Ok(_file) => {}
+/// Play the best of a snippet's `matches` on Spotify, if it is likely to be the snippet's song.
+/// A Spotify track URI looked up for it is stored in the database if `store_uri`, i.e. if the
+/// library is a local one.
+async fn play_best_match(
+    snippet_file: &str,
+    matches: Vec<Match>,
+    store_uri: bool,
+) -> Result<(), u8> {
+    if matches.is_empty() {
+        eprintln!("No matches found for `{}`!", snippet_file);
+        return Err(NO_SONG_MATCH_ERROR);
+    }
+
+    #[cfg(debug_assertions)]
+    for a_match in &matches {
+        println!("{:?}", a_match);
+    }
+
+    let best_match: Match = matches[0].clone();
+
+    if best_match.score < MATCH_SCORE_THRESHOLD {
+        eprintln!("No LIKELY match found for `{}`!", snippet_file);
+        println!("Best match was: {:?}", best_match);
+        return Err(NO_SONG_MATCH_ERROR);
+    }
+
+    if best_match.speed_factor != 1.0 {
+        println!(
+            "`{}` is playing at {:.3}x the speed of `{}`",
+            snippet_file, best_match.speed_factor, best_match.name
+        );
+    } else if (best_match.time_scale - 1.0).abs() >= 0.01 {
+        // Triplet matches estimate the tempo to within a fraction of a percent
+        println!(
+            "`{}` is playing at about {:.2}x the tempo of `{}`",
+            snippet_file, best_match.time_scale, best_match.name
+        );
     }
 
-    let file_as_path = Path::new(file_path);
+    if let Some(uri) = best_match.spotify_uri {
+        if uri.is_empty() {
+            let uri: String =
+                spotify_utils::play_song(&best_match.name, &best_match.artist, &best_match.album)
+                    .await?;
+
+            if store_uri {
+                db_utils::update_song_uri(&best_match.name, &best_match.artist, uri)?;
+            }
+        } else {
+            spotify_utils::play_song_from_uri(&uri).await?;
+        }
+    } else {
+        let uri: String =
+            spotify_utils::play_song(&best_match.name, &best_match.artist, &best_match.album)
+                .await?;
 
-    let file_name: &str = match file_as_path.file_stem() {
-        None => {
-            eprintln!("ERROR: `{}` does not have a file name!", file_path);
-            return Err(INCOMPATIBLE_FILE_ERROR);
+        if store_uri {
+            db_utils::update_song_uri(&best_match.name, &best_match.artist, uri)?;
         }
+    }
+
+    Ok(())
+}
 
-        Some(file_name) => file_name.to_str().unwrap(),
+/// The analysis parameters to fingerprint with: the `--profile` file if given, otherwise the
+/// profile the database was built with (or the default one for a new database), with
+/// `--peak-method` taking precedence over the profile's.
+fn fingerprint_config(args: &Args) -> Result<FingerprintConfig, u8> {
+    let mut config: FingerprintConfig = match &args.profile {
+        Some(profile_path) => FingerprintConfig::load(profile_path)?,
+        None => profile_utils::database_profile()?.unwrap_or_default(),
     };
 
-    let vec_names: Vec<&str> = file_name.split('_').collect();
+    if let Some(peak_method) = args.peak_method {
+        config.peak_method = peak_method;
+        config.validate()?;
+    }
 
-    if vec_names.len() < 3 {
-        eprintln!(
-            "ERROR: `{}` does not have sufficient underscore-delimited parts!",
-            file_path
+    Ok(config)
+}
+
+/// Print a library's songs, a line each, and how many there are.
+fn print_songs(songs: &[Song]) {
+    for song in songs {
+        let duration: String = match song.duration_ms {
+            Some(duration_ms) => tracklist::minutes_and_seconds(duration_ms as f64 / 1000.),
+            None => "-".to_string(),
+        };
+
+        println!(
+            "{:>5}  {} - {} ({})  {}",
+            song.id, song.artist, song.title, song.album, duration
         );
-        println!("Example: `title_artist_album.wav`");
-        return Err(INCOMPATIBLE_FILE_ERROR);
     }
 
-    let (song, artist, album) = (vec_names[0], vec_names[1], vec_names[2]);
+    println!("{} song(s)", songs.len());
+}
+
+/// Print why a recording is unlikely to be identified, if it is.
+fn warn_about_level(file_path: &str, level: &LevelAnalysis) {
+    for warning in level.warnings() {
+        eprintln!("WARNING: `{}`: {}", file_path, warning);
+    }
+}
 
-    Ok((song.to_string(), artist.to_string(), album.to_string()))
+/// The number of files to ingest at once, defaulting to the number of available CPUs.
+fn worker_count(requested: Option<usize>) -> usize {
+    match requested {
+        Some(count) => count.max(1),
+        None => std::thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1),
+    }
+}
+
+/// Parse a speed tolerance between 0 and `MAX_SPEED_TOLERANCE`, given in percent.
+fn speed_tolerance_percent(value: &str) -> Result<f64, String> {
+    let max_percent = shazam::MAX_SPEED_TOLERANCE * 100.;
+
+    match value.parse::<f64>() {
+        Ok(percent) if (0.0..=max_percent).contains(&percent) => Ok(percent),
+        _ => Err(format!(
+            "`{}` is not a percentage between 0 and {}",
+            value, max_percent
+        )),
+    }
+}
+
+/// Parse a strictly positive number of seconds.
+fn positive_seconds(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(seconds),
+        _ => Err(format!("`{}` is not a positive number of seconds", value)),
+    }
 }
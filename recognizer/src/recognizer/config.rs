@@ -0,0 +1,277 @@
+// file: src/recognizer/config.rs
+// purpose: the analysis parameters of the fingerprinting pipeline, loadable from a TOML profile so
+// density/accuracy trade-offs can be tried without recompiling. Songs and snippets must be
+// fingerprinted with the same profile to match, so each database records the one it was built
+// with (see `db::profile_utils`).
+
+use crate::recognizer::declarations::{INVALID_PROFILE_ERROR, PROFILE_MISMATCH_ERROR};
+use crate::recognizer::spectrogram::{FftPrecision, PeakMethod};
+use serde::{Deserialize, Serialize};
+
+/// The version of the way fingerprints are made from a profile's parameters. Bump it with every
+/// change to filtering, peak picking or hashing that changes the hashes of a recording, so
+/// databases and files fingerprinted before it are refused instead of silently not matching.
+pub const SCHEME_VERSION: u32 = 2;
+
+/// Every parameter that changes the fingerprints of a recording. Fields missing from a profile
+/// keep their default values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FingerprintConfig {
+    /// The `SCHEME_VERSION` fingerprints made with the profile are made under. Profiles saved
+    /// before it was recorded are of version 0
+    pub scheme_version: u32,
+    /// How the peaks of the spectrogram are picked
+    pub peak_method: PeakMethod,
+    /// The anti-aliasing filter applied before downsampling passes frequencies up to this (Hz)
+    /// unchanged, or up to 90% of the downsampled Nyquist frequency if that is lower. It only
+    /// removes frequencies from the downsampled Nyquist frequency up (see `decimator`), so those
+    /// between the two are attenuated partly, and with a `dsp_ratio` of 1 hardly at all
+    pub max_frequency: f64,
+    /// The audio is decimated to one in every `dsp_ratio` samples before the STFT
+    pub dsp_ratio: u32,
+    /// Samples (after downsampling) per STFT frame
+    pub window_size: usize,
+    /// Samples (after downsampling) between the starts of consecutive frames
+    pub hop_size: usize,
+    /// The floating point type the STFT is computed in
+    pub fft_precision: FftPrecision,
+    /// `[first, last)` FFT bins of each band `bands` peak picking takes its maximum from
+    pub frequency_bands: Vec<[usize; 2]>,
+    /// Target of peaks per second kept by `constellation` peak picking
+    pub peaks_per_second: f64,
+    /// Most peaks of its target zone each anchor peak is paired with (the fan-out)
+    pub target_zone_size: usize,
+    /// Seconds after an anchor peak its target zone starts
+    pub target_zone_min_delay: f64,
+    /// Seconds after an anchor peak its target zone ends
+    pub target_zone_max_delay: f64,
+    /// Hz above or below an anchor peak its target zone extends
+    pub target_zone_max_frequency_span: f64,
+    /// FFT bins per unit of the frequencies in a hash
+    pub hash_frequency_step: usize,
+    /// Spectrogram frames per unit of the time between the peaks in a hash
+    pub hash_time_step: usize,
+    /// Bits of a hash given to each peak's frequency (in units of `hash_frequency_step` bins)
+    pub frequency_bits: u32,
+    /// Bits of a hash given to the time between the anchor and target (in units of
+    /// `hash_time_step` frames)
+    pub time_delta_bits: u32,
+    /// Also store triplet hashes (see `triplet`), which still match when a recording's tempo or
+    /// pitch was changed on its own. Needs `constellation` peak picking
+    pub triplet_hashes: bool,
+}
+
+impl Default for FingerprintConfig {
+    fn default() -> FingerprintConfig {
+        FingerprintConfig {
+            scheme_version: SCHEME_VERSION,
+            peak_method: PeakMethod::Bands,
+            max_frequency: 5000.0, // 5 kHz
+            dsp_ratio: 4,
+            window_size: 1024,
+            hop_size: 512, // allow overlap
+            fft_precision: FftPrecision::Double,
+            frequency_bands: vec![[0, 10], [10, 20], [20, 40], [40, 80], [80, 160], [160, 512]],
+            peaks_per_second: 30.0,
+            target_zone_size: 5,
+            target_zone_min_delay: 0.0,
+            target_zone_max_delay: 2.0,
+            target_zone_max_frequency_span: 2000.0,
+            hash_frequency_step: 1,
+            hash_time_step: 1,
+            frequency_bits: 9,
+            time_delta_bits: 14,
+            triplet_hashes: false,
+        }
+    }
+}
+
+impl FingerprintConfig {
+    /// Reads and validates a TOML profile.
+    pub fn load(file_path: &str) -> Result<FingerprintConfig, u8> {
+        let profile = match std::fs::read_to_string(file_path) {
+            Ok(profile) => profile,
+            Err(error) => {
+                eprintln!("ERROR: Cannot read profile `{}`: {}", file_path, error);
+                return Err(INVALID_PROFILE_ERROR);
+            }
+        };
+
+        FingerprintConfig::from_toml(&profile)
+    }
+
+    /// Parses and validates the text of a TOML profile.
+    pub fn from_toml(profile: &str) -> Result<FingerprintConfig, u8> {
+        let config: FingerprintConfig = match toml::from_str(profile) {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("ERROR: Invalid fingerprint profile: {}", error);
+                return Err(INVALID_PROFILE_ERROR);
+            }
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Parses the text of a profile saved alongside fingerprints, such as a database's, failing
+    /// if they were made under another scheme version. `source` names what saved it in the
+    /// error printed.
+    pub fn from_saved_toml(profile: &str, source: &str) -> Result<FingerprintConfig, u8> {
+        let scheme_version: u32 = match toml::from_str::<toml::Table>(profile) {
+            Ok(table) => match table.get("scheme_version") {
+                Some(version) => version
+                    .as_integer()
+                    .and_then(|version| u32::try_from(version).ok())
+                    .unwrap_or(u32::MAX),
+                None => 0,
+            },
+            Err(error) => {
+                eprintln!("ERROR: Invalid fingerprint profile: {}", error);
+                return Err(INVALID_PROFILE_ERROR);
+            }
+        };
+
+        check_scheme_version(scheme_version, source)?;
+
+        FingerprintConfig::from_toml(profile)
+    }
+
+    pub fn to_toml(&self) -> String {
+        // Every field is a plain value or an array of them, which TOML can always represent
+        toml::to_string(self).unwrap_or_default()
+    }
+
+    /// Check the parameters are consistent with each other, printing the first problem found.
+    pub fn validate(&self) -> Result<(), u8> {
+        let problem: Option<String> = if self.scheme_version != SCHEME_VERSION {
+            Some(format!(
+                "`scheme_version` must be {}, the version fingerprints are made under",
+                SCHEME_VERSION
+            ))
+        } else if !is_positive(self.max_frequency) {
+            Some("`max_frequency` must be positive".to_string())
+        } else if self.dsp_ratio == 0 {
+            Some("`dsp_ratio` must be at least 1".to_string())
+        } else if self.window_size < 2 {
+            Some("`window_size` must be at least 2".to_string())
+        } else if self.hop_size == 0 || self.hop_size > self.window_size {
+            Some("`hop_size` must be between 1 and `window_size`".to_string())
+        } else if self.frequency_bands.is_empty() {
+            Some("`frequency_bands` must contain at least one band".to_string())
+        } else if let Some([first, last]) = self
+            .frequency_bands
+            .iter()
+            .find(|[first, last]| first >= last || *last > self.window_size / 2)
+        {
+            Some(format!(
+                "frequency band [{}, {}] must be non-empty and end at or below bin {} \
+                 (`window_size` / 2)",
+                first,
+                last,
+                self.window_size / 2
+            ))
+        } else if !is_positive(self.peaks_per_second) {
+            Some("`peaks_per_second` must be positive".to_string())
+        } else if self.target_zone_size == 0 {
+            Some("`target_zone_size` must be at least 1".to_string())
+        } else if !(self.target_zone_min_delay >= 0.0
+            && self.target_zone_min_delay <= self.target_zone_max_delay)
+        {
+            Some(
+                "`target_zone_min_delay` must be between 0 and `target_zone_max_delay`".to_string(),
+            )
+        } else if !is_positive(self.target_zone_max_frequency_span) {
+            Some("`target_zone_max_frequency_span` must be positive".to_string())
+        } else if self.hash_frequency_step == 0 || self.hash_time_step == 0 {
+            Some("`hash_frequency_step` and `hash_time_step` must be at least 1".to_string())
+        } else if self.frequency_bits == 0 || self.time_delta_bits == 0 {
+            Some("`frequency_bits` and `time_delta_bits` must be at least 1".to_string())
+        } else if 2 * self.frequency_bits + self.time_delta_bits > 32 {
+            Some("two frequencies and a time delta must fit in a 32-bit hash".to_string())
+        } else if (self.window_size / 2 - 1) / self.hash_frequency_step >= 1 << self.frequency_bits
+        {
+            Some(format!(
+                "the highest FFT bin ({}) must fit in `frequency_bits` after dividing by \
+                 `hash_frequency_step`",
+                self.window_size / 2 - 1
+            ))
+        } else if self.triplet_hashes && self.peak_method != PeakMethod::Constellation {
+            // Per-band peaks repeat the same tones frame after frame, and triplets of those are
+            // shared by nearly every song
+            Some("`triplet_hashes` needs `peak_method = \"constellation\"`".to_string())
+        } else {
+            None
+        };
+
+        match problem {
+            Some(problem) => {
+                eprintln!("ERROR: Invalid fingerprint profile: {}", problem);
+                Err(INVALID_PROFILE_ERROR)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The sample rate of audio originally sampled at `sample_rate` once it is downsampled, i.e.
+    /// the rate the STFT runs at.
+    pub fn analysis_sample_rate(&self, sample_rate: u32) -> f64 {
+        // The same whole number of samples the decimator keeps one of
+        let sample_ratio = (sample_rate / (sample_rate / self.dsp_ratio).max(1)).max(1);
+
+        sample_rate as f64 / sample_ratio as f64
+    }
+
+    /// Check the target zone's time differences fit in `time_delta_bits` for audio sampled at
+    /// `sample_rate`, which sets how many frames a second holds.
+    pub fn check_sample_rate(&self, sample_rate: u32) -> Result<(), u8> {
+        let max_delay_frames =
+            (self.target_zone_max_delay / self.frame_duration(sample_rate)).ceil();
+        let max_delay_units = max_delay_frames as u64 / self.hash_time_step as u64;
+
+        if max_delay_units >= 1 << self.time_delta_bits {
+            eprintln!(
+                "ERROR: Invalid fingerprint profile: at {} Hz, `target_zone_max_delay` spans {} \
+                 frames, too many for `time_delta_bits` after dividing by `hash_time_step`",
+                sample_rate, max_delay_frames
+            );
+            return Err(INVALID_PROFILE_ERROR);
+        }
+
+        Ok(())
+    }
+
+    /// Hz covered by one FFT bin of a spectrogram of audio originally sampled at `sample_rate`.
+    pub fn frequency_resolution(&self, sample_rate: u32) -> f64 {
+        self.analysis_sample_rate(sample_rate) / self.window_size as f64
+    }
+
+    /// Seconds between the starts of consecutive spectrogram frames of audio originally sampled
+    /// at `sample_rate`. Frame `n` starts exactly `n` times this after the start of the audio.
+    pub fn frame_duration(&self, sample_rate: u32) -> f64 {
+        self.hop_size as f64 / self.analysis_sample_rate(sample_rate)
+    }
+}
+
+/// Check fingerprints made under `scheme_version` can be compared with those made now, telling
+/// the user to rebuild `source` from the audio if not.
+pub fn check_scheme_version(scheme_version: u32, source: &str) -> Result<(), u8> {
+    if scheme_version != SCHEME_VERSION {
+        eprintln!(
+            "ERROR: {} was fingerprinted under scheme version {}, but this version of the \
+             recognizer fingerprints under version {} and the two never match; rebuild it from \
+             the audio",
+            source, scheme_version, SCHEME_VERSION
+        );
+        return Err(PROFILE_MISMATCH_ERROR);
+    }
+
+    Ok(())
+}
+
+// False for NaN as well as zero and negative values.
+fn is_positive(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
@@ -0,0 +1,211 @@
+// file: src/recognizer/constellation.rs
+// purpose: an alternative to the per-band peaks of `spectrogram::get_peaks` that finds local
+// maxima over a neighbourhood of time and frequency, so the constellation spreads evenly over
+// time and ignores quiet passages' noise.
+
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::spectrogram::Peak;
+use std::collections::VecDeque;
+
+// A magnitude is a peak only if no other magnitude within this many frames (before or after)
+// and this many frequency bins (below or above) is larger.
+const NEIGHBOURHOOD_FRAMES: usize = 3;
+const NEIGHBOURHOOD_BINS: usize = 8;
+
+// Each frequency bin's noise floor follows the average magnitude of the bins up to this many
+// bins away, so a sustained tone (only a few bins wide) stays well above it.
+const NOISE_FLOOR_BINS: usize = 16;
+
+// The noise floor follows those averages with this smoothing factor, and a peak must exceed the
+// floor by this ratio (a ratio of 4 is about 12 dB, which the largest of a neighbourhood of
+// noise magnitudes almost never reaches).
+const NOISE_FLOOR_SMOOTHING: f64 = 0.05;
+const MIN_PEAK_TO_FLOOR_RATIO: f64 = 4.0;
+
+/// Finds the peaks of a spectrogram fed to it one frame at a time. Peaks are returned in order
+/// of time, then frequency, as soon as the frames around them have arrived.
+pub struct ConstellationPeakPicker {
+    frame_duration: f64,
+    frequency_resolution: f64,
+    frames: VecDeque<Vec<f64>>,
+    first_frame_idx: usize, // index of `frames[0]` in the whole spectrogram
+    next_center_idx: usize, // next frame whose peaks are searched for
+    noise_floor: Vec<f64>,
+    frames_per_window: usize,
+    peaks_per_window: usize,
+    window_start_idx: usize,
+    candidates: Vec<Candidate>,
+}
+
+struct Candidate {
+    strength: f64, // magnitude relative to the noise floor
+    frame_idx: usize,
+    frequency_idx: usize,
+}
+
+impl ConstellationPeakPicker {
+    /// `frame_duration` is the time between the starts of consecutive frames. Of the
+    /// candidates in each second of audio, only the profile's `peaks_per_second` strongest are
+    /// kept.
+    pub fn new(
+        frame_duration: f64,
+        sample_rate: u32,
+        config: &FingerprintConfig,
+    ) -> ConstellationPeakPicker {
+        // Density is enforced over windows of about one second
+        let frames_per_window = ((1.0 / frame_duration).round() as usize).max(1);
+        let window_duration = frames_per_window as f64 * frame_duration;
+        let peaks_per_window =
+            ((config.peaks_per_second * window_duration).round() as usize).max(1);
+
+        ConstellationPeakPicker {
+            frame_duration,
+            frequency_resolution: config.frequency_resolution(sample_rate),
+            frames: VecDeque::with_capacity(2 * NEIGHBOURHOOD_FRAMES + 1),
+            first_frame_idx: 0,
+            next_center_idx: 0,
+            noise_floor: Vec::new(),
+            frames_per_window,
+            peaks_per_window,
+            window_start_idx: 0,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Add the next spectrogram frame, returning the peaks that became final.
+    pub fn push_frame(&mut self, frame: Vec<f64>) -> Vec<Peak> {
+        let mut peaks = Vec::<Peak>::new();
+
+        self.frames.push_back(frame);
+        let last_frame_idx = self.first_frame_idx + self.frames.len() - 1;
+
+        while self.next_center_idx + NEIGHBOURHOOD_FRAMES <= last_frame_idx {
+            self.search_next_center(&mut peaks);
+        }
+
+        peaks
+    }
+
+    /// Search the final frames, whose neighbourhoods are cut short by the end of the recording,
+    /// and return the remaining peaks.
+    pub fn finish(&mut self) -> Vec<Peak> {
+        let mut peaks = Vec::<Peak>::new();
+
+        while self.next_center_idx < self.first_frame_idx + self.frames.len() {
+            self.search_next_center(&mut peaks);
+        }
+
+        self.flush_window(&mut peaks);
+
+        peaks
+    }
+
+    fn search_next_center(&mut self, peaks: &mut Vec<Peak>) {
+        let center_idx = self.next_center_idx;
+        let center = &self.frames[center_idx - self.first_frame_idx];
+
+        let local_average: Vec<f64> = local_average(center);
+
+        if self.noise_floor.len() != center.len() {
+            self.noise_floor = local_average.clone();
+        }
+
+        // Skip the DC bin; it only carries the recording's offset
+        for (frequency_idx, (&magnitude, &floor)) in
+            center.iter().zip(&self.noise_floor).enumerate().skip(1)
+        {
+            if magnitude <= floor * MIN_PEAK_TO_FLOOR_RATIO || magnitude <= 0.0 {
+                continue;
+            }
+
+            if self.is_local_maximum(center_idx, frequency_idx, magnitude) {
+                self.candidates.push(Candidate {
+                    strength: magnitude / floor.max(f64::MIN_POSITIVE),
+                    frame_idx: center_idx,
+                    frequency_idx,
+                });
+            }
+        }
+
+        for (floor, average) in self.noise_floor.iter_mut().zip(&local_average) {
+            *floor += NOISE_FLOOR_SMOOTHING * (average - *floor);
+        }
+
+        self.next_center_idx += 1;
+
+        if self.next_center_idx - self.window_start_idx >= self.frames_per_window {
+            self.flush_window(peaks);
+        }
+
+        // Drop frames no later center can reach
+        while self.first_frame_idx + NEIGHBOURHOOD_FRAMES < self.next_center_idx {
+            self.frames.pop_front();
+            self.first_frame_idx += 1;
+        }
+    }
+
+    fn is_local_maximum(&self, center_idx: usize, frequency_idx: usize, magnitude: f64) -> bool {
+        let first_frame = center_idx.saturating_sub(NEIGHBOURHOOD_FRAMES);
+        let last_frame =
+            (center_idx + NEIGHBOURHOOD_FRAMES).min(self.first_frame_idx + self.frames.len() - 1);
+
+        for frame_idx in first_frame.max(self.first_frame_idx)..=last_frame {
+            let frame = &self.frames[frame_idx - self.first_frame_idx];
+
+            let low_bin = frequency_idx.saturating_sub(NEIGHBOURHOOD_BINS);
+            let high_bin = (frequency_idx + NEIGHBOURHOOD_BINS).min(frame.len() - 1);
+
+            for (bin, value) in frame[low_bin..=high_bin].iter().enumerate() {
+                if *value > magnitude {
+                    return false;
+                }
+
+                // Of equal magnitudes, only the earliest (then lowest) one is a peak
+                let is_before = frame_idx < center_idx
+                    || (frame_idx == center_idx && low_bin + bin < frequency_idx);
+                if *value == magnitude && is_before {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Keep the strongest candidates of the current window and emit them in time order.
+    fn flush_window(&mut self, peaks: &mut Vec<Peak>) {
+        self.candidates
+            .sort_by(|a, b| b.strength.total_cmp(&a.strength));
+        self.candidates.truncate(self.peaks_per_window);
+        self.candidates
+            .sort_by_key(|candidate| (candidate.frame_idx, candidate.frequency_idx));
+
+        for candidate in self.candidates.drain(..) {
+            peaks.push(Peak {
+                frequency: self.frequency_resolution * candidate.frequency_idx as f64,
+                time_sec: self.frame_duration * candidate.frame_idx as f64,
+                frequency_idx: candidate.frequency_idx,
+                frame_idx: candidate.frame_idx,
+            });
+        }
+
+        self.window_start_idx = self.next_center_idx;
+    }
+}
+
+// The average magnitude of the bins up to `NOISE_FLOOR_BINS` away from each bin of `frame`.
+fn local_average(frame: &[f64]) -> Vec<f64> {
+    let mut running_sum = vec![0.0; frame.len() + 1];
+    for (idx, magnitude) in frame.iter().enumerate() {
+        running_sum[idx + 1] = running_sum[idx] + magnitude;
+    }
+
+    (0..frame.len())
+        .map(|idx| {
+            let low_bin = idx.saturating_sub(NOISE_FLOOR_BINS);
+            let high_bin = (idx + NOISE_FLOOR_BINS + 1).min(frame.len());
+
+            (running_sum[high_bin] - running_sum[low_bin]) / (high_bin - low_bin) as f64
+        })
+        .collect()
+}
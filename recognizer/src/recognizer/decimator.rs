@@ -0,0 +1,271 @@
+// file: src/recognizer/decimator.rs
+// purpose: reduces a channel's sample rate by the profile's `dsp_ratio` before the STFT. A
+// windowed-sinc low-pass filter designed for the decimation ratio first removes everything that
+// would alias below the new Nyquist frequency, so aliases cannot show up as spectrogram peaks.
+
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::SPECTROGRAM_GENERATION_FAILURE;
+use std::f64::consts::PI;
+
+// Frequencies from the new Nyquist frequency up are attenuated by at least this much, which
+// puts aliases below the quantisation noise of 16-bit audio.
+const STOPBAND_ATTENUATION_DB: f64 = 80.0;
+
+// Kaiser's formulas only estimate the window a given attenuation needs, so the filter is
+// designed for this much more.
+const DESIGN_MARGIN_DB: f64 = 3.0;
+
+// The passband ends this fraction of the new Nyquist frequency below it (or at the profile's
+// `max_frequency`, if that is lower), leaving the rest for the filter's transition.
+const TRANSITION_FRACTION: f64 = 0.1;
+
+/// The anti-aliasing filter for decimating audio sampled at `sample_rate`: unity gain up to
+/// `passband_edge` Hz, and at least `STOPBAND_ATTENUATION_DB` of attenuation from
+/// `stopband_edge` Hz (the new Nyquist frequency) up.
+pub struct FilterSpec {
+    sample_rate: f64,
+    passband_edge: f64,
+    stopband_edge: f64,
+}
+
+impl FilterSpec {
+    pub fn new(sample_rate: u32, sample_ratio: usize, max_frequency: f64) -> FilterSpec {
+        let stopband_edge = sample_rate as f64 / sample_ratio as f64 / 2.0;
+
+        FilterSpec {
+            sample_rate: sample_rate as f64,
+            passband_edge: max_frequency.min(stopband_edge * (1.0 - TRANSITION_FRACTION)),
+            stopband_edge,
+        }
+    }
+
+    /// The taps of a linear-phase low-pass filter meeting the spec: a sinc cut off halfway
+    /// through the transition, shaped by a Kaiser window. There are always an odd number, so
+    /// the filter delays its input by a whole number of samples.
+    pub fn taps(&self) -> Vec<f64> {
+        // Kaiser's formulas for the window's shape and length
+        let attenuation = STOPBAND_ATTENUATION_DB + DESIGN_MARGIN_DB;
+        let beta = 0.1102 * (attenuation - 8.7);
+        let transition_width =
+            2.0 * PI * (self.stopband_edge - self.passband_edge) / self.sample_rate;
+        let length = ((attenuation - 8.0) / (2.285 * transition_width)).ceil() as usize;
+        let half_length = length / 2 + 1;
+
+        let cutoff = (self.passband_edge + self.stopband_edge) / 2.0 / self.sample_rate;
+
+        let mut taps: Vec<f64> = (0..=2 * half_length)
+            .map(|idx| {
+                let offset = idx as f64 - half_length as f64;
+                let sinc = if offset == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (2.0 * PI * cutoff * offset).sin() / (PI * offset)
+                };
+                let position = offset / half_length as f64;
+                let window = bessel_i0(beta * (1.0 - position * position).sqrt()) / bessel_i0(beta);
+
+                sinc * window
+            })
+            .collect();
+
+        // Unity gain at 0 Hz, like the averaging this replaced
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|tap| *tap /= sum);
+
+        taps
+    }
+}
+
+/// Low-pass filters and decimates a channel's samples as they arrive. Output sample `k` is the
+/// filtered signal at input sample `k * sample_ratio` (the filter's delay is compensated), so
+/// decimated audio starts at the same time as the original.
+pub struct Decimator {
+    taps: Vec<f64>,
+    sample_ratio: usize,
+    buffer: Vec<f64>, // the input, preceded by half the filter's length of silence
+    buffer_start: usize, // index of `buffer[0]` in that padded input
+    next_output: usize,
+}
+
+impl Decimator {
+    pub fn new(sample_rate: u32, config: &FingerprintConfig) -> Result<Decimator, u8> {
+        let target_sample_rate = sample_rate / config.dsp_ratio;
+
+        // Check that integer division did not result in 0
+        if target_sample_rate == 0 || sample_rate / target_sample_rate == 0 {
+            eprintln!("Invalid ratio calculated from sample rates");
+            return Err(SPECTROGRAM_GENERATION_FAILURE);
+        }
+
+        let sample_ratio = (sample_rate / target_sample_rate) as usize;
+        let taps: Vec<f64> =
+            FilterSpec::new(sample_rate, sample_ratio, config.max_frequency).taps();
+
+        Ok(Decimator {
+            buffer: vec![0.0; taps.len() / 2],
+            taps,
+            sample_ratio,
+            buffer_start: 0,
+            next_output: 0,
+        })
+    }
+
+    /// Feed the next samples, returning the decimated samples they completed.
+    pub fn push(&mut self, samples: &[f64]) -> Vec<f64> {
+        self.buffer.extend_from_slice(samples);
+
+        let mut output = Vec::<f64>::new();
+
+        while self.next_output * self.sample_ratio + self.taps.len()
+            <= self.buffer_start + self.buffer.len()
+        {
+            let start = self.next_output * self.sample_ratio - self.buffer_start;
+            let window = &self.buffer[start..start + self.taps.len()];
+
+            output.push(symmetric_convolution(&self.taps, window));
+            self.next_output += 1;
+        }
+
+        // Keep only the samples later outputs still need
+        let consumed =
+            (self.next_output * self.sample_ratio - self.buffer_start).min(self.buffer.len());
+        self.buffer.drain(..consumed);
+        self.buffer_start += consumed;
+
+        output
+    }
+
+    /// Signal the end of the channel, returning the remaining decimated samples: one for every
+    /// `sample_ratio` input samples, rounded up, treating the audio after the end as silence.
+    pub fn finish(&mut self) -> Vec<f64> {
+        let padding = vec![0.0; self.taps.len() / 2];
+
+        self.push(&padding)
+    }
+}
+
+// The filtered value at the middle of `window`, as long as `taps`. A linear-phase filter's taps
+// are symmetric, so each is multiplied with the sum of the two samples it applies to.
+fn symmetric_convolution(taps: &[f64], window: &[f64]) -> f64 {
+    let middle = taps.len() / 2;
+    let mirrored = window[middle + 1..].iter().rev();
+
+    taps[..middle]
+        .iter()
+        .zip(window[..middle].iter().zip(mirrored))
+        .map(|(tap, (early, late))| tap * (early + late))
+        .sum::<f64>()
+        + taps[middle] * window[middle]
+}
+
+// The zeroth-order modified Bessel function of the first kind, from its power series.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The passband may deviate from unity gain by this much.
+    const MAX_PASSBAND_RIPPLE_DB: f64 = 0.01;
+
+    // Sample rates and decimation ratios the filter is checked for
+    const SAMPLE_RATES: [u32; 4] = [22050, 44100, 48000, 96000];
+    const SAMPLE_RATIOS: [usize; 4] = [1, 2, 4, 6];
+
+    // The gain of a filter at `frequency` cycles per sample, in dB.
+    fn gain_db(taps: &[f64], frequency: f64) -> f64 {
+        let (re, im) = taps
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (idx, tap)| {
+                let phase = 2.0 * PI * frequency * idx as f64;
+                (re + tap * phase.cos(), im - tap * phase.sin())
+            });
+
+        20.0 * (re * re + im * im).sqrt().max(f64::MIN_POSITIVE).log10()
+    }
+
+    // The gain furthest from `initial` by `worst` over a fine grid of `low` to `high` Hz.
+    fn worst_gain(
+        spec: &FilterSpec,
+        low: f64,
+        high: f64,
+        worst: fn(f64, f64) -> f64,
+        initial: f64,
+    ) -> f64 {
+        const POINTS: usize = 2000;
+        let taps: Vec<f64> = spec.taps();
+
+        (0..=POINTS)
+            .map(|idx| low + (high - low) * idx as f64 / POINTS as f64)
+            .map(|frequency| gain_db(&taps, frequency / spec.sample_rate))
+            .fold(initial, worst)
+    }
+
+    fn filter_specs() -> impl Iterator<Item = FilterSpec> {
+        let max_frequency = FingerprintConfig::default().max_frequency;
+
+        SAMPLE_RATES.into_iter().flat_map(move |sample_rate| {
+            SAMPLE_RATIOS
+                .into_iter()
+                .map(move |ratio| FilterSpec::new(sample_rate, ratio, max_frequency))
+        })
+    }
+
+    #[test]
+    fn passband_ripple_is_within_design() {
+        for spec in filter_specs() {
+            let ripple = worst_gain(
+                &spec,
+                0.0,
+                spec.passband_edge,
+                |worst, gain| worst.max(gain.abs()),
+                0.0,
+            );
+
+            assert!(
+                ripple <= MAX_PASSBAND_RIPPLE_DB,
+                "{} dB of ripple up to {} Hz at {} Hz",
+                ripple,
+                spec.passband_edge,
+                spec.sample_rate
+            );
+        }
+    }
+
+    #[test]
+    fn stopband_attenuation_is_within_design() {
+        // Without decimation the stopband is only the Nyquist frequency itself, and nothing can
+        // alias
+        for spec in filter_specs().filter(|spec| spec.stopband_edge < spec.sample_rate / 2.0) {
+            let nyquist = spec.sample_rate / 2.0;
+            let gain = worst_gain(
+                &spec,
+                spec.stopband_edge,
+                nyquist,
+                f64::max,
+                f64::NEG_INFINITY,
+            );
+
+            assert!(
+                -gain >= STOPBAND_ATTENUATION_DB,
+                "only {} dB of attenuation from {} Hz at {} Hz",
+                -gain,
+                spec.stopband_edge,
+                spec.sample_rate
+            );
+        }
+    }
+}
@@ -9,5 +9,19 @@ pub const SPECTROGRAM_GENERATION_FAILURE: u8 = 4;
 pub const DATABASE_INSERT_ERROR: u8 = 5;
 pub const DATABASE_QUERY_ERROR: u8 = 6;
 pub const SPOTIFY_ERROR: u8 = 7;
+pub const INVALID_PROFILE_ERROR: u8 = 8;
+pub const PROFILE_MISMATCH_ERROR: u8 = 9;
+pub const INDEX_FILE_ERROR: u8 = 10;
+pub const FINGERPRINT_FILE_ERROR: u8 = 11;
+pub const SERVER_ERROR: u8 = 12;
+pub const INVALID_REQUEST_ERROR: u8 = 13;
 
-pub const MATCH_SCORE_THRESHOLD: f64 = 15.;
\ No newline at end of file
+// The least score a song needs to be a match. A hit weighs at most 1 (a hash only one song has)
+// and an isolated hit half that, so this takes about 15 aligned hits on rare hashes: chance
+// hits on a song a snippet is not from stay well below it, and the scorer tests in
+// evaluation.rs check it still falls between true matches and impostors. Triplet matches are
+// weighed the same way, so one threshold serves both.
+pub const MATCH_SCORE_THRESHOLD: f64 = 15.;
+
+// Audio formats `get_wav_info` can decode; other files are ignored when adding a directory.
+pub const SUPPORTED_AUDIO_EXTENSIONS: [&str; 1] = ["wav"];
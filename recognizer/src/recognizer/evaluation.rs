@@ -0,0 +1,380 @@
+// file: src/recognizer/evaluation.rs
+// purpose: scores a labelled corpus of snippets against the database with the matcher's scorer
+// and the simpler one it replaced, and reports how well each tells a snippet's song apart from
+// the songs it does not come from.
+
+use crate::db::db_utils;
+use crate::db::index_utils::FingerprintIndex;
+use crate::ingest::ingest_utils;
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::MATCH_SCORE_THRESHOLD;
+use crate::recognizer::fingerprint::{self, AudioFingerprint, fingerprint_peaks};
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::shazam::{
+    Hit, coherent_score, find_hits, offset_bin_score, score_songs_by_triplets,
+};
+use crate::recognizer::wav::WavStream;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// Snippets named with this prefix come from no song in the database
+const NEGATIVE_PREFIX: &str = "none_";
+
+struct Scorer {
+    name: &'static str,
+    score: fn(&[Hit]) -> f64,
+}
+
+const SCORERS: [Scorer; 2] = [
+    Scorer {
+        name: "coherent",
+        score: coherent_score,
+    },
+    Scorer {
+        name: "offset bin",
+        score: offset_bin_score,
+    },
+];
+
+// How one scorer did over the corpus
+struct Tally {
+    identified: usize,
+    false_accepts: usize,
+    lowest_true_score: f64,
+    highest_impostor_score: f64,
+}
+
+impl Tally {
+    fn new() -> Tally {
+        Tally {
+            identified: 0,
+            false_accepts: 0,
+            lowest_true_score: f64::INFINITY,
+            highest_impostor_score: 0.0,
+        }
+    }
+
+    // Count a snippet whose song scored `true_score` (`None` if it comes from no song in the
+    // database) and whose best other song scored `best_other_score`.
+    fn add(&mut self, true_score: Option<f64>, best_other_score: f64) {
+        self.highest_impostor_score = self.highest_impostor_score.max(best_other_score);
+
+        if let Some(true_score) = true_score {
+            self.lowest_true_score = self.lowest_true_score.min(true_score);
+
+            if true_score > best_other_score && true_score >= MATCH_SCORE_THRESHOLD {
+                self.identified += 1;
+            }
+            if best_other_score >= true_score && best_other_score >= MATCH_SCORE_THRESHOLD {
+                self.false_accepts += 1;
+            }
+        } else if best_other_score >= MATCH_SCORE_THRESHOLD {
+            self.false_accepts += 1;
+        }
+    }
+
+    // Above 1, some threshold accepts every true match and rejects every other song
+    fn separation(&self) -> f64 {
+        self.lowest_true_score / self.highest_impostor_score.max(1.0)
+    }
+}
+
+// Every song's score of its hits by `scorer`.
+fn scores_by(hits: &HashMap<u32, Vec<Hit>>, scorer: &Scorer) -> HashMap<u32, f64> {
+    hits.iter()
+        .map(|(song_id, song_hits)| (*song_id, (scorer.score)(song_hits)))
+        .collect()
+}
+
+// The score of the song a snippet comes from (0 if it has no hits, `None` for a snippet from no
+// song in the database) and the best score of any other song, given every song's score.
+fn snippet_scores(
+    scores: &HashMap<u32, f64>,
+    labelled: bool,
+    true_song: Option<u32>,
+) -> (Option<f64>, f64) {
+    let best_other_score: f64 = scores
+        .iter()
+        .filter(|(song_id, _)| Some(**song_id) != true_song)
+        .map(|(_, score)| *score)
+        .fold(0.0, f64::max);
+    let true_score: Option<f64> = labelled.then(|| {
+        true_song
+            .and_then(|song_id| scores.get(&song_id).copied())
+            .unwrap_or(0.0)
+    });
+
+    (true_score, best_other_score)
+}
+
+/// Score every snippet in `dir` (searched recursively) with each scorer, and by its triplet hashes
+/// if the profile has them, and print, per snippet, the score of the song it comes from and the
+/// best score of any other song, then how many snippets each scorer identified, how many it would
+/// have named a wrong song for, and how far apart its lowest true score and highest impostor score
+/// are.
+///
+/// A snippet's file is named `title_artist_anything.wav` after the song it was cut from, like a
+/// song added to the database, or `none_anything.wav` if it comes from no song in the database.
+pub fn evaluate_scorers(
+    dir: &str,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+    index: &dyn FingerprintIndex,
+) -> Result<(), u8> {
+    let snippets: Vec<PathBuf> = ingest_utils::collect_audio_files(Path::new(dir))?;
+    let mut song_names = HashMap::<u32, (String, String)>::new();
+    let mut positives = 0;
+
+    let mut names: Vec<&str> = SCORERS.iter().map(|scorer| scorer.name).collect();
+    if config.triplet_hashes {
+        names.push("triplets");
+    }
+    let mut tallies: Vec<Tally> = names.iter().map(|_| Tally::new()).collect();
+
+    print!("{:<40}", "snippet");
+    for name in &names {
+        print!(" {:>23}", format!("{} (true / other)", name));
+    }
+    println!();
+
+    for path in &snippets {
+        let file_path: String = path.to_string_lossy().to_string();
+        let label: Option<(String, String)> = snippet_label(&file_path)?;
+
+        let mut wav_stream = WavStream::open(&file_path)?;
+        let peaks = fingerprint::audio_peaks(&mut wav_stream, config, preprocessing)?;
+        let snippet_fingerprint: AudioFingerprint = fingerprint_peaks(&peaks, 0, config, 1.0);
+        let pairs: HashMap<u32, u32> = snippet_fingerprint
+            .pairs
+            .into_iter()
+            .map(|(hash, key_audio_point)| (hash, key_audio_point.anchor_time_ms as u32))
+            .collect();
+        let hits: HashMap<u32, Vec<Hit>> = find_hits(index, &pairs)?;
+
+        // Every song's score by each scorer, in the order of `names`
+        let mut song_scores: Vec<HashMap<u32, f64>> = SCORERS
+            .iter()
+            .map(|scorer| scores_by(&hits, scorer))
+            .collect();
+        if config.triplet_hashes {
+            song_scores.push(
+                score_songs_by_triplets(index, snippet_fingerprint.triplets)?
+                    .into_iter()
+                    .map(|(song_id, (score, _))| (song_id, score))
+                    .collect(),
+            );
+        }
+
+        let mut true_song: Option<u32> = None;
+        if let Some((title, artist)) = &label {
+            positives += 1;
+
+            for song_id in song_scores.iter().flat_map(|scores| scores.keys()) {
+                if !song_names.contains_key(song_id) {
+                    let song = db_utils::get_song_by_id(*song_id)?;
+                    song_names.insert(*song_id, (song.title, song.artist));
+                }
+                if song_names[song_id] == (title.clone(), artist.clone()) {
+                    true_song = Some(*song_id);
+                }
+            }
+
+            if true_song.is_none() && !db_utils::song_exists(title, artist)? {
+                eprintln!(
+                    "WARNING: `{}` is labelled `{}` by `{}`, which is not in the database",
+                    file_path, title, artist
+                );
+            }
+        }
+
+        print!("{:<40}", file_name(path));
+
+        for (scores, tally) in song_scores.iter().zip(tallies.iter_mut()) {
+            let (true_score, best_other_score) = snippet_scores(scores, label.is_some(), true_song);
+            tally.add(true_score, best_other_score);
+
+            match true_score {
+                Some(true_score) => print!(" {:>11.1} / {:<9.1}", true_score, best_other_score),
+                None => print!(" {:>11} / {:<9.1}", "-", best_other_score),
+            }
+        }
+        println!();
+    }
+
+    println!();
+    for (name, tally) in names.iter().zip(&tallies) {
+        println!(
+            "{}: {}/{} identified, {} of {} snippet(s) falsely matched at a score of {} or more",
+            name,
+            tally.identified,
+            positives,
+            tally.false_accepts,
+            snippets.len(),
+            MATCH_SCORE_THRESHOLD
+        );
+
+        if positives > 0 {
+            println!(
+                "  lowest true score {:.1}, highest impostor score {:.1}: separation {:.2}",
+                tally.lowest_true_score,
+                tally.highest_impostor_score,
+                tally.separation()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// The title and artist of the song a snippet comes from, or `None` for a negative snippet.
+fn snippet_label(file_path: &str) -> Result<Option<(String, String)>, u8> {
+    if file_name(Path::new(file_path)).starts_with(NEGATIVE_PREFIX) {
+        return Ok(None);
+    }
+
+    let (title, artist, _) = ingest_utils::get_song_info(file_path)?;
+
+    Ok(Some((title, artist)))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::index_utils::MemoryIndex;
+    use crate::recognizer::fingerprint::KeyAudioPoint;
+    use crate::recognizer::spectrogram::PeakMethod;
+    use crate::recognizer::test_audio::{self, SAMPLE_RATE};
+    use crate::recognizer::triplet::TripletPoint;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const SONG_COUNT: u64 = 6;
+    const SONG_DURATION_SEC: f64 = 20.0;
+
+    // Where each snippet is cut from its song, how long it is (both in seconds) and the peak
+    // level of the noise added to it (16-bit scale)
+    const SNIPPETS: [(f64, f64, f64); 4] = [
+        (1.3, 2.0, 0.0),
+        (6.7, 5.0, 0.0),
+        (12.1, 3.0, 1500.0),
+        (3.9, 6.0, 3000.0),
+    ];
+
+    // Score snippets of synthetic songs in the index and of one that is not, and noise, with
+    // each scorer, and then by their triplet hashes if `config` has them.
+    fn score_corpus(config: &FingerprintConfig) -> Vec<Tally> {
+        let preprocessing = Preprocessing::default();
+        let mut rng = StdRng::seed_from_u64(42);
+
+        // The last song is left out of the index, so its snippets match none
+        let songs: Vec<Vec<f64>> = (0..=SONG_COUNT)
+            .map(|seed| test_audio::synthetic_song(SONG_DURATION_SEC, 420 + seed))
+            .collect();
+        let mut points = Vec::<(u32, KeyAudioPoint)>::new();
+        let mut triplet_points = Vec::<(u32, TripletPoint)>::new();
+        for (idx, song) in songs[..SONG_COUNT as usize].iter().enumerate() {
+            let fingerprint: AudioFingerprint =
+                test_audio::fingerprint_samples(song, idx as u32 + 1, config, &preprocessing);
+            points.extend(fingerprint.pairs);
+            triplet_points.extend(fingerprint.triplets);
+        }
+        let index = MemoryIndex::from_points(points, triplet_points, SONG_COUNT as i64);
+
+        let mut snippets: Vec<(Vec<f64>, Option<u32>)> = Vec::new();
+        for (idx, song) in songs.iter().enumerate() {
+            let song_id = (idx < SONG_COUNT as usize).then_some(idx as u32 + 1);
+
+            for (start_sec, duration_sec, noise) in SNIPPETS {
+                let start = (start_sec * SAMPLE_RATE as f64) as usize;
+                let end = start + (duration_sec * SAMPLE_RATE as f64) as usize;
+                let snippet: Vec<f64> = song[start..end]
+                    .iter()
+                    .map(|sample| sample + noise * rng.gen_range(-1.0, 1.0))
+                    .collect();
+                snippets.push((snippet, song_id));
+            }
+        }
+        let noise: Vec<f64> = (0..5 * SAMPLE_RATE)
+            .map(|_| 3000.0 * rng.gen_range(-1.0, 1.0))
+            .collect();
+        snippets.push((noise, None));
+
+        let mut tallies: Vec<Tally> = SCORERS.iter().map(|_| Tally::new()).collect();
+        let mut triplet_tally = Tally::new();
+        for (snippet, song_id) in &snippets {
+            let fingerprint: AudioFingerprint =
+                test_audio::fingerprint_samples(snippet, 0, config, &preprocessing);
+            let pairs: HashMap<u32, u32> = fingerprint
+                .pairs
+                .into_iter()
+                .map(|(hash, point)| (hash, point.anchor_time_ms as u32))
+                .collect();
+            let hits: HashMap<u32, Vec<Hit>> = find_hits(&index, &pairs).unwrap();
+
+            for (scorer, tally) in SCORERS.iter().zip(tallies.iter_mut()) {
+                let (true_score, best_other_score) =
+                    snippet_scores(&scores_by(&hits, scorer), song_id.is_some(), *song_id);
+                tally.add(true_score, best_other_score);
+            }
+
+            let triplet_scores: HashMap<u32, f64> =
+                score_songs_by_triplets(&index, fingerprint.triplets)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(song_id, (score, _))| (song_id, score))
+                    .collect();
+            let (true_score, best_other_score) =
+                snippet_scores(&triplet_scores, song_id.is_some(), *song_id);
+            triplet_tally.add(true_score, best_other_score);
+        }
+
+        if config.triplet_hashes {
+            tallies.push(triplet_tally);
+        }
+
+        tallies
+    }
+
+    #[test]
+    fn coherent_scorer_separates_true_matches_from_impostors() {
+        let tallies = score_corpus(&FingerprintConfig::default());
+        let (coherent, offset_bin) = (&tallies[0], &tallies[1]);
+        let positives = SONG_COUNT as usize * SNIPPETS.len();
+
+        assert_eq!(coherent.identified, positives);
+        assert_eq!(coherent.false_accepts, 0);
+        // The threshold falls between the true matches and the impostors
+        assert!(coherent.lowest_true_score >= MATCH_SCORE_THRESHOLD);
+        assert!(coherent.highest_impostor_score < MATCH_SCORE_THRESHOLD);
+        assert!(
+            coherent.separation() > 1.5 * offset_bin.separation(),
+            "coherent separation {:.2}, offset bin separation {:.2}",
+            coherent.separation(),
+            offset_bin.separation()
+        );
+    }
+
+    #[test]
+    fn triplet_scores_separate_true_matches_from_impostors() {
+        let config = FingerprintConfig {
+            peak_method: PeakMethod::Constellation,
+            triplet_hashes: true,
+            ..Default::default()
+        };
+        let tallies = score_corpus(&config);
+        let triplets = &tallies[SCORERS.len()];
+        let positives = SONG_COUNT as usize * SNIPPETS.len();
+
+        assert_eq!(triplets.identified, positives);
+        assert_eq!(triplets.false_accepts, 0);
+        // Triplet scores are held to the same threshold as pair scores
+        assert!(triplets.lowest_true_score >= MATCH_SCORE_THRESHOLD);
+        assert!(triplets.highest_impostor_score < MATCH_SCORE_THRESHOLD);
+    }
+}
@@ -0,0 +1,335 @@
+// file: src/recognizer/explain.rs
+// purpose: explains how a snippet was matched: how many of its hashes the index holds, which
+// songs they were found in, how the best song's hits spread over offsets, and how far ahead of
+// the runner-up it finished, with a scatter of the best song's hits as text or SVG.
+
+use crate::db::db_utils;
+use crate::db::index_utils::FingerprintIndex;
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::MATCH_SCORE_THRESHOLD;
+use crate::recognizer::fingerprint::fingerprint_peaks;
+use crate::recognizer::shazam::{
+    Hit, Match, OFFSET_BIN_MS, OffsetMatch, coherent_match, find_hits,
+};
+use crate::recognizer::spectrogram::Peak;
+use std::collections::HashMap;
+
+// Songs listed with their hits, best first
+const CANDIDATES_SHOWN: usize = 5;
+
+// Offset bins shown either side of the best song's peak
+const HISTOGRAM_RADIUS_BINS: i32 = 10;
+const HISTOGRAM_BAR_WIDTH: usize = 40;
+
+const ASCII_PLOT_COLUMNS: usize = 64;
+const ASCII_PLOT_ROWS: usize = 20;
+
+const SVG_WIDTH: f64 = 640.;
+const SVG_HEIGHT: f64 = 480.;
+const SVG_MARGIN: f64 = 60.;
+
+/// How the scatter of the best song's hits is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PlotFormat {
+    /// Characters on a grid, for the terminal
+    Ascii,
+    /// An SVG image
+    Svg,
+}
+
+// A song sharing hashes with the snippet, and where they line up best.
+struct Candidate {
+    song_id: u32,
+    hits: Vec<Hit>,
+    offset_match: OffsetMatch,
+}
+
+/// Print how the snippet with the given peaks matched, given the `matches` found for it (best
+/// first). Its pair hashes are broken down at the speed the best match was found at. Returns the
+/// scatter of the best song's hits (snippet time against song time) in `plot` format, if asked
+/// for one and any song was hit.
+pub fn explain_match(
+    channel_peaks: &[Vec<Peak>],
+    config: &FingerprintConfig,
+    matches: &[Match],
+    index: &dyn FingerprintIndex,
+    plot: Option<PlotFormat>,
+) -> Result<Option<String>, u8> {
+    let speed: f64 = matches.first().map_or(1.0, |best| best.speed_factor);
+
+    let fingerprint: HashMap<u32, u32> = fingerprint_peaks(channel_peaks, 0, config, speed)
+        .pairs
+        .into_iter()
+        .map(|(hash, key_audio_point)| (hash, key_audio_point.anchor_time_ms as u32))
+        .collect();
+    let hashes: Vec<u32> = fingerprint.keys().copied().collect();
+    let found_hashes: usize = index.key_audio_points(&hashes)?.len();
+
+    println!(
+        "Snippet hashes at {:.3}x speed: {}, of which {} ({:.1}%) are in the index",
+        speed,
+        hashes.len(),
+        found_hashes,
+        100. * found_hashes as f64 / hashes.len().max(1) as f64
+    );
+
+    let mut candidates: Vec<Candidate> = find_hits(index, &fingerprint)?
+        .into_iter()
+        .filter_map(|(song_id, hits)| {
+            coherent_match(&hits).map(|offset_match| Candidate {
+                song_id,
+                hits,
+                offset_match,
+            })
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.offset_match.score.total_cmp(&a.offset_match.score));
+
+    println!(
+        "Songs sharing a hash with the snippet: {}{}",
+        candidates.len(),
+        if candidates.len() > CANDIDATES_SHOWN {
+            format!(", the best {}:", CANDIDATES_SHOWN)
+        } else {
+            String::new()
+        }
+    );
+    for (idx, candidate) in candidates.iter().take(CANDIDATES_SHOWN).enumerate() {
+        let song = db_utils::get_song_by_id(candidate.song_id)?;
+
+        println!(
+            "{:>3}. {} by {}: {} hits, {} of them at offset {:.2}s, score {:.1}",
+            idx + 1,
+            song.title,
+            song.artist,
+            candidate.hits.len(),
+            candidate.offset_match.aligned_hits,
+            candidate.offset_match.offset_ms as f64 / 1000.,
+            candidate.offset_match.score
+        );
+    }
+
+    match matches {
+        [] => println!("No song was matched"),
+        [best] => println!(
+            "Best score {:.1} ({}), with no runner-up",
+            best.score, best.name
+        ),
+        [best, runner_up, ..] => println!(
+            "Best score {:.1} ({}), runner-up {:.1} ({}): ahead by {:.1}, {:.2}x the runner-up's",
+            best.score,
+            best.name,
+            runner_up.score,
+            runner_up.name,
+            best.score - runner_up.score,
+            best.score / runner_up.score.max(f64::MIN_POSITIVE)
+        ),
+    }
+    if let Some(best) = matches.first() {
+        println!(
+            "A match needs a score of at least {}: {}",
+            MATCH_SCORE_THRESHOLD,
+            if best.score >= MATCH_SCORE_THRESHOLD {
+                "reached"
+            } else {
+                "not reached"
+            }
+        );
+    }
+    if config.triplet_hashes {
+        println!("Triplet hashes count towards these scores, but are not broken down here");
+    }
+
+    let Some(best) = candidates.first() else {
+        return Ok(None);
+    };
+
+    print!("{}", offset_histogram(best));
+
+    let song = db_utils::get_song_by_id(best.song_id)?;
+    let title = format!("{} by {}", song.title, song.artist);
+
+    Ok(plot.map(|format| match format {
+        PlotFormat::Ascii => ascii_scatter(best, &title),
+        PlotFormat::Svg => svg_scatter(best, &title),
+    }))
+}
+
+// The number and weight of a song's hits in each offset bin around its peak, with a bar for
+// the number.
+fn offset_histogram(candidate: &Candidate) -> String {
+    let peak_bin: i32 = candidate.offset_match.offset_bin;
+
+    let mut bins = HashMap::<i32, (usize, f64)>::new();
+    for hit in &candidate.hits {
+        let (count, weight) = bins.entry(hit.offset_bin()).or_default();
+        *count += 1;
+        *weight += hit.weight;
+    }
+
+    let shown_bins = peak_bin - HISTOGRAM_RADIUS_BINS..=peak_bin + HISTOGRAM_RADIUS_BINS;
+    let max_count: usize = shown_bins
+        .clone()
+        .filter_map(|bin| bins.get(&bin).map(|(count, _)| *count))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let mut histogram = format!(
+        "Hits by offset (song time - snippet time) in {} ms bins: offset, hits, weight\n",
+        OFFSET_BIN_MS
+    );
+    for bin in shown_bins {
+        let (count, weight) = bins.get(&bin).copied().unwrap_or_default();
+        let bar_length = (count as f64 / max_count as f64 * HISTOGRAM_BAR_WIDTH as f64).round();
+
+        histogram += &format!(
+            "{:>9.1}s {:>5} {:>7.2} |{}{}\n",
+            (bin * OFFSET_BIN_MS) as f64 / 1000.,
+            count,
+            weight,
+            "#".repeat(bar_length as usize),
+            if bin == peak_bin { "  <- peak" } else { "" }
+        );
+    }
+
+    histogram
+}
+
+// Whether a hit is one of those agreeing on the song's offset.
+fn is_aligned(candidate: &Candidate, hit: &Hit) -> bool {
+    (hit.offset_bin() - candidate.offset_match.offset_bin).abs() <= 1
+}
+
+// The ranges of snippet time and song time the hits cover, in ms, neither empty.
+fn plot_ranges(hits: &[Hit]) -> ((f64, f64), (f64, f64)) {
+    let x_max = hits.iter().map(|hit| hit.sample_time_ms).max().unwrap_or(0) as f64;
+    let y_min = hits.iter().map(|hit| hit.db_time_ms).min().unwrap_or(0) as f64;
+    let y_max = hits.iter().map(|hit| hit.db_time_ms).max().unwrap_or(0) as f64;
+
+    ((0.0, x_max.max(1.0)), (y_min, y_max.max(y_min + 1.0)))
+}
+
+// Snippet time across, song time up: `*` where hits at the song's offset fall, `.` where only
+// others do.
+fn ascii_scatter(candidate: &Candidate, title: &str) -> String {
+    let ((x_min, x_max), (y_min, y_max)) = plot_ranges(&candidate.hits);
+    let mut grid = vec![vec![' '; ASCII_PLOT_COLUMNS]; ASCII_PLOT_ROWS];
+
+    for hit in &candidate.hits {
+        let column = ((hit.sample_time_ms as f64 - x_min) / (x_max - x_min)
+            * (ASCII_PLOT_COLUMNS - 1) as f64)
+            .round() as usize;
+        let row = ASCII_PLOT_ROWS
+            - 1
+            - ((hit.db_time_ms as f64 - y_min) / (y_max - y_min) * (ASCII_PLOT_ROWS - 1) as f64)
+                .round() as usize;
+
+        if is_aligned(candidate, hit) {
+            grid[row][column] = '*';
+        } else if grid[row][column] == ' ' {
+            grid[row][column] = '.';
+        }
+    }
+
+    let mut plot = format!(
+        "Hits of {}: snippet time across, song time up; * at the matched offset\n",
+        title
+    );
+    for (row, cells) in grid.iter().enumerate() {
+        let label = match row {
+            0 => format!("{:.1}s", y_max / 1000.),
+            _ if row == ASCII_PLOT_ROWS - 1 => format!("{:.1}s", y_min / 1000.),
+            _ => String::new(),
+        };
+        plot += &format!("{:>8} |{}\n", label, cells.iter().collect::<String>());
+    }
+    plot += &format!("{:>8} +{}\n", "", "-".repeat(ASCII_PLOT_COLUMNS));
+    plot += &format!(
+        "{:>10}{:>width$}\n",
+        format!("{:.1}s", x_min / 1000.),
+        format!("{:.1}s", x_max / 1000.),
+        width = ASCII_PLOT_COLUMNS - 1
+    );
+
+    plot
+}
+
+// The same scatter as an SVG image, with the line of the matched offset dashed through it.
+fn svg_scatter(candidate: &Candidate, title: &str) -> String {
+    let ((x_min, x_max), (y_min, y_max)) = plot_ranges(&candidate.hits);
+    let x = |time_ms: f64| {
+        SVG_MARGIN + (time_ms - x_min) / (x_max - x_min) * (SVG_WIDTH - 2. * SVG_MARGIN)
+    };
+    let y = |time_ms: f64| {
+        SVG_HEIGHT
+            - SVG_MARGIN
+            - (time_ms - y_min) / (y_max - y_min) * (SVG_HEIGHT - 2. * SVG_MARGIN)
+    };
+
+    let (left, right, top, bottom) = (x(x_min), x(x_max), y(y_max), y(y_min));
+    let offset_ms = candidate.offset_match.offset_ms as f64;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{SVG_WIDTH}\" height=\"{SVG_HEIGHT}\" \
+         font-family=\"sans-serif\" font-size=\"12\">\n\
+         <rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n\
+         <text x=\"{}\" y=\"24\" text-anchor=\"middle\" font-size=\"14\">Hits of {}</text>\n\
+         <clipPath id=\"plot\"><rect x=\"{left}\" y=\"{top}\" width=\"{}\" height=\"{}\"/></clipPath>\n\
+         <polyline points=\"{left},{top} {left},{bottom} {right},{bottom}\" fill=\"none\" stroke=\"black\"/>\n\
+         <text x=\"{}\" y=\"{}\" text-anchor=\"middle\">Snippet time (s)</text>\n\
+         <text x=\"{left}\" y=\"{}\" text-anchor=\"middle\">{:.1}</text>\n\
+         <text x=\"{right}\" y=\"{}\" text-anchor=\"middle\">{:.1}</text>\n\
+         <text transform=\"translate({}, {}) rotate(-90)\" text-anchor=\"middle\">Song time (s)</text>\n\
+         <text x=\"{}\" y=\"{bottom}\" text-anchor=\"end\">{:.1}</text>\n\
+         <text x=\"{}\" y=\"{}\" text-anchor=\"end\">{:.1}</text>\n\
+         <line x1=\"{left}\" y1=\"{:.1}\" x2=\"{right}\" y2=\"{:.1}\" stroke=\"#1f77b4\" \
+         stroke-dasharray=\"4 4\" clip-path=\"url(#plot)\"/>\n",
+        SVG_WIDTH / 2.,
+        xml_text(title),
+        right - left,
+        bottom - top,
+        (left + right) / 2.,
+        SVG_HEIGHT - 16.,
+        bottom + 18.,
+        x_min / 1000.,
+        bottom + 18.,
+        x_max / 1000.,
+        SVG_MARGIN / 3.,
+        (top + bottom) / 2.,
+        left - 6.,
+        y_min / 1000.,
+        left - 6.,
+        top + 4.,
+        y_max / 1000.,
+        y(x_min + offset_ms),
+        y(x_max + offset_ms),
+    );
+
+    // Hits at the matched offset are drawn last, so they are not hidden by the others
+    let (aligned, others): (Vec<&Hit>, Vec<&Hit>) = candidate
+        .hits
+        .iter()
+        .partition(|hit| is_aligned(candidate, hit));
+
+    for (hits, colour) in [(others, "#999999"), (aligned, "#d62728")] {
+        for hit in hits {
+            svg += &format!(
+                "<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"2\" fill=\"{}\"/>\n",
+                x(hit.sample_time_ms as f64),
+                y(hit.db_time_ms as f64),
+                colour
+            );
+        }
+    }
+
+    svg + "</svg>\n"
+}
+
+// Text with the characters XML gives meaning to escaped.
+fn xml_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
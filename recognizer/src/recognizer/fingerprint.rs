@@ -1,96 +1,683 @@
 // file: src/recognizer/fingerprint.rs
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use crate::recognizer::spectrogram::{gen_spectrogram, get_peaks, Peak};
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::constellation::ConstellationPeakPicker;
+use crate::recognizer::declarations::INCOMPATIBLE_FILE_ERROR;
+use crate::recognizer::loudness::TimeRange;
+use crate::recognizer::preprocess::{LevelAdjustment, Preprocessing};
+use crate::recognizer::spectrogram::{Peak, PeakMethod, SpectrogramStream, get_peaks};
+use crate::recognizer::triplet::{TripletPoint, triplet_hash};
 use crate::recognizer::wav;
 
-const MAX_FREQUENCY_BITS: i32 = 9;
-const MAX_TIME_DELTA_BITS: i32 = 14;
-const TARGET_ZONE_SIZE: usize = 5;
+// Samples per channel decoded at a time. Together with the fingerprints themselves, this bounds
+// the memory `fingerprint_audio` uses, however long the recording is.
+pub const CHUNK_FRAMES: usize = 1 << 16;
 
 pub struct KeyAudioPoint {
     pub anchor_time_ms: i32,
     pub song_id: i32,
 }
 
+/// The hashes of a recording: a map of pair hash to KeyAudioPoint and, if the profile enables
+/// `triplet_hashes`, every triplet hash with where it occurred. A triplet hash can occur many
+/// times in a recording, so none of its occurrences are dropped. `skipped_ranges` are the
+/// stretches of silence no hashes were made from.
+#[derive(Default)]
+pub struct AudioFingerprint {
+    pub pairs: HashMap<u32, KeyAudioPoint>,
+    pub triplets: Vec<(u32, TripletPoint)>,
+    pub skipped_ranges: Vec<TimeRange>,
+}
+
+impl AudioFingerprint {
+    fn extend(&mut self, other: AudioFingerprint) {
+        self.pairs.extend(other.pairs);
+        self.triplets.extend(other.triplets);
+    }
+}
+
 /** Generates the "fingerprint" of an audio file, returning a hash map where
 the key is the unique hash generated from anchor-target pairs and the value is a list of anchor
-times and the associated song.
+times and the associated song, along with the triplet hashes if the profile enables them. The
+audio is preprocessed first as `preprocessing` says.
+
+This is CPU-bound work (decoding, filtering, FFTs and hashing): call it from a blocking-capable
+thread such as `tokio::task::spawn_blocking`, never directly on an async runtime worker. The two
+channels are analysed in parallel on rayon's thread pool.
 */
-pub async fn fingerprint_audio(
+pub fn fingerprint_audio(
     file_path: String,
     song_id: u32,
-) -> Result<HashMap<u32, KeyAudioPoint>, u8> {
-    let wav_info: wav::WavInfo = wav::get_wav_info(&file_path)?;
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+) -> Result<AudioFingerprint, u8> {
+    let mut wav_stream = wav::WavStream::open(&file_path)?;
+    let sample_rate = wav_stream.info.spec.sample_rate;
+    let adjustment: LevelAdjustment = level_adjustment(&mut wav_stream, preprocessing)?;
+
+    fingerprint_chunks(
+        std::iter::from_fn(|| wav_stream.next_chunk(CHUNK_FRAMES)),
+        sample_rate,
+        song_id,
+        config,
+        preprocessing,
+        &adjustment,
+    )
+}
+
+/// Fingerprint audio that is already decoded, given as consecutive chunks of the left and right
+/// channels' samples (the right channel's chunks are empty for mono audio), adjusted as
+/// `adjustment` says.
+pub fn fingerprint_chunks(
+    chunks: impl Iterator<Item = (Vec<f64>, Vec<f64>)>,
+    sample_rate: u32,
+    song_id: u32,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+    adjustment: &LevelAdjustment,
+) -> Result<AudioFingerprint, u8> {
+    config.check_sample_rate(sample_rate)?;
 
-    let mut fingerprint: HashMap<u32, KeyAudioPoint> = HashMap::new();
+    let (left_fingerprint, right_fingerprint) = analyse_channels(
+        chunks.map(|chunk| apply_gain(chunk, adjustment.gain)),
+        ChannelFingerprinter::new(sample_rate, song_id, config, preprocessing, adjustment)?,
+        ChannelFingerprinter::new(sample_rate, song_id, config, preprocessing, adjustment)?,
+    )?;
 
-    let left_spectrogram =
-        gen_spectrogram(wav_info.left_channel_samples, wav_info.spec.sample_rate)?;
+    let mut fingerprint: AudioFingerprint = left_fingerprint;
+    fingerprint.extend(right_fingerprint);
+    fingerprint.skipped_ranges = adjustment.skipped_ranges.clone();
 
-    let left_peaks = get_peaks(
-        left_spectrogram,
-        wav_info.duration_sec,
-        wav_info.spec.sample_rate,
+    Ok(fingerprint)
+}
+
+/// The peaks of each channel of an audio file (one channel for mono audio), so they can be
+/// fingerprinted more than once with `fingerprint_peaks`. Like `fingerprint_audio`, this is
+/// CPU-bound work. A level already measured by `WavStream::analyse_level` is reused.
+pub fn audio_peaks(
+    wav_stream: &mut wav::WavStream,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+) -> Result<Vec<Vec<Peak>>, u8> {
+    let (sample_rate, channels) = (
+        wav_stream.info.spec.sample_rate,
+        wav_stream.info.spec.channels,
     );
 
-    fingerprint.extend(gen_fingerprints(left_peaks, song_id));
+    let adjustment: LevelAdjustment = level_adjustment(wav_stream, preprocessing)?;
+
+    chunk_peaks(
+        std::iter::from_fn(|| wav_stream.next_chunk(CHUNK_FRAMES)),
+        sample_rate,
+        channels,
+        config,
+        preprocessing,
+        &adjustment,
+    )
+}
+
+/// The peaks of audio that is already decoded, given as `fingerprint_chunks` takes it, for each
+/// of its `channels` (one for mono audio).
+pub fn chunk_peaks(
+    chunks: impl Iterator<Item = (Vec<f64>, Vec<f64>)>,
+    sample_rate: u32,
+    channels: u16,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+    adjustment: &LevelAdjustment,
+) -> Result<Vec<Vec<Peak>>, u8> {
+    config.check_sample_rate(sample_rate)?;
+
+    let (left_peaks, right_peaks) = analyse_channels(
+        chunks.map(|chunk| apply_gain(chunk, adjustment.gain)),
+        ChannelPeaks::new(sample_rate, config, preprocessing, adjustment)?,
+        ChannelPeaks::new(sample_rate, config, preprocessing, adjustment)?,
+    )?;
+
+    if channels == 1 {
+        return Ok(vec![left_peaks]);
+    }
 
-    let right_spectrogram =
-        gen_spectrogram(wav_info.right_channel_samples, wav_info.spec.sample_rate)?;
+    Ok(vec![left_peaks, right_peaks])
+}
 
-    let right_peaks = get_peaks(
-        right_spectrogram,
-        wav_info.duration_sec,
-        wav_info.spec.sample_rate,
+/// The spectrogram of one channel of an audio file (0 is the left or only channel) as the
+/// fingerprinter sees it, preprocessed and downsampled: its frames of magnitudes by FFT bin, and
+/// its peaks, leaving out those in skipped ranges. Unlike `audio_peaks`, this keeps every frame in
+/// memory, so it is meant for inspecting recordings rather than fingerprinting them.
+pub fn channel_spectrogram(
+    wav_stream: &mut wav::WavStream,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+    channel: usize,
+) -> Result<(Vec<Vec<f64>>, Vec<Peak>), u8> {
+    let (sample_rate, channels) = (
+        wav_stream.info.spec.sample_rate,
+        wav_stream.info.spec.channels,
     );
 
-    fingerprint.extend(gen_fingerprints(right_peaks, song_id));
+    if channel >= channels as usize {
+        eprintln!(
+            "ERROR: Channel {} asked for, but the recording has {} channel(s)",
+            channel, channels
+        );
+        return Err(INCOMPATIBLE_FILE_ERROR);
+    }
 
-    Ok(fingerprint)
+    config.check_sample_rate(sample_rate)?;
+    let adjustment: LevelAdjustment = level_adjustment(wav_stream, preprocessing)?;
+
+    let mut peak_finder = ChannelPeakFinder::new(sample_rate, config, preprocessing, &adjustment)?;
+    peak_finder.kept_frames = Some(Vec::new());
+
+    let mut peaks = Vec::<Peak>::new();
+    while let Some(chunk) = wav_stream.next_chunk(CHUNK_FRAMES) {
+        let (left_samples, right_samples) = apply_gain(chunk, adjustment.gain);
+
+        peaks.extend(peak_finder.push(if channel == 0 {
+            &left_samples
+        } else {
+            &right_samples
+        })?);
+    }
+    peaks.extend(peak_finder.finish()?);
+
+    Ok((peak_finder.kept_frames.take().unwrap_or_default(), peaks))
 }
 
-/// Returns a map of hash value (u32) to a KeyAudioPoint. The hash is generated based on a Peak
-/// and TARGET_ZONE_SIZE number of Peaks after it.
-pub fn gen_fingerprints(peaks: Vec<Peak>, song_id: u32) -> HashMap<u32, KeyAudioPoint> {
-    let mut fingerprints = HashMap::<u32, KeyAudioPoint>::new();
+// How the steps of `preprocessing` that need the whole recording's level adjust it. Measuring the
+// level reads the whole file once more, unless it was measured already.
+fn level_adjustment(
+    wav_stream: &mut wav::WavStream,
+    preprocessing: &Preprocessing,
+) -> Result<LevelAdjustment, u8> {
+    if !preprocessing.needs_level() {
+        return Ok(LevelAdjustment::none());
+    }
+
+    let level = match wav_stream.info.level.clone() {
+        Some(level) => level,
+        None => wav_stream.analyse_level()?,
+    };
+
+    Ok(LevelAdjustment::new(&level, preprocessing))
+}
+
+// Scale both channels of a chunk of samples by `gain`.
+fn apply_gain(chunk: (Vec<f64>, Vec<f64>), gain: f64) -> (Vec<f64>, Vec<f64>) {
+    if gain == 1.0 {
+        return chunk;
+    }
+
+    let (left_samples, right_samples) = chunk;
+    let scale = |samples: Vec<f64>| samples.into_iter().map(|value| value * gain).collect();
+
+    (scale(left_samples), scale(right_samples))
+}
+
+/// Fingerprint the peaks found by `audio_peaks` as if the audio had been played at
+/// `playback_speed` times the speed it was recorded at: peak frequencies are divided by the
+/// speed and peak times multiplied by it (rounded to whole bins and frames) before pairing, so
+/// the hashes and anchor times are those of the recording at its original speed.
+pub fn fingerprint_peaks(
+    channel_peaks: &[Vec<Peak>],
+    song_id: u32,
+    config: &FingerprintConfig,
+    playback_speed: f64,
+) -> AudioFingerprint {
+    let mut fingerprint = AudioFingerprint::default();
 
-    for (i, anchor) in (&peaks).into_iter().enumerate() {
-        for j in ((i + 1)..peaks.len()).take_while(|&j| j <= i + TARGET_ZONE_SIZE) {
-            let target: &Peak = &peaks[j];
+    for peaks in channel_peaks {
+        let mut peak_pairer = PeakPairer::new(song_id, config);
 
-            let hash = gen_hash(&anchor, &target);
-            let anchor_time_ms: i32 = (anchor.time_sec * 1000.) as i32;
+        for peak in peaks {
+            let peak = Peak {
+                frequency: peak.frequency / playback_speed,
+                time_sec: peak.time_sec * playback_speed,
+                frequency_idx: (peak.frequency_idx as f64 / playback_speed).round() as usize,
+                frame_idx: (peak.frame_idx as f64 * playback_speed).round() as usize,
+            };
+
+            peak_pairer.push(peak, &mut fingerprint);
+        }
+
+        peak_pairer.finish(&mut fingerprint);
+    }
+
+    fingerprint
+}
+
+/// Every anchor-target pair made from the peaks of one channel, in order of time, each making
+/// one pair hash as `fingerprint_peaks` would.
+pub fn peak_pairs(peaks: &[Peak], config: &FingerprintConfig) -> Vec<(Peak, Peak)> {
+    let peak_pairer = PeakPairer::new(0, config);
+
+    peaks
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, anchor)| {
+            peak_pairer
+                .hashed_targets(anchor, &peaks[idx + 1..])
+                .map(|(_, target)| (*anchor, *target))
+                .collect::<Vec<(Peak, Peak)>>()
+        })
+        .collect()
+}
+
+// Work done on each channel's samples as they arrive, producing one result per channel.
+trait ChannelAnalysis: Send {
+    type Output: Send;
+
+    fn push(&mut self, samples: &[f64]) -> Result<(), u8>;
+
+    fn finish(self) -> Result<Self::Output, u8>;
+}
+
+// Feed every chunk to the left and right channels' analyses, running the two in parallel.
+fn analyse_channels<A: ChannelAnalysis>(
+    chunks: impl Iterator<Item = (Vec<f64>, Vec<f64>)>,
+    mut left_channel: A,
+    mut right_channel: A,
+) -> Result<(A::Output, A::Output), u8> {
+    for (left_samples, right_samples) in chunks {
+        let (left_result, right_result) = rayon::join(
+            || left_channel.push(&left_samples),
+            || right_channel.push(&right_samples),
+        );
+
+        left_result?;
+        right_result?;
+    }
+
+    let (left_output, right_output) =
+        rayon::join(|| left_channel.finish(), || right_channel.finish());
+
+    Ok((left_output?, right_output?))
+}
+
+// Spectrogram and peaks of a single channel, computed as its samples arrive.
+struct ChannelPeakFinder<'a> {
+    config: &'a FingerprintConfig,
+    skipped_ranges: Vec<TimeRange>,
+    spectrogram: SpectrogramStream,
+    sample_rate: u32,
+    frame_duration: f64,
+    frame_idx: usize,
+    peak_picker: PeakPicker,
+    kept_frames: Option<Vec<Vec<f64>>>, // every frame so far, if they are to be kept
+}
+
+// The per-band peaks need nothing but the current frame; the constellation needs the frames
+// around it.
+enum PeakPicker {
+    Bands,
+    Constellation(ConstellationPeakPicker),
+}
+
+impl<'a> ChannelPeakFinder<'a> {
+    fn new(
+        sample_rate: u32,
+        config: &'a FingerprintConfig,
+        preprocessing: &Preprocessing,
+        adjustment: &LevelAdjustment,
+    ) -> Result<ChannelPeakFinder<'a>, u8> {
+        // Peak times follow from the frame index alone, so a clip cut from a song has its peaks
+        // at the same times as the song's, shifted by where it was cut.
+        let frame_duration: f64 = config.frame_duration(sample_rate);
+
+        let peak_picker =
+            match config.peak_method {
+                PeakMethod::Bands => PeakPicker::Bands,
+                PeakMethod::Constellation => PeakPicker::Constellation(
+                    ConstellationPeakPicker::new(frame_duration, sample_rate, config),
+                ),
+            };
+
+        Ok(ChannelPeakFinder {
+            config,
+            skipped_ranges: adjustment.skipped_ranges.clone(),
+            spectrogram: SpectrogramStream::new(
+                sample_rate,
+                config,
+                preprocessing,
+                &adjustment.skipped_ranges,
+            )?,
+            sample_rate,
+            frame_duration,
+            frame_idx: 0,
+            peak_picker,
+            kept_frames: None,
+        })
+    }
+
+    // Returns the peaks completed by the samples, in order of time, except those in skipped
+    // ranges.
+    fn push(&mut self, samples: &[f64]) -> Result<Vec<Peak>, u8> {
+        let frames = self.spectrogram.push(samples)?;
+        let peaks = self.add_frames(frames);
+
+        Ok(self.without_skipped(peaks))
+    }
+
+    fn finish(&mut self) -> Result<Vec<Peak>, u8> {
+        let frames = self.spectrogram.finish()?;
+        let mut peaks = self.add_frames(frames);
+
+        if let PeakPicker::Constellation(constellation) = &mut self.peak_picker {
+            peaks.extend(constellation.finish());
+        }
+
+        Ok(self.without_skipped(peaks))
+    }
+
+    // The spectrogram already leaves skipped frames blank, but a constellation peak can still be
+    // picked from the edge of a neighbouring frame, so peaks are filtered as well.
+    fn without_skipped(&self, mut peaks: Vec<Peak>) -> Vec<Peak> {
+        if !self.skipped_ranges.is_empty() {
+            peaks.retain(|peak| {
+                !self
+                    .skipped_ranges
+                    .iter()
+                    .any(|range| range.contains(peak.time_sec))
+            });
+        }
 
-            fingerprints.insert(
+        peaks
+    }
+
+    fn add_frames(&mut self, frames: Vec<Vec<f64>>) -> Vec<Peak> {
+        let mut peaks = Vec::<Peak>::new();
+
+        for frame in frames {
+            if let Some(kept_frames) = &mut self.kept_frames {
+                kept_frames.push(frame.clone());
+            }
+
+            match &mut self.peak_picker {
+                PeakPicker::Bands => peaks.extend(get_peaks(
+                    &frame,
+                    self.frame_idx,
+                    self.frame_duration,
+                    self.sample_rate,
+                    self.config,
+                )),
+                PeakPicker::Constellation(constellation) => {
+                    peaks.extend(constellation.push_frame(frame))
+                }
+            }
+
+            self.frame_idx += 1;
+        }
+
+        peaks
+    }
+}
+
+// Peaks and hashes of a single channel, computed as its samples arrive.
+struct ChannelFingerprinter<'a> {
+    peak_finder: ChannelPeakFinder<'a>,
+    peak_pairer: PeakPairer,
+    fingerprints: AudioFingerprint,
+}
+
+impl<'a> ChannelFingerprinter<'a> {
+    fn new(
+        sample_rate: u32,
+        song_id: u32,
+        config: &'a FingerprintConfig,
+        preprocessing: &Preprocessing,
+        adjustment: &LevelAdjustment,
+    ) -> Result<ChannelFingerprinter<'a>, u8> {
+        Ok(ChannelFingerprinter {
+            peak_finder: ChannelPeakFinder::new(sample_rate, config, preprocessing, adjustment)?,
+            peak_pairer: PeakPairer::new(song_id, config),
+            fingerprints: AudioFingerprint::default(),
+        })
+    }
+}
+
+impl ChannelAnalysis for ChannelFingerprinter<'_> {
+    type Output = AudioFingerprint;
+
+    fn push(&mut self, samples: &[f64]) -> Result<(), u8> {
+        for peak in self.peak_finder.push(samples)? {
+            self.peak_pairer.push(peak, &mut self.fingerprints);
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<AudioFingerprint, u8> {
+        for peak in self.peak_finder.finish()? {
+            self.peak_pairer.push(peak, &mut self.fingerprints);
+        }
+
+        self.peak_pairer.finish(&mut self.fingerprints);
+
+        Ok(self.fingerprints)
+    }
+}
+
+// Every peak of a single channel.
+struct ChannelPeaks<'a> {
+    peak_finder: ChannelPeakFinder<'a>,
+    peaks: Vec<Peak>,
+}
+
+impl<'a> ChannelPeaks<'a> {
+    fn new(
+        sample_rate: u32,
+        config: &'a FingerprintConfig,
+        preprocessing: &Preprocessing,
+        adjustment: &LevelAdjustment,
+    ) -> Result<ChannelPeaks<'a>, u8> {
+        Ok(ChannelPeaks {
+            peak_finder: ChannelPeakFinder::new(sample_rate, config, preprocessing, adjustment)?,
+            peaks: Vec::new(),
+        })
+    }
+}
+
+impl ChannelAnalysis for ChannelPeaks<'_> {
+    type Output = Vec<Peak>;
+
+    fn push(&mut self, samples: &[f64]) -> Result<(), u8> {
+        let peaks = self.peak_finder.push(samples)?;
+        self.peaks.extend(peaks);
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<Peak>, u8> {
+        let peaks = self.peak_finder.finish()?;
+        self.peaks.extend(peaks);
+
+        Ok(self.peaks)
+    }
+}
+
+/// Pairs each Peak (the anchor) with up to `target_zone_size` of the Peaks in its target zone,
+/// adding the hash of every pair to a map of hash value (u32) to KeyAudioPoint. The target zone
+/// holds the later Peaks between `target_zone_min_delay` and `target_zone_max_delay` seconds
+/// after the anchor and no more than `target_zone_max_frequency_span` Hz above or below it,
+/// nearest in time first. With `triplet_hashes`, the anchor and each two of the first
+/// `target_zone_size` Peaks of its zone also make a triplet hash. Peaks are only kept until they
+/// can no longer be a target.
+struct PeakPairer {
+    song_id: u32,
+    target_zone: TargetZone,
+    hash_layout: HashLayout,
+    triplet_hashes: bool,
+    pending_peaks: VecDeque<Peak>,
+}
+
+struct TargetZone {
+    size: usize,
+    min_delay: f64,
+    max_delay: f64,
+    max_frequency_span: f64,
+}
+
+impl TargetZone {
+    fn contains(&self, anchor: &Peak, target: &Peak) -> bool {
+        let delay = target.time_sec - anchor.time_sec;
+
+        delay >= self.min_delay
+            && delay <= self.max_delay
+            && (target.frequency - anchor.frequency).abs() <= self.max_frequency_span
+    }
+}
+
+impl PeakPairer {
+    fn new(song_id: u32, config: &FingerprintConfig) -> PeakPairer {
+        PeakPairer {
+            song_id,
+            target_zone: TargetZone {
+                size: config.target_zone_size,
+                min_delay: config.target_zone_min_delay,
+                max_delay: config.target_zone_max_delay,
+                max_frequency_span: config.target_zone_max_frequency_span,
+            },
+            hash_layout: HashLayout {
+                frequency_step: config.hash_frequency_step,
+                time_step: config.hash_time_step,
+                frequency_bits: config.frequency_bits,
+                time_delta_bits: config.time_delta_bits,
+            },
+            triplet_hashes: config.triplet_hashes,
+            pending_peaks: VecDeque::new(),
+        }
+    }
+
+    // Peaks must arrive in order of time.
+    fn push(&mut self, peak: Peak, fingerprints: &mut AudioFingerprint) {
+        // Peaks later than the oldest one's target zone ends complete its zone
+        while let Some(oldest) = self.pending_peaks.front() {
+            if peak.time_sec - oldest.time_sec <= self.target_zone.max_delay {
+                break;
+            }
+
+            self.pair_oldest(fingerprints);
+        }
+
+        self.pending_peaks.push_back(peak);
+    }
+
+    // The first `target_zone.size` of the peaks after `anchor` (in order of time) that are in its
+    // target zone and make a hash with it, with their hashes.
+    fn hashed_targets<'p>(
+        &'p self,
+        anchor: &'p Peak,
+        later_peaks: impl IntoIterator<Item = &'p Peak> + 'p,
+    ) -> impl Iterator<Item = (u32, &'p Peak)> + 'p {
+        later_peaks
+            .into_iter()
+            .take_while(|target| target.time_sec - anchor.time_sec <= self.target_zone.max_delay)
+            .filter(|target| self.target_zone.contains(anchor, target))
+            .filter_map(|target| Some((self.hash_layout.gen_hash(anchor, target)?, target)))
+            .take(self.target_zone.size)
+    }
+
+    // Pair the remaining Peaks once no more will arrive.
+    fn finish(&mut self, fingerprints: &mut AudioFingerprint) {
+        while !self.pending_peaks.is_empty() {
+            self.pair_oldest(fingerprints);
+        }
+    }
+
+    fn pair_oldest(&mut self, fingerprints: &mut AudioFingerprint) {
+        let Some(anchor) = self.pending_peaks.pop_front() else {
+            return;
+        };
+
+        let anchor_time_ms: i32 = (anchor.time_sec * 1000.) as i32;
+
+        let hashes = self
+            .hashed_targets(&anchor, self.pending_peaks.iter())
+            .map(|(hash, _)| hash);
+
+        for hash in hashes {
+            fingerprints.pairs.insert(
                 hash,
                 KeyAudioPoint {
                     anchor_time_ms,
-                    song_id: song_id as i32,
+                    song_id: self.song_id as i32,
                 },
             );
         }
-    }
 
-    fingerprints
+        if !self.triplet_hashes {
+            return;
+        }
+
+        let targets: Vec<&Peak> = self
+            .pending_peaks
+            .iter()
+            .filter(|target| self.target_zone.contains(&anchor, target))
+            .take(self.target_zone.size)
+            .collect();
+
+        for (first_idx, first) in targets.iter().enumerate() {
+            for second in &targets[first_idx + 1..] {
+                if let Some(hash) = triplet_hash(&anchor, first, second) {
+                    let span_ms = ((second.time_sec - anchor.time_sec) * 1000.) as i32;
+
+                    fingerprints.triplets.push((
+                        hash,
+                        TripletPoint {
+                            anchor_time_ms,
+                            span_ms,
+                            song_id: self.song_id as i32,
+                        },
+                    ));
+                }
+            }
+        }
+    }
 }
 
-/// Compute a hash by packing the anchor's frequency, the target's frequency, and the difference
-/// in time between the two Peaks into 32 bits.
-fn gen_hash(anchor: &Peak, target: &Peak) -> u32 {
-    // Scale down to fit in 9 bits
-    let anchor_frequency: u32 = (anchor.frequency / 10.) as u32;
-    let target_frequency: u32 = (target.frequency / 10.) as u32;
+/// How a hash is packed. From the most significant bit down, a hash holds:
+///
+/// | bits              | value                                                          |
+/// |-------------------|----------------------------------------------------------------|
+/// | `frequency_bits`  | anchor's FFT bin / `hash_frequency_step`                       |
+/// | `frequency_bits`  | target's FFT bin / `hash_frequency_step`                       |
+/// | `time_delta_bits` | frames from the anchor to the target / `hash_time_step`        |
+///
+/// Any bits above those are zero. The parts are whole numbers of bins and frames, so small
+/// changes in timing or level do not move a peak's hash unless they move the peak to another
+/// bin or frame.
+struct HashLayout {
+    frequency_step: usize,
+    time_step: usize,
+    frequency_bits: u32,
+    time_delta_bits: u32,
+}
 
-    let time_delta_ms: u32 = ((target.time_sec - anchor.time_sec) * 1000.) as u32;
+impl HashLayout {
+    /// Compute a hash by packing the anchor's frequency, the target's frequency, and the
+    /// difference in time between the two Peaks into 32 bits. Returns `None` if any of them is
+    /// too large for its bits, rather than letting it wrap around onto smaller values.
+    fn gen_hash(&self, anchor: &Peak, target: &Peak) -> Option<u32> {
+        let anchor_frequency = (anchor.frequency_idx / self.frequency_step) as u32;
+        let target_frequency = (target.frequency_idx / self.frequency_step) as u32;
 
-    // Mask to fit within bit constraints
-    let anchor_frequency_bits = anchor_frequency & ((1 << MAX_FREQUENCY_BITS) - 1); // 9 bits
-    let target_frequency_bits = target_frequency & ((1 << MAX_FREQUENCY_BITS) - 1); // 9 bits
-    let time_delta_bits = time_delta_ms & ((1 << MAX_TIME_DELTA_BITS) - 1); // 14 bits
+        let frame_delta = target.frame_idx.checked_sub(anchor.frame_idx)?;
+        let time_delta = (frame_delta / self.time_step) as u32;
 
-    // Pack into 32-bit hash
-    let hash = (anchor_frequency_bits << 23) | (target_frequency_bits << 14) | time_delta_bits;
+        if anchor_frequency >> self.frequency_bits != 0
+            || target_frequency >> self.frequency_bits != 0
+            || time_delta >> self.time_delta_bits != 0
+        {
+            return None;
+        }
 
-    hash
+        // Pack into 32-bit hash
+        Some(
+            (anchor_frequency << (self.frequency_bits + self.time_delta_bits))
+                | (target_frequency << self.time_delta_bits)
+                | time_delta,
+        )
+    }
 }
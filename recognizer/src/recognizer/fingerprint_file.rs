@@ -0,0 +1,290 @@
+// file: src/recognizer/fingerprint_file.rs
+// purpose: saves a recording's fingerprint to a file of its own, with the profile it was made
+// with and the song it is of, so recordings can be fingerprinted on one machine and the
+// fingerprints imported into a library, or identified, on another without moving the audio.
+
+use crate::recognizer::config::{self, FingerprintConfig};
+use crate::recognizer::declarations::{FILE_NOT_FOUND, FINGERPRINT_FILE_ERROR};
+use crate::recognizer::fingerprint::{self, AudioFingerprint, KeyAudioPoint};
+use crate::recognizer::loudness::TimeRange;
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::triplet::TripletPoint;
+use crate::recognizer::wav::WavStream;
+use std::path::Path;
+
+/// The extension fingerprint files are written with and looked for by.
+pub const FINGERPRINT_FILE_EXTENSION: &str = "fp";
+
+// A fingerprint file is laid out as follows, every integer little-endian and every string its
+// length (u32) followed by its UTF-8 bytes:
+//   MAGIC, FORMAT_VERSION, the scheme version the fingerprint was made under (u32)
+//   the TOML of the profile the fingerprint was made with (string)
+//   the song's title, artist and album (strings, all empty if the recording is not of a known
+//     song, e.g. a snippet to identify)
+//   the recording's duration in ms (u32)
+//   the count (u32) of skipped ranges, then each one's start and end in seconds (f64 each)
+//   the count (u32) of pair hashes, then each hash (u32) and its anchor time in ms (i32), in
+//     ascending order of hash
+//   the count (u32) of triplet hashes, then each hash (u32), anchor time and span in ms (i32
+//     each), in the order they were made
+//   the CRC-32 of everything before it
+const MAGIC: [u8; 8] = *b"RECOGFPR";
+const FORMAT_VERSION: u32 = 2;
+
+/// The song a fingerprint file was made from, as taken from its audio file's name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SongInfo {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+}
+
+/// A recording's fingerprint and everything needed to use it without the recording.
+pub struct FingerprintFile {
+    pub profile: FingerprintConfig,
+    pub song: Option<SongInfo>,
+    pub duration_ms: i32,
+    pub fingerprint: AudioFingerprint,
+}
+
+impl FingerprintFile {
+    /// Fingerprint the audio file at `file_path` with `config`, preprocessed as `preprocessing`
+    /// says. `song` is the song it is a recording of, if it is to be added to a library.
+    pub fn of_recording(
+        file_path: &str,
+        song: Option<SongInfo>,
+        config: &FingerprintConfig,
+        preprocessing: &Preprocessing,
+    ) -> Result<FingerprintFile, u8> {
+        let duration_sec: f64 = WavStream::open(file_path)?.info.duration_sec;
+        let fingerprint: AudioFingerprint =
+            fingerprint::fingerprint_audio(file_path.to_string(), 0, config, preprocessing)?;
+
+        Ok(FingerprintFile {
+            profile: config.clone(),
+            song,
+            duration_ms: (duration_sec * 1000.).round() as i32,
+            fingerprint,
+        })
+    }
+
+    /// The fingerprint, with every hash attributed to the song `song_id`.
+    pub fn into_song_fingerprint(self, song_id: u32) -> AudioFingerprint {
+        let mut fingerprint: AudioFingerprint = self.fingerprint;
+
+        for point in fingerprint.pairs.values_mut() {
+            point.song_id = song_id as i32;
+        }
+        for (_, point) in fingerprint.triplets.iter_mut() {
+            point.song_id = song_id as i32;
+        }
+
+        fingerprint
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = MAGIC.to_vec();
+        bytes.extend(FORMAT_VERSION.to_le_bytes());
+        bytes.extend(self.profile.scheme_version.to_le_bytes());
+
+        write_string(&mut bytes, &self.profile.to_toml());
+        let no_song = SongInfo {
+            title: String::new(),
+            artist: String::new(),
+            album: String::new(),
+        };
+        let song: &SongInfo = self.song.as_ref().unwrap_or(&no_song);
+        for text in [&song.title, &song.artist, &song.album] {
+            write_string(&mut bytes, text);
+        }
+        bytes.extend((self.duration_ms.max(0) as u32).to_le_bytes());
+
+        let skipped_ranges: &[TimeRange] = &self.fingerprint.skipped_ranges;
+        bytes.extend((skipped_ranges.len() as u32).to_le_bytes());
+        for range in skipped_ranges {
+            bytes.extend(range.start_sec.to_le_bytes());
+            bytes.extend(range.end_sec.to_le_bytes());
+        }
+
+        let mut pairs: Vec<(u32, i32)> = self
+            .fingerprint
+            .pairs
+            .iter()
+            .map(|(hash, point)| (*hash, point.anchor_time_ms))
+            .collect();
+        pairs.sort_unstable();
+        bytes.extend((pairs.len() as u32).to_le_bytes());
+        for (hash, anchor_time_ms) in pairs {
+            bytes.extend(hash.to_le_bytes());
+            bytes.extend(anchor_time_ms.to_le_bytes());
+        }
+
+        let triplets: &[(u32, TripletPoint)] = &self.fingerprint.triplets;
+        bytes.extend((triplets.len() as u32).to_le_bytes());
+        for (hash, point) in triplets {
+            bytes.extend(hash.to_le_bytes());
+            bytes.extend(point.anchor_time_ms.to_le_bytes());
+            bytes.extend(point.span_ms.to_le_bytes());
+        }
+
+        let checksum: u32 = crc32fast::hash(&bytes);
+        bytes.extend(checksum.to_le_bytes());
+
+        bytes
+    }
+
+    /// Read a fingerprint file from its bytes, failing if it is not one, is corrupt, was written
+    /// by another version or holds an invalid profile. `source` names where the bytes came from
+    /// in the errors printed.
+    pub fn from_bytes(bytes: &[u8], source: &str) -> Result<FingerprintFile, u8> {
+        if bytes.len() < MAGIC.len() + 12 || bytes[..MAGIC.len()] != MAGIC {
+            eprintln!("ERROR: `{}` is not a fingerprint file!", source);
+            return Err(FINGERPRINT_FILE_ERROR);
+        }
+
+        let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            eprintln!(
+                "ERROR: `{}` is a fingerprint file of version {}, but only version {} can be \
+                read; fingerprint the recording again",
+                source, version, FORMAT_VERSION
+            );
+            return Err(FINGERPRINT_FILE_ERROR);
+        }
+
+        let scheme_version = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+        config::check_scheme_version(scheme_version, &format!("`{}`", source))?;
+
+        let (contents, checksum) = bytes.split_at(bytes.len() - 4);
+        if u32::from_le_bytes(checksum.try_into().unwrap()) != crc32fast::hash(contents) {
+            eprintln!("ERROR: `{}` is truncated or corrupt!", source);
+            return Err(FINGERPRINT_FILE_ERROR);
+        }
+
+        let corrupt = || {
+            eprintln!("ERROR: `{}` is corrupt!", source);
+            FINGERPRINT_FILE_ERROR
+        };
+
+        let mut reader = Reader {
+            bytes: contents,
+            position: 16,
+        };
+        let profile: String = reader.string().ok_or_else(corrupt)?;
+        let profile = FingerprintConfig::from_toml(&profile)?;
+
+        reader.fingerprint_file(profile).ok_or_else(corrupt)
+    }
+
+    pub fn read(file_path: &Path) -> Result<FingerprintFile, u8> {
+        match std::fs::read(file_path) {
+            Ok(bytes) => FingerprintFile::from_bytes(&bytes, &file_path.display().to_string()),
+            Err(_) => {
+                eprintln!("ERROR: Cannot open file: `{}`", file_path.display());
+                Err(FILE_NOT_FOUND)
+            }
+        }
+    }
+
+    pub fn write(&self, file_path: &Path) -> Result<(), u8> {
+        if std::fs::write(file_path, self.to_bytes()).is_err() {
+            eprintln!("ERROR: Cannot write `{}`", file_path.display());
+            return Err(FILE_NOT_FOUND);
+        }
+
+        Ok(())
+    }
+}
+
+// The fields of a fingerprint file after its version, read in order. Every read returns `None`
+// past the end of the bytes, so a corrupt length cannot read out of bounds.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let end: usize = self.position.checked_add(len)?;
+        let taken: &[u8] = self.bytes.get(self.position..end)?;
+        self.position = end;
+
+        Some(taken)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        Some(f64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    // A count of items, each `item_len` bytes long, as long as that many are left to read.
+    fn count(&mut self, item_len: usize) -> Option<usize> {
+        let count = self.u32()? as usize;
+        (count.checked_mul(item_len)? <= self.bytes.len() - self.position).then_some(count)
+    }
+
+    // Everything after the profile.
+    fn fingerprint_file(&mut self, profile: FingerprintConfig) -> Option<FingerprintFile> {
+        let song = SongInfo {
+            title: self.string()?,
+            artist: self.string()?,
+            album: self.string()?,
+        };
+        let song: Option<SongInfo> = (!song.title.is_empty()).then_some(song);
+
+        let duration_ms = self.u32()?.min(i32::MAX as u32) as i32;
+
+        let mut fingerprint = AudioFingerprint::default();
+        for _ in 0..self.count(16)? {
+            fingerprint.skipped_ranges.push(TimeRange {
+                start_sec: self.f64()?,
+                end_sec: self.f64()?,
+            });
+        }
+        for _ in 0..self.count(8)? {
+            let hash: u32 = self.u32()?;
+            let anchor_time_ms: i32 = self.i32()?;
+            fingerprint.pairs.insert(
+                hash,
+                KeyAudioPoint {
+                    anchor_time_ms,
+                    song_id: 0,
+                },
+            );
+        }
+        for _ in 0..self.count(12)? {
+            let hash: u32 = self.u32()?;
+            let point = TripletPoint {
+                anchor_time_ms: self.i32()?,
+                span_ms: self.i32()?,
+                song_id: 0,
+            };
+            fingerprint.triplets.push((hash, point));
+        }
+
+        // Nothing may follow the triplets but the checksum, already split off
+        (self.position == self.bytes.len()).then_some(FingerprintFile {
+            profile,
+            song,
+            duration_ms,
+            fingerprint,
+        })
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, text: &str) {
+    bytes.extend((text.len() as u32).to_le_bytes());
+    bytes.extend(text.as_bytes());
+}
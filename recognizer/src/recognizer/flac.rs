@@ -0,0 +1,554 @@
+// file: src/recognizer/flac.rs
+// purpose: encodes 16-bit audio as a FLAC file tagged with Vorbis comments. Each channel of each
+// block is coded independently with whichever fixed predictor leaves the smallest residual, and
+// the residual is Rice coded in as many partitions as pays off.
+
+use crate::recognizer::declarations::INCOMPATIBLE_FILE_ERROR;
+
+// Samples per channel in every block but the last
+const BLOCK_SIZE: usize = 4096;
+
+// The highest fixed predictor order FLAC defines
+const MAX_FIXED_ORDER: usize = 4;
+
+// The most partitions (as a power of two) a block's residual is split into
+const MAX_PARTITION_ORDER: u32 = 6;
+
+// The largest parameter a 4-bit Rice parameter field can hold; 0b1111 is the escape code
+const MAX_RICE_PARAMETER: u32 = 14;
+
+const BITS_PER_SAMPLE: u32 = 16;
+
+// Channels beyond two would need a channel layout, and beyond eight do not fit STREAMINFO, so
+// only mono and stereo are encoded
+const MAX_CHANNELS: u16 = 2;
+
+// The highest sample rate FLAC can store
+const MAX_SAMPLE_RATE: u32 = 655_350;
+
+const STREAMINFO_BLOCK: u8 = 0;
+const VORBIS_COMMENT_BLOCK: u8 = 4;
+
+/// The FLAC file of the given interleaved 16-bit samples of `channels` (1 or 2) channels, with
+/// `tags` as its Vorbis comments, e.g. `("TITLE", "Blue Moon")`. Fails for other channel counts
+/// and for sample rates FLAC cannot store.
+pub fn encode_flac(
+    samples: &[i16],
+    channels: u16,
+    sample_rate: u32,
+    tags: &[(&str, &str)],
+) -> Result<Vec<u8>, u8> {
+    if channels == 0 || channels > MAX_CHANNELS {
+        eprintln!(
+            "ERROR: Only mono and stereo audio can be written as FLAC, not {} channels",
+            channels
+        );
+        return Err(INCOMPATIBLE_FILE_ERROR);
+    }
+    if sample_rate == 0 || sample_rate > MAX_SAMPLE_RATE {
+        eprintln!(
+            "ERROR: A sample rate of {} Hz cannot be written as FLAC",
+            sample_rate
+        );
+        return Err(INCOMPATIBLE_FILE_ERROR);
+    }
+
+    let channels = channels as usize;
+    let frame_count: usize = samples.len() / channels;
+
+    let mut flac: Vec<u8> = b"fLaC".to_vec();
+
+    let streaminfo: Vec<u8> = streaminfo(frame_count, channels, sample_rate);
+    flac.extend(metadata_block_header(
+        STREAMINFO_BLOCK,
+        false,
+        streaminfo.len(),
+    ));
+    flac.extend(streaminfo);
+
+    let vorbis_comment: Vec<u8> = vorbis_comment(tags);
+    flac.extend(metadata_block_header(
+        VORBIS_COMMENT_BLOCK,
+        true,
+        vorbis_comment.len(),
+    ));
+    flac.extend(vorbis_comment);
+
+    for (frame_number, block) in samples.chunks(BLOCK_SIZE * channels).enumerate() {
+        let channel_samples: Vec<Vec<i32>> = (0..channels)
+            .map(|channel| {
+                block
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .map(|sample| *sample as i32)
+                    .collect()
+            })
+            .collect();
+
+        flac.extend(frame(frame_number as u64, &channel_samples));
+    }
+
+    Ok(flac)
+}
+
+fn metadata_block_header(block_type: u8, is_last: bool, length: usize) -> Vec<u8> {
+    let mut header = vec![((is_last as u8) << 7) | block_type];
+    header.extend(&(length as u32).to_be_bytes()[1..]);
+    header
+}
+
+// The stream's format. The block sizes are those of every block but the last; the frame sizes
+// and the MD5 signature of the audio are left as unknown (zero), which the format allows.
+fn streaminfo(frame_count: usize, channels: usize, sample_rate: u32) -> Vec<u8> {
+    let block_size = frame_count.clamp(16, BLOCK_SIZE) as u64;
+
+    let mut writer = BitWriter::new();
+    writer.write(block_size, 16); // minimum block size
+    writer.write(block_size, 16); // maximum block size
+    writer.write(0, 24); // minimum frame size
+    writer.write(0, 24); // maximum frame size
+    writer.write(sample_rate as u64, 20);
+    writer.write(channels as u64 - 1, 3);
+    writer.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    writer.write(frame_count as u64, 36);
+    writer.write(0, 64); // MD5 signature
+    writer.write(0, 64);
+
+    writer.into_bytes()
+}
+
+// Vorbis comments are the one part of a FLAC file with little-endian lengths.
+fn vorbis_comment(tags: &[(&str, &str)]) -> Vec<u8> {
+    let vendor = concat!("recognizer ", env!("CARGO_PKG_VERSION"));
+
+    let mut comment = Vec::<u8>::new();
+    comment.extend((vendor.len() as u32).to_le_bytes());
+    comment.extend(vendor.as_bytes());
+    comment.extend((tags.len() as u32).to_le_bytes());
+
+    for (field, value) in tags {
+        let tag = format!("{}={}", field, value);
+        comment.extend((tag.len() as u32).to_le_bytes());
+        comment.extend(tag.as_bytes());
+    }
+
+    comment
+}
+
+// One frame of the stream: a header, a subframe per channel and a CRC-16 of the whole frame.
+fn frame(frame_number: u64, channel_samples: &[Vec<i32>]) -> Vec<u8> {
+    let block_size: usize = channel_samples[0].len();
+
+    let mut writer = BitWriter::new();
+
+    // Sync code, fixed block sizes
+    writer.write(0xFFF8, 16);
+
+    // Block size: 4096 samples, or read from the end of the header; the sample rate is read from
+    // STREAMINFO
+    let block_size_code: u64 = if block_size == BLOCK_SIZE {
+        0b1100
+    } else {
+        0b0111
+    };
+    writer.write(block_size_code, 4);
+    writer.write(0b0000, 4);
+
+    // Independent channels of 16-bit samples
+    writer.write(channel_samples.len() as u64 - 1, 4);
+    writer.write(0b100, 3);
+    writer.write(0, 1);
+
+    for byte in utf8_coded(frame_number) {
+        writer.write(byte as u64, 8);
+    }
+    if block_size_code == 0b0111 {
+        writer.write(block_size as u64 - 1, 16);
+    }
+
+    let header_crc: u8 = crc8(writer.bytes());
+    writer.write(header_crc as u64, 8);
+
+    for samples in channel_samples {
+        subframe(&mut writer, samples);
+    }
+
+    let mut frame: Vec<u8> = writer.into_bytes();
+    let frame_crc: u16 = crc16(&frame);
+    frame.extend(frame_crc.to_be_bytes());
+
+    frame
+}
+
+// A frame number in the variable-length code UTF-8 uses for characters, extended to 36 bits.
+fn utf8_coded(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+
+    // Each continuation byte holds 6 bits; the first byte holds what fits after its length prefix
+    let mut continuation_bytes: usize = 1;
+    while value >> (6 * continuation_bytes) >= 1 << (6 - continuation_bytes) {
+        continuation_bytes += 1;
+    }
+
+    let length_prefix: u8 = !(0xFFu8 >> (continuation_bytes + 1));
+    let mut bytes = vec![length_prefix | (value >> (6 * continuation_bytes)) as u8];
+
+    for idx in (0..continuation_bytes).rev() {
+        bytes.push(0x80 | ((value >> (6 * idx)) & 0x3F) as u8);
+    }
+
+    bytes
+}
+
+// One channel of a block, as a constant, the fixed predictor whose residual codes smallest, or
+// the samples themselves if no predictor helps.
+fn subframe(writer: &mut BitWriter, samples: &[i32]) {
+    if samples.iter().all(|sample| *sample == samples[0]) {
+        writer.write(0b0000_0000, 8);
+        writer.write_signed(samples[0], BITS_PER_SAMPLE);
+        return;
+    }
+
+    // The order leaving the smallest residual usually codes smallest, too
+    let order: usize = (0..=MAX_FIXED_ORDER.min(samples.len() - 1))
+        .min_by_key(|order| {
+            fixed_residual(samples, *order)
+                .iter()
+                .map(|residual| residual.unsigned_abs() as u64)
+                .sum::<u64>()
+        })
+        .unwrap_or(0);
+
+    let residual: Vec<i32> = fixed_residual(samples, order);
+    let (partition_order, rice_parameters, residual_bits) =
+        best_partitioning(&residual, samples.len(), order);
+
+    let fixed_bits: usize = 8 + order * BITS_PER_SAMPLE as usize + 6 + residual_bits;
+    let verbatim_bits: usize = 8 + samples.len() * BITS_PER_SAMPLE as usize;
+
+    if fixed_bits >= verbatim_bits {
+        writer.write(0b0000_0010, 8);
+        for sample in samples {
+            writer.write_signed(*sample, BITS_PER_SAMPLE);
+        }
+        return;
+    }
+
+    writer.write(0b0001_0000 | (order as u64) << 1, 8);
+    for sample in &samples[..order] {
+        writer.write_signed(*sample, BITS_PER_SAMPLE);
+    }
+
+    // Partitioned Rice coding with 4-bit parameters
+    writer.write(0b00, 2);
+    writer.write(partition_order as u64, 4);
+
+    let mut residual_values = residual.iter();
+    for (partition, rice_parameter) in rice_parameters.iter().enumerate() {
+        writer.write(*rice_parameter as u64, 4);
+
+        for value in residual_values.by_ref().take(partition_length(
+            samples.len(),
+            partition_order,
+            order,
+            partition,
+        )) {
+            writer.write_rice(*value, *rice_parameter);
+        }
+    }
+}
+
+// The difference between each sample after the first `order` and the polynomial of that order
+// through the samples before it.
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i32> {
+    (order..samples.len())
+        .map(|idx| {
+            let s = |back: usize| samples[idx - back] as i64;
+
+            (match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }) as i32
+        })
+        .collect()
+}
+
+// The partition order and Rice parameters coding the residual of an `order` predictor over a
+// block of `block_size` samples in the fewest bits, and that number of bits.
+fn best_partitioning(residual: &[i32], block_size: usize, order: usize) -> (u32, Vec<u32>, usize) {
+    let mut best: Option<(u32, Vec<u32>, usize)> = None;
+
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        // Every partition holds the same number of samples, and the first at least the warm-up
+        if block_size % (1 << partition_order) != 0 || block_size >> partition_order <= order {
+            break;
+        }
+
+        let mut rice_parameters = Vec::<u32>::new();
+        let mut bits: usize = 0;
+        let mut start: usize = 0;
+
+        for partition in 0..1 << partition_order {
+            let length: usize = partition_length(block_size, partition_order, order, partition);
+            let (rice_parameter, partition_bits) =
+                best_rice_parameter(&residual[start..start + length]);
+
+            rice_parameters.push(rice_parameter);
+            bits += 4 + partition_bits;
+            start += length;
+        }
+
+        if best
+            .as_ref()
+            .is_none_or(|(_, _, best_bits)| bits < *best_bits)
+        {
+            best = Some((partition_order, rice_parameters, bits));
+        }
+    }
+
+    best.unwrap_or((0, vec![0], usize::MAX))
+}
+
+// The number of residual values in a partition: the first partition is short by the warm-up
+// samples.
+fn partition_length(
+    block_size: usize,
+    partition_order: u32,
+    order: usize,
+    partition: usize,
+) -> usize {
+    let length: usize = block_size >> partition_order;
+
+    if partition == 0 {
+        length - order
+    } else {
+        length
+    }
+}
+
+// The Rice parameter coding `values` in the fewest bits, and that number of bits. The best
+// parameter is close to the base-2 logarithm of the mean value, so only its neighbours are tried.
+fn best_rice_parameter(values: &[i32]) -> (u32, usize) {
+    let folded: Vec<u64> = values.iter().map(|value| zigzag(*value) as u64).collect();
+    let mean: u64 = folded.iter().sum::<u64>() / folded.len().max(1) as u64;
+    let estimate: u32 = (u64::BITS - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+
+    (estimate.saturating_sub(1)..=(estimate + 1).min(MAX_RICE_PARAMETER))
+        .map(|rice_parameter| {
+            let bits: usize = folded
+                .iter()
+                .map(|value| (value >> rice_parameter) as usize + 1 + rice_parameter as usize)
+                .sum();
+            (rice_parameter, bits)
+        })
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap_or((0, 0))
+}
+
+// A signed value as an unsigned one, interleaving the negative values with the positive.
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+// CRC-8 with the polynomial x^8 + x^2 + x + 1, over the frame header.
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// CRC-16 with the polynomial x^16 + x^15 + x^2 + 1, over the whole frame.
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// Writes values most significant bit first, as FLAC stores them.
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bit_count: u32, // bits in `accumulator` not yet written to `bytes`
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            accumulator: 0,
+            bit_count: 0,
+        }
+    }
+
+    // The low `bits` bits of `value`, at most 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+
+        self.accumulator = (self.accumulator << bits) | (value & ((1 << bits) - 1));
+        self.bit_count += bits;
+
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            self.bytes.push((self.accumulator >> self.bit_count) as u8);
+        }
+        self.accumulator &= (1 << self.bit_count) - 1;
+    }
+
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        self.write(value as u32 as u64, bits);
+    }
+
+    // The quotient in unary (zeros ended by a one), then the remainder in `rice_parameter` bits.
+    fn write_rice(&mut self, value: i32, rice_parameter: u32) {
+        let folded: u32 = zigzag(value);
+        let mut quotient: u32 = folded >> rice_parameter;
+
+        while quotient >= 32 {
+            self.write(0, 32);
+            quotient -= 32;
+        }
+        self.write(1, quotient + 1);
+        self.write(folded as u64, rice_parameter);
+    }
+
+    // The bytes written so far, excluding any incomplete last byte.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // Every byte written, padding the last with zeros.
+    fn into_bytes(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.write(0, 8 - self.bit_count);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::test_audio::{self, SAMPLE_RATE};
+
+    // Decode a FLAC file, returning its sample rate, channel count, interleaved samples and tags.
+    fn decode(flac: Vec<u8>) -> (u32, u32, Vec<i16>, Vec<(String, String)>) {
+        let mut reader = claxon::FlacReader::new(std::io::Cursor::new(flac)).unwrap();
+        let info = reader.streaminfo();
+        let tags: Vec<(String, String)> = reader
+            .tags()
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .collect();
+        let samples: Vec<i16> = reader
+            .samples()
+            .map(|sample| sample.unwrap() as i16)
+            .collect();
+
+        assert_eq!(info.bits_per_sample, BITS_PER_SAMPLE);
+        assert_eq!(
+            info.samples,
+            Some(samples.len() as u64 / info.channels as u64)
+        );
+        (info.sample_rate, info.channels, samples, tags)
+    }
+
+    fn song_samples(duration_sec: f64, seed: u64) -> Vec<i16> {
+        test_audio::synthetic_song(duration_sec, seed)
+            .into_iter()
+            .map(|sample| sample as i16)
+            .collect()
+    }
+
+    #[test]
+    fn mono_and_stereo_decode_to_their_samples() {
+        let left: Vec<i16> = song_samples(2.5, 46);
+        let right: Vec<i16> = song_samples(2.5, 47);
+        let stereo: Vec<i16> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(left, right)| [*left, *right])
+            .collect();
+        let tags = [("TITLE", "Blue Moon"), ("ARTIST", "Ella Fitzgerald")];
+
+        for (samples, channels) in [(&left, 1), (&stereo, 2)] {
+            let flac = encode_flac(samples, channels, SAMPLE_RATE, &tags).unwrap();
+            let (sample_rate, decoded_channels, decoded, decoded_tags) = decode(flac);
+
+            assert_eq!(sample_rate, SAMPLE_RATE);
+            assert_eq!(decoded_channels, channels as u32);
+            assert_eq!(&decoded, samples);
+            assert_eq!(
+                decoded_tags,
+                [
+                    ("TITLE".to_string(), "Blue Moon".to_string()),
+                    ("ARTIST".to_string(), "Ella Fitzgerald".to_string())
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn extreme_and_short_blocks_decode_to_their_samples() {
+        // Silence (a constant subframe), full-scale noise (verbatim), full-scale square waves
+        // (large residuals), and blocks too short for some predictors and partitions
+        let noise: Vec<i16> = (0..BLOCK_SIZE as u32 + 100)
+            .map(|idx| (idx.wrapping_mul(2_654_435_761) >> 16) as u16 as i16)
+            .collect();
+        let square: Vec<i16> = (0..BLOCK_SIZE + 3)
+            .map(|idx| if idx % 7 < 3 { i16::MAX } else { i16::MIN })
+            .collect();
+
+        for samples in [
+            vec![0; 3 * BLOCK_SIZE],
+            noise,
+            square,
+            vec![1234],
+            vec![5, -3, 7],
+            song_samples(0.001, 48),
+        ] {
+            let flac = encode_flac(&samples, 1, 8000, &[]).unwrap();
+            assert_eq!(decode(flac).2, samples);
+        }
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_encode() {
+        let samples: Vec<i16> = vec![0; 8 * 100];
+
+        for channels in [0, 3, 8, 9] {
+            assert_eq!(
+                encode_flac(&samples, channels, SAMPLE_RATE, &[]),
+                Err(INCOMPATIBLE_FILE_ERROR)
+            );
+        }
+        assert_eq!(
+            encode_flac(&samples, 1, 0, &[]),
+            Err(INCOMPATIBLE_FILE_ERROR)
+        );
+        assert_eq!(
+            encode_flac(&samples, 1, 768_000, &[]),
+            Err(INCOMPATIBLE_FILE_ERROR)
+        );
+    }
+}
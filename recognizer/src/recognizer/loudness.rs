@@ -0,0 +1,392 @@
+// file: src/recognizer/loudness.rs
+// purpose: measures how loud a recording is (integrated loudness in the manner of EBU R128,
+// RMS and peak level) and how much of it is clipped or silent, so a recording unlikely to
+// identify can be flagged, its level normalised and its silent stretches skipped before analysis.
+
+use crate::recognizer::preprocess::Biquad;
+use serde::{Deserialize, Serialize};
+
+// Samples are 16-bit, so full scale is this; samples at either end of the range count as clipped.
+const FULL_SCALE: f64 = 32768.0;
+const CLIPPED_LEVEL: f64 = 32767.0;
+
+// Loudness is measured over blocks of 400 ms starting every 100 ms. Blocks quieter than the
+// absolute gate (silence) are ignored, then blocks more than the relative gate below the loudness
+// of the rest (pauses).
+const BLOCK_SECONDS: f64 = 0.4;
+const BLOCK_STEPS: usize = 4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+// EBU R128's programme loudness, which normalisation brings a recording to. A recording is never
+// amplified by more than `MAX_NORMALISATION_GAIN_DB`, so a near-silent one is not made of noise.
+const TARGET_LOUDNESS_LUFS: f64 = -23.0;
+const MAX_NORMALISATION_GAIN_DB: f64 = 30.0;
+
+// A recording is flagged when more than this share of its samples are clipped, when it is
+// quieter than this overall, or when more than this share of it is silence.
+const CLIPPED_WARNING_FRACTION: f64 = 0.001;
+const QUIET_WARNING_LUFS: f64 = -50.0;
+const SILENT_WARNING_FRACTION: f64 = 0.5;
+
+// A 100 ms step is silent (or noise only, like a record's lead-in groove) if it is quieter than
+// `SILENCE_LUFS`, or more than `SILENCE_MARGIN_LU` quieter than the recording as a whole. Only
+// silences of at least `MIN_SILENCE_SECONDS` are skipped, so the gaps between notes are kept,
+// and sounds shorter than `MIN_SOUND_SECONDS` between them (e.g. the needle dropping) are
+// skipped with them.
+const SILENCE_LUFS: f64 = -60.0;
+const SILENCE_MARGIN_LU: f64 = 25.0;
+const MIN_SILENCE_SECONDS: f64 = 1.0;
+const MIN_SOUND_SECONDS: f64 = 0.5;
+
+/// A stretch of a recording, in seconds from its start.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeRange {
+    pub start_sec: f64,
+    pub end_sec: f64,
+}
+
+impl TimeRange {
+    pub fn contains(&self, time_sec: f64) -> bool {
+        self.start_sec <= time_sec && time_sec < self.end_sec
+    }
+}
+
+impl std::fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:.1}-{:.1}s", self.start_sec, self.end_sec)
+    }
+}
+
+/// The level of a recording.
+#[derive(Debug, Clone)]
+pub struct LevelAnalysis {
+    /// Gated loudness of the whole recording (LUFS), or `None` if it is entirely silent
+    pub integrated_loudness: Option<f64>,
+    /// Level of the unweighted samples (dBFS)
+    pub rms_level: f64,
+    /// Level of the largest sample (dBFS)
+    pub peak_level: f64,
+    /// Share of samples at full scale
+    pub clipped_fraction: f64,
+    /// Share of the loudness blocks below the absolute gate
+    pub silent_fraction: f64,
+    /// Stretches of silence or noise long enough to skip, in order
+    pub silent_ranges: Vec<TimeRange>,
+}
+
+impl LevelAnalysis {
+    /// The factor to multiply samples by to bring the recording to the target loudness.
+    pub fn normalisation_gain(&self) -> f64 {
+        match self.integrated_loudness {
+            Some(loudness) => {
+                let gain_db = (TARGET_LOUDNESS_LUFS - loudness).min(MAX_NORMALISATION_GAIN_DB);
+                10f64.powf(gain_db / 20.0)
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Why the recording is unlikely to be identified, if it is.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::<String>::new();
+
+        if self.clipped_fraction > CLIPPED_WARNING_FRACTION {
+            warnings.push(format!(
+                "{:.1}% of samples are clipped; the recording is distorted",
+                self.clipped_fraction * 100.0
+            ));
+        }
+
+        match self.integrated_loudness {
+            None => warnings.push("the recording is silent".to_string()),
+            Some(loudness) if loudness < QUIET_WARNING_LUFS => warnings.push(format!(
+                "the recording is very quiet ({:.1} LUFS); it may be mostly noise",
+                loudness
+            )),
+            Some(_) => {}
+        }
+
+        if self.integrated_loudness.is_some() && self.silent_fraction > SILENT_WARNING_FRACTION {
+            warnings.push(format!(
+                "{:.0}% of the recording is silence",
+                self.silent_fraction * 100.0
+            ));
+        }
+
+        warnings
+    }
+
+    pub fn print(&self) {
+        match self.integrated_loudness {
+            Some(loudness) => println!("Integrated loudness: {:.1} LUFS", loudness),
+            None => println!("Integrated loudness: silent"),
+        }
+        println!("RMS level:           {:.1} dBFS", self.rms_level);
+        println!("Peak level:          {:.1} dBFS", self.peak_level);
+        println!("Clipped samples:     {:.3}%", self.clipped_fraction * 100.0);
+        println!("Silence:             {:.0}%", self.silent_fraction * 100.0);
+        if !self.silent_ranges.is_empty() {
+            println!(
+                "Silent stretches:    {}",
+                format_ranges(&self.silent_ranges)
+            );
+        }
+    }
+}
+
+/// Measures the level of a recording fed to it a chunk of both channels at a time.
+pub struct LoudnessMeter {
+    sample_rate: u32,
+    filters: [[Biquad; 2]; 2], // K-weighting of the left and right channels
+    step_length: usize,
+    step_energy: f64, // summed over both channels
+    step_count: usize,
+    recent_steps: Vec<f64>, // energies of the last `BLOCK_STEPS` steps
+    block_energies: Vec<f64>,
+    step_energies: Vec<f64>, // mean square of every step
+    sum_of_squares: f64,
+    sample_count: usize,
+    peak: f64,
+    clipped_count: usize,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32) -> LoudnessMeter {
+        LoudnessMeter {
+            sample_rate,
+            filters: [k_weighting(sample_rate), k_weighting(sample_rate)],
+            step_length: ((BLOCK_SECONDS / BLOCK_STEPS as f64 * sample_rate as f64).round()
+                as usize)
+                .max(1),
+            step_energy: 0.0,
+            step_count: 0,
+            recent_steps: Vec::with_capacity(BLOCK_STEPS),
+            block_energies: Vec::new(),
+            step_energies: Vec::new(),
+            sum_of_squares: 0.0,
+            sample_count: 0,
+            peak: 0.0,
+            clipped_count: 0,
+        }
+    }
+
+    /// Add the next samples of the left and right channels (the right is empty for mono audio).
+    pub fn push(&mut self, left_samples: &[f64], right_samples: &[f64]) {
+        for idx in 0..left_samples.len() {
+            for (channel, samples) in [left_samples, right_samples].iter().enumerate() {
+                if let Some(&sample) = samples.get(idx) {
+                    self.add_sample(channel, sample);
+                }
+            }
+
+            self.step_count += 1;
+            if self.step_count == self.step_length {
+                self.end_step();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> LevelAnalysis {
+        // A recording shorter than one block is measured as a single block
+        if self.block_energies.is_empty() && self.step_count + self.recent_steps.len() > 0 {
+            let samples = self.recent_steps.len() * self.step_length + self.step_count;
+            let energy = self.recent_steps.iter().sum::<f64>() + self.step_energy;
+            self.block_energies.push(energy / samples as f64);
+        }
+
+        if self.step_count > 0 {
+            self.step_energies
+                .push(self.step_energy / self.step_count as f64);
+        }
+
+        let gated_mean = |gate: f64| -> Option<f64> {
+            let gated: Vec<f64> = self
+                .block_energies
+                .iter()
+                .copied()
+                .filter(|&energy| loudness(energy) > gate)
+                .collect();
+
+            (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+        };
+
+        let integrated_loudness = gated_mean(ABSOLUTE_GATE_LUFS)
+            .and_then(|energy| gated_mean(loudness(energy) + RELATIVE_GATE_LU))
+            .map(loudness);
+
+        let silent_blocks = self
+            .block_energies
+            .iter()
+            .filter(|&&energy| loudness(energy) <= ABSOLUTE_GATE_LUFS)
+            .count();
+
+        let silent_ranges: Vec<TimeRange> = self.silent_ranges(integrated_loudness);
+
+        LevelAnalysis {
+            integrated_loudness,
+            rms_level: decibels((self.sum_of_squares / self.sample_count.max(1) as f64).sqrt()),
+            peak_level: decibels(self.peak),
+            clipped_fraction: self.clipped_count as f64 / self.sample_count.max(1) as f64,
+            silent_fraction: silent_blocks as f64 / self.block_energies.len().max(1) as f64,
+            silent_ranges,
+        }
+    }
+
+    // The stretches to skip: runs of silent steps long enough to matter, together with any
+    // sound too short to matter between them.
+    fn silent_ranges(&self, integrated_loudness: Option<f64>) -> Vec<TimeRange> {
+        let threshold = match integrated_loudness {
+            Some(loudness) => SILENCE_LUFS.max(loudness - SILENCE_MARGIN_LU),
+            None => f64::INFINITY,
+        };
+        let step_seconds = self.step_length as f64 / self.sample_rate as f64;
+        let steps_for = |seconds: f64| (seconds / step_seconds).round() as usize;
+
+        // Runs of steps that are all silent or all sound, as (is silent, steps)
+        let mut runs = Vec::<(bool, usize)>::new();
+        for &energy in &self.step_energies {
+            let is_silent = loudness(energy) < threshold;
+
+            match runs.last_mut() {
+                Some((run_is_silent, length)) if *run_is_silent == is_silent => *length += 1,
+                _ => runs.push((is_silent, 1)),
+            }
+        }
+
+        // Short silences are part of the sound around them
+        for run in &mut runs {
+            if run.0 && run.1 < steps_for(MIN_SILENCE_SECONDS) {
+                run.0 = false;
+            }
+        }
+
+        let mut runs: Vec<(bool, usize)> = join_runs(runs);
+
+        // Short sounds next to a long silence are part of it
+        if runs.iter().any(|run| run.0) {
+            for run in &mut runs {
+                if !run.0 && run.1 < steps_for(MIN_SOUND_SECONDS) {
+                    run.0 = true;
+                }
+            }
+        }
+
+        let mut ranges = Vec::<TimeRange>::new();
+        let mut start_step = 0;
+        for (is_silent, length) in join_runs(runs) {
+            if is_silent {
+                ranges.push(TimeRange {
+                    start_sec: start_step as f64 * step_seconds,
+                    end_sec: (start_step + length) as f64 * step_seconds,
+                });
+            }
+            start_step += length;
+        }
+
+        ranges
+    }
+
+    fn add_sample(&mut self, channel: usize, sample: f64) {
+        let value = sample / FULL_SCALE;
+
+        self.sum_of_squares += value * value;
+        self.sample_count += 1;
+        self.peak = self.peak.max(value.abs());
+        if sample.abs() >= CLIPPED_LEVEL {
+            self.clipped_count += 1;
+        }
+
+        let weighted = self.filters[channel]
+            .iter_mut()
+            .fold(value, |value, biquad| biquad.next(value));
+        self.step_energy += weighted * weighted;
+    }
+
+    fn end_step(&mut self) {
+        if self.recent_steps.len() == BLOCK_STEPS {
+            self.recent_steps.remove(0);
+        }
+        self.recent_steps.push(self.step_energy);
+        self.step_energies
+            .push(self.step_energy / self.step_length as f64);
+
+        if self.recent_steps.len() == BLOCK_STEPS {
+            let block_length = (BLOCK_STEPS * self.step_length) as f64;
+            self.block_energies
+                .push(self.recent_steps.iter().sum::<f64>() / block_length);
+        }
+
+        (self.step_energy, self.step_count) = (0.0, 0);
+    }
+}
+
+// Join neighbouring runs of the same kind.
+fn join_runs(runs: Vec<(bool, usize)>) -> Vec<(bool, usize)> {
+    let mut joined = Vec::<(bool, usize)>::with_capacity(runs.len());
+
+    for (is_silent, length) in runs {
+        match joined.last_mut() {
+            Some((last_is_silent, last_length)) if *last_is_silent == is_silent => {
+                *last_length += length
+            }
+            _ => joined.push((is_silent, length)),
+        }
+    }
+
+    joined
+}
+
+/// The ranges as a comma-separated list.
+pub fn format_ranges(ranges: &[TimeRange]) -> String {
+    ranges
+        .iter()
+        .map(|range| range.to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// The loudness (LUFS) of a block whose K-weighted mean squares, summed over the channels, are
+// `energy`.
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.max(f64::MIN_POSITIVE).log10()
+}
+
+// The level (dBFS) of a full-scale-relative amplitude; minus infinity for digital silence.
+fn decibels(amplitude: f64) -> f64 {
+    if amplitude <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+
+    20.0 * amplitude.log10()
+}
+
+// The K-weighting of ITU-R BS.1770: a high shelf modelling the head, then a high-pass filter,
+// with their analog prototypes' parameters so they fit any sample rate.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let sample_rate = sample_rate as f64;
+
+    let (frequency, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * frequency / sample_rate).tan();
+    let high_gain = 10f64.powf(gain_db / 20.0);
+    let band_gain = high_gain.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (high_gain + band_gain * k / q + k * k) / a0,
+            2.0 * (k * k - high_gain) / a0,
+            (high_gain - band_gain * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (frequency, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * frequency / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
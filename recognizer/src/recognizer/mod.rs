@@ -1,8 +1,26 @@
 /* file: src/recognizer/mod.rs
 
 */
+pub(crate) mod config;
+mod constellation;
+pub(crate) mod decimator;
 pub(crate) mod declarations;
+pub(crate) mod evaluation;
+pub(crate) mod explain;
 pub(crate) mod fingerprint;
+pub(crate) mod fingerprint_file;
+mod flac;
+pub(crate) mod loudness;
+pub(crate) mod preprocess;
+#[cfg(test)]
+mod robustness;
+pub(crate) mod segmentation;
 pub(crate) mod shazam;
-mod spectrogram;
+pub(crate) mod spectrogram;
+pub(crate) mod spectrogram_export;
+pub(crate) mod split;
+#[cfg(test)]
+pub(crate) mod test_audio;
+pub(crate) mod tracklist;
+pub(crate) mod triplet;
 pub(crate) mod wav;
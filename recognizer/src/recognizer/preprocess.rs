@@ -0,0 +1,398 @@
+// file: src/recognizer/preprocess.rs
+// purpose: optional clean-up of audio recorded from a turntable before it is fingerprinted. A
+// worn record adds a DC offset, sub-sonic rumble, clicks and crackle, and surface hiss, each of
+// which can outweigh the music in the spectrogram. Loudness normalisation (see `loudness`) is
+// chosen alongside them. Unlike the fingerprint profile, the steps used can differ between the
+// songs of a database and the snippets identified against it.
+
+use crate::recognizer::loudness::{LevelAnalysis, TimeRange};
+use std::f64::consts::PI;
+
+/// One step of the preprocessing chain, named on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PreprocessStep {
+    /// Remove the recording's DC offset
+    Dc,
+    /// High-pass filter out turntable rumble below 25 Hz
+    Rumble,
+    /// Detect clicks and crackle and interpolate over them
+    Declick,
+    /// Subtract each frequency's noise floor (e.g. surface hiss) from the spectrogram
+    Denoise,
+    /// Bring the whole recording to a standard loudness (-23 LUFS) first
+    Loudness,
+    /// Skip silence and noise-only stretches, such as a record's lead-in groove
+    Trim,
+}
+
+/// Which preprocessing steps are applied. Every step is off by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Preprocessing {
+    pub dc_removal: bool,
+    pub rumble_filter: bool,
+    pub declick: bool,
+    pub noise_subtraction: bool,
+    pub loudness_normalisation: bool,
+    pub silence_trimming: bool,
+}
+
+impl Preprocessing {
+    pub fn from_steps(steps: &[PreprocessStep]) -> Preprocessing {
+        Preprocessing {
+            dc_removal: steps.contains(&PreprocessStep::Dc),
+            rumble_filter: steps.contains(&PreprocessStep::Rumble),
+            declick: steps.contains(&PreprocessStep::Declick),
+            noise_subtraction: steps.contains(&PreprocessStep::Denoise),
+            loudness_normalisation: steps.contains(&PreprocessStep::Loudness),
+            silence_trimming: steps.contains(&PreprocessStep::Trim),
+        }
+    }
+
+    /// Whether a step needs the level of the whole recording (see `LevelAdjustment`).
+    pub fn needs_level(&self) -> bool {
+        self.loudness_normalisation || self.silence_trimming
+    }
+}
+
+/// What the steps that need the whole recording's level make of it: the gain to apply to every
+/// sample and the silent stretches whose peaks are skipped. Skipped peaks leave the times of the
+/// others unchanged, so matches still refer to the recording's own timeline.
+#[derive(Debug, Clone)]
+pub struct LevelAdjustment {
+    pub gain: f64,
+    pub skipped_ranges: Vec<TimeRange>,
+}
+
+impl LevelAdjustment {
+    pub fn none() -> LevelAdjustment {
+        LevelAdjustment {
+            gain: 1.0,
+            skipped_ranges: Vec::new(),
+        }
+    }
+
+    pub fn new(level: &LevelAnalysis, preprocessing: &Preprocessing) -> LevelAdjustment {
+        LevelAdjustment {
+            gain: if preprocessing.loudness_normalisation {
+                level.normalisation_gain()
+            } else {
+                1.0
+            },
+            skipped_ranges: if preprocessing.silence_trimming {
+                level.silent_ranges.clone()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+// Cut-off of the DC blocker, low enough to leave every audible frequency untouched
+const DC_CUTOFF_FREQUENCY: f64 = 5.0;
+
+// Cut-off of the rumble filter, a 4th-order Butterworth high-pass made of two biquads with these
+// Q factors. Rumble sits below about 20 Hz, under the lowest note of most music.
+const RUMBLE_CUTOFF_FREQUENCY: f64 = 25.0;
+const RUMBLE_FILTER_Q: [f64; 2] = [0.5412, 1.3066];
+
+// A sample is part of a click if its second difference is this many times the level of the
+// second differences of the click-free samples before it, and this many seconds either side of it
+// are replaced by a straight line between the samples around them. The level rises within about
+// `CLICK_ATTACK_SECONDS`, so it keeps up with a note's onset but not with a click, and falls
+// within about `CLICK_RELEASE_SECONDS`; it is only trusted after `CLICK_WARM_UP_SECONDS`.
+const CLICK_THRESHOLD: f64 = 6.0;
+const CLICK_HALF_WIDTH_SECONDS: f64 = 0.0002;
+const CLICK_ATTACK_SECONDS: f64 = 0.001;
+const CLICK_RELEASE_SECONDS: f64 = 0.05;
+const CLICK_WARM_UP_SECONDS: f64 = 0.01;
+
+// Each frequency bin's noise floor falls quickly to a quieter magnitude but rises only slowly to
+// a louder one (rates per frame), so it follows the quietest moments: the hiss between notes
+// rather than the notes. The floor is subtracted `NOISE_OVER_SUBTRACTION` times (more took too
+// much of the music with it), and a magnitude keeps at least `SPECTRAL_FLOOR` of itself so the
+// order of the loudest bins is kept.
+const NOISE_FLOOR_FALL_RATE: f64 = 0.2;
+const NOISE_FLOOR_RISE_RATE: f64 = 0.005;
+const NOISE_OVER_SUBTRACTION: f64 = 1.0;
+const SPECTRAL_FLOOR: f64 = 0.05;
+
+/// The sample-by-sample steps (DC removal, rumble filter and declicking, in that order) applied
+/// to a channel before it is low-pass filtered and downsampled. Declicking holds back a fraction
+/// of a millisecond of samples until it knows whether they are part of a click; every sample is
+/// returned, in order, by `push` or `finish`.
+pub struct SamplePreprocessor {
+    dc_blocker: Option<DcBlocker>,
+    rumble_filter: Option<[Biquad; 2]>,
+    declicker: Option<Declicker>,
+}
+
+impl SamplePreprocessor {
+    pub fn new(sample_rate: u32, preprocessing: &Preprocessing) -> SamplePreprocessor {
+        SamplePreprocessor {
+            dc_blocker: preprocessing
+                .dc_removal
+                .then(|| DcBlocker::new(sample_rate)),
+            rumble_filter: preprocessing.rumble_filter.then(|| {
+                RUMBLE_FILTER_Q.map(|q| Biquad::high_pass(RUMBLE_CUTOFF_FREQUENCY, q, sample_rate))
+            }),
+            declicker: preprocessing.declick.then(|| Declicker::new(sample_rate)),
+        }
+    }
+
+    /// Process the next samples of the channel, returning the samples that are ready.
+    pub fn push(&mut self, samples: &[f64]) -> Vec<f64> {
+        let mut filtered: Vec<f64> = samples.to_vec();
+
+        if let Some(dc_blocker) = &mut self.dc_blocker {
+            filtered
+                .iter_mut()
+                .for_each(|value| *value = dc_blocker.next(*value));
+        }
+
+        if let Some(rumble_filter) = &mut self.rumble_filter {
+            for biquad in rumble_filter {
+                filtered
+                    .iter_mut()
+                    .for_each(|value| *value = biquad.next(*value));
+            }
+        }
+
+        match &mut self.declicker {
+            Some(declicker) => declicker.push(&filtered),
+            None => filtered,
+        }
+    }
+
+    /// Signal the end of the channel, returning the samples still held back.
+    pub fn finish(&mut self) -> Vec<f64> {
+        match &mut self.declicker {
+            Some(declicker) => declicker.finish(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// A first-order high-pass filter with a very low cut-off.
+struct DcBlocker {
+    pole: f64,
+    previous_input: f64,
+    previous_output: f64,
+}
+
+impl DcBlocker {
+    fn new(sample_rate: u32) -> DcBlocker {
+        DcBlocker {
+            pole: 1.0 - 2.0 * PI * DC_CUTOFF_FREQUENCY / sample_rate as f64,
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    fn next(&mut self, value: f64) -> f64 {
+        let output = value - self.previous_input + self.pole * self.previous_output;
+
+        (self.previous_input, self.previous_output) = (value, output);
+        output
+    }
+}
+
+/// A second-order IIR filter (direct form I), with coefficients normalised so `a0` is 1.
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    inputs: [f64; 2],  // the previous input, then the one before it
+    outputs: [f64; 2], // the previous output, then the one before it
+}
+
+impl Biquad {
+    /// A filter with feedforward coefficients `b` and feedback coefficients `a` (`a1` and `a2`).
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Biquad {
+        Biquad {
+            b,
+            a,
+            inputs: [0.0; 2],
+            outputs: [0.0; 2],
+        }
+    }
+
+    // The high-pass filter of the "Audio EQ Cookbook".
+    fn high_pass(cutoff_frequency: f64, q: f64, sample_rate: u32) -> Biquad {
+        let omega = 2.0 * PI * cutoff_frequency / sample_rate as f64;
+        let alpha = omega.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+
+        Biquad::new(
+            [
+                (1.0 + omega.cos()) / 2.0 / a0,
+                -(1.0 + omega.cos()) / a0,
+                (1.0 + omega.cos()) / 2.0 / a0,
+            ],
+            [-2.0 * omega.cos() / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    pub fn next(&mut self, value: f64) -> f64 {
+        let output = self.b[0] * value + self.b[1] * self.inputs[0] + self.b[2] * self.inputs[1]
+            - self.a[0] * self.outputs[0]
+            - self.a[1] * self.outputs[1];
+
+        self.inputs = [value, self.inputs[0]];
+        self.outputs = [output, self.outputs[0]];
+        output
+    }
+}
+
+// Finds clicks (impulses much sharper than the music around them) and replaces the samples
+// around each with a straight line. Samples are held until no later click can reach them.
+struct Declicker {
+    half_width: usize,
+    warm_up: usize,
+    attack: f64, // rates per sample
+    release: f64,
+    level: f64,                    // envelope of the click-free second differences
+    learned: usize,                // samples `level` was learned from
+    held: Vec<f64>,                // samples not yet returned
+    scanned: usize,                // held samples already checked for clicks
+    click: Option<(usize, usize)>, // `[start, end)` in `held` of the click being found
+    previous: [f64; 2],            // the two samples returned last, oldest first
+}
+
+impl Declicker {
+    fn new(sample_rate: u32) -> Declicker {
+        let sample_rate = sample_rate as f64;
+
+        Declicker {
+            half_width: ((CLICK_HALF_WIDTH_SECONDS * sample_rate).round() as usize).max(1),
+            warm_up: (CLICK_WARM_UP_SECONDS * sample_rate).round() as usize,
+            attack: 1.0 / (CLICK_ATTACK_SECONDS * sample_rate).max(1.0),
+            release: 1.0 / (CLICK_RELEASE_SECONDS * sample_rate).max(1.0),
+            level: 0.0,
+            learned: 0,
+            held: Vec::new(),
+            scanned: 0,
+            click: None,
+            previous: [0.0; 2],
+        }
+    }
+
+    fn push(&mut self, samples: &[f64]) -> Vec<f64> {
+        self.held.extend_from_slice(samples);
+
+        while self.scanned < self.held.len() {
+            self.scan_next();
+        }
+
+        // A click found in the next samples can reach `half_width` samples back
+        let mut ready = self.scanned.saturating_sub(self.half_width);
+        if let Some((start, _)) = self.click {
+            ready = ready.min(start);
+        }
+
+        self.release(ready)
+    }
+
+    fn finish(&mut self) -> Vec<f64> {
+        if let Some((start, end)) = self.click.take() {
+            self.repair(start, end.min(self.held.len()));
+        }
+
+        self.release(self.held.len())
+    }
+
+    fn scan_next(&mut self) {
+        let idx = self.scanned;
+        let [before_previous, previous] = self.samples_before(idx);
+        let second_difference = (self.held[idx] - 2.0 * previous + before_previous).abs();
+
+        if self.learned >= self.warm_up && second_difference > CLICK_THRESHOLD * self.level {
+            let (start, end) = (
+                idx.saturating_sub(self.half_width),
+                idx + self.half_width + 1,
+            );
+
+            self.click = match self.click {
+                Some((click_start, click_end)) => Some((click_start, click_end.max(end))),
+                None => Some((start, end)),
+            };
+        } else if self.click.is_none() {
+            // An average at first, then an envelope once enough samples were seen
+            self.learned += 1;
+            let rate = if second_difference > self.level {
+                self.attack
+            } else {
+                self.release
+            };
+            self.level += rate.max(1.0 / self.learned as f64) * (second_difference - self.level);
+        }
+
+        self.scanned += 1;
+
+        // A click is repaired once the first sample after it is known to be click-free
+        if let Some((start, end)) = self.click {
+            if self.scanned > end {
+                self.click = None;
+                self.repair(start, end);
+            }
+        }
+    }
+
+    // The two samples before `held[idx]`, oldest first.
+    fn samples_before(&self, idx: usize) -> [f64; 2] {
+        match idx {
+            0 => self.previous,
+            1 => [self.previous[1], self.held[0]],
+            _ => [self.held[idx - 2], self.held[idx - 1]],
+        }
+    }
+
+    // Replace `held[start..end]` with a straight line between the samples either side of it.
+    fn repair(&mut self, start: usize, end: usize) {
+        let before = self.samples_before(start)[1];
+        let after = self.held.get(end).copied().unwrap_or(before);
+        let steps = (end - start + 1) as f64;
+
+        for (step, value) in self.held[start..end].iter_mut().enumerate() {
+            *value = before + (after - before) * (step + 1) as f64 / steps;
+        }
+    }
+
+    fn release(&mut self, count: usize) -> Vec<f64> {
+        let released: Vec<f64> = self.held.drain(..count).collect();
+
+        for value in &released {
+            self.previous = [self.previous[1], *value];
+        }
+
+        self.scanned -= count;
+        self.click = self.click.map(|(start, end)| (start - count, end - count));
+
+        released
+    }
+}
+
+/// Spectral subtraction of each frequency bin's noise floor, applied to spectrogram frames as
+/// they are made.
+#[derive(Default)]
+pub struct NoiseFloorSubtraction {
+    noise_floor: Vec<f64>,
+}
+
+impl NoiseFloorSubtraction {
+    pub fn apply(&mut self, frame: &mut [f64]) {
+        if self.noise_floor.len() != frame.len() {
+            self.noise_floor = frame.to_vec();
+        }
+
+        for (magnitude, floor) in frame.iter_mut().zip(&mut self.noise_floor) {
+            let rate = if *magnitude < *floor {
+                NOISE_FLOOR_FALL_RATE
+            } else {
+                NOISE_FLOOR_RISE_RATE
+            };
+            *floor += rate * (*magnitude - *floor);
+
+            *magnitude =
+                (*magnitude - NOISE_OVER_SUBTRACTION * *floor).max(SPECTRAL_FLOOR * *magnitude);
+        }
+    }
+}
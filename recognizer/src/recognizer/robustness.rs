@@ -0,0 +1,491 @@
+// file: src/recognizer/robustness.rs
+// purpose: tests of how many of a recording's hashes survive the distortions a snippet picks up
+// on its way to the recognizer (noise, a different level or equalisation, being cut at another
+// point or played at the wrong speed, the wear of a vinyl record), run on a synthetic song so
+// a change to the fingerprinting that makes it more fragile fails them.
+
+use crate::db::index_utils::MemoryIndex;
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::MATCH_SCORE_THRESHOLD;
+use crate::recognizer::fingerprint::{KeyAudioPoint, fingerprint_peaks};
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::shazam::{self, coherent_score};
+use crate::recognizer::spectrogram::PeakMethod;
+use crate::recognizer::test_audio::{self, SAMPLE_RATE};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+enum Distortion {
+    Noise { snr_db: f64 }, // white noise this many dB below the recording's RMS level
+    Gain { db: f64 },
+    // Bass and treble shelves, boosting or cutting everything below and above `EQ_CROSSOVER`
+    Equaliser { bass_db: f64, treble_db: f64 },
+    Shift { ms: f64 }, // the first `ms` milliseconds are cut off
+    // On average `per_second` impulses a second, each about `size` times the recording's RMS
+    // level and decaying over a few samples
+    Clicks { per_second: f64, size: f64 },
+    Rumble { db: f64 }, // a `RUMBLE_FREQUENCY` tone this many dB above the recording's RMS level
+    DcOffset { fraction: f64 }, // this fraction of full scale is added to every sample
+    WornRecord,         // every distortion of `WORN_RECORD` in turn
+}
+
+const WORN_RECORD: [Distortion; 5] = [
+    Distortion::DcOffset { fraction: 0.05 },
+    Distortion::Rumble { db: 6.0 },
+    Distortion::Clicks {
+        per_second: 5.0,
+        size: 20.0,
+    },
+    Distortion::Clicks {
+        per_second: 200.0,
+        size: 3.0,
+    },
+    Distortion::Noise { snr_db: 20.0 },
+];
+
+// The equaliser's shelves meet here (Hz)
+const EQ_CROSSOVER: f64 = 1000.0;
+
+// Turntable rumble is mostly below 20 Hz
+const RUMBLE_FREQUENCY: f64 = 12.0;
+
+// Each sample of a click is this fraction of the one before
+const CLICK_DECAY: f64 = 0.6;
+
+// The noise is the same on every run, so the tests are deterministic
+const NOISE_SEED: u64 = 0x5eed;
+
+// The synthetic song each test distorts
+const SONG_DURATION_SEC: f64 = 10.0;
+
+// Where the snippets the triplet tests identify are cut from a recording, and how long they are
+const SNIPPET_START_SEC: f64 = 1.5;
+const SNIPPET_DURATION_SEC: f64 = 6.0;
+
+// How far the tempo a triplet match finds may be from the snippet's, as a fraction of it
+const TIME_SCALE_TOLERANCE: f64 = 0.02;
+const SONG_SEED: u64 = 35;
+
+impl Distortion {
+    fn label(&self) -> String {
+        match self {
+            Distortion::Noise { snr_db } => format!("noise at {} dB SNR", snr_db),
+            Distortion::Gain { db } => format!("gain {:+} dB", db),
+            Distortion::Equaliser { bass_db, treble_db } => {
+                format!("bass {:+} dB, treble {:+} dB", bass_db, treble_db)
+            }
+            Distortion::Shift { ms } => format!("shifted {} ms", ms),
+            Distortion::Clicks { per_second, size } => {
+                format!("clicks {}/s at {}x RMS", per_second, size)
+            }
+            Distortion::Rumble { db } => format!("rumble at {:+} dB", db),
+            Distortion::DcOffset { fraction } => format!("DC offset {}%", fraction * 100.),
+            Distortion::WornRecord => "worn record".to_string(),
+        }
+    }
+
+    // How much earlier the distorted recording's peaks are than the original's.
+    fn time_offset_ms(&self) -> f64 {
+        match self {
+            Distortion::Shift { ms } => *ms,
+            _ => 0.0,
+        }
+    }
+
+    fn apply(&self, samples: &[f64]) -> Vec<f64> {
+        match self {
+            Distortion::Noise { snr_db } => {
+                let noise_level = rms(samples) / 10f64.powf(snr_db / 20.0);
+                let mut rng = StdRng::seed_from_u64(NOISE_SEED);
+
+                map_samples(samples, |_, value| {
+                    value + noise_level * standard_normal(&mut rng)
+                })
+            }
+            Distortion::Gain { db } => {
+                let factor = 10f64.powf(db / 20.0);
+
+                map_samples(samples, |_, value| value * factor)
+            }
+            Distortion::Equaliser { bass_db, treble_db } => {
+                let (bass, treble) = (10f64.powf(bass_db / 20.0), 10f64.powf(treble_db / 20.0));
+                // One-pole low-pass at the crossover; the treble is what it removes
+                let coefficient = (-2.0 * PI * EQ_CROSSOVER / SAMPLE_RATE as f64).exp();
+                let mut low = 0.0;
+
+                map_samples(samples, |_, value| {
+                    low = (1.0 - coefficient) * value + coefficient * low;
+                    bass * low + treble * (value - low)
+                })
+            }
+            Distortion::Shift { ms } => {
+                let cut = ((ms / 1000.0) * SAMPLE_RATE as f64).round() as usize;
+
+                samples.iter().skip(cut).copied().collect()
+            }
+            Distortion::Clicks { per_second, size } => {
+                let click_level = rms(samples) * size;
+                let probability = per_second / SAMPLE_RATE as f64;
+                let mut rng = StdRng::seed_from_u64(NOISE_SEED);
+                let mut click = 0.0;
+
+                map_samples(samples, |_, value| {
+                    if rng.gen_range(0.0, 1.0) < probability {
+                        let sign = if rng.gen_range(0.0, 1.0) < 0.5 {
+                            -1.0
+                        } else {
+                            1.0
+                        };
+                        click = sign * click_level * rng.gen_range(0.5, 1.5);
+                    }
+
+                    let clicked = value + click;
+                    click *= CLICK_DECAY;
+                    clicked
+                })
+            }
+            Distortion::Rumble { db } => {
+                // The RMS level of a sine wave is its amplitude / √2
+                let amplitude = rms(samples) * 10f64.powf(db / 20.0) * 2f64.sqrt();
+                let angular_frequency = 2.0 * PI * RUMBLE_FREQUENCY;
+
+                map_samples(samples, |idx, value| {
+                    let time_sec = idx as f64 / SAMPLE_RATE as f64;
+                    value + amplitude * (angular_frequency * time_sec).sin()
+                })
+            }
+            Distortion::DcOffset { fraction } => {
+                let offset = fraction * i16::MAX as f64;
+
+                map_samples(samples, |_, value| value + offset)
+            }
+            Distortion::WornRecord => WORN_RECORD
+                .iter()
+                .fold(samples.to_vec(), |worn, distortion| distortion.apply(&worn)),
+        }
+    }
+}
+
+// The share of the hashes of a recording (`original`) a distorted copy of it still produces at
+// the right time, to within a spectrogram frame. Like a song and the snippets identified against
+// it, only the distorted copy is preprocessed.
+fn hash_survival(
+    original: &HashMap<u32, KeyAudioPoint>,
+    distorted: &[f64],
+    time_offset_ms: f64,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+) -> f64 {
+    let distorted: HashMap<u32, KeyAudioPoint> =
+        test_audio::fingerprint_samples(distorted, 0, config, preprocessing).pairs;
+    let tolerance_ms = config.frame_duration(SAMPLE_RATE) * 1000.;
+
+    let aligned = original
+        .iter()
+        .filter(|(hash, original_point)| {
+            distorted.get(hash).is_some_and(|distorted_point| {
+                let offset_ms = (original_point.anchor_time_ms - distorted_point.anchor_time_ms)
+                    as f64
+                    - time_offset_ms;
+
+                offset_ms.abs() <= tolerance_ms
+            })
+        })
+        .count();
+
+    aligned as f64 / original.len().max(1) as f64
+}
+
+// Check every distortion leaves at least its minimum share of a synthetic song's hashes at the
+// right time, with `bands` and with `constellation` peaks (the two minimums, in that order).
+fn check_survival(preprocessing: &Preprocessing, minimums: &[(Distortion, [f64; 2])]) {
+    let song: Vec<f64> = test_audio::synthetic_song(SONG_DURATION_SEC, SONG_SEED);
+
+    for (config_idx, peak_method) in [PeakMethod::Bands, PeakMethod::Constellation]
+        .into_iter()
+        .enumerate()
+    {
+        let config = FingerprintConfig {
+            peak_method,
+            ..Default::default()
+        };
+        let original: HashMap<u32, KeyAudioPoint> =
+            test_audio::fingerprint_samples(&song, 0, &config, &Preprocessing::default()).pairs;
+
+        for (distortion, minimum) in minimums {
+            let minimum = minimum[config_idx];
+            let survival = hash_survival(
+                &original,
+                &distortion.apply(&song),
+                distortion.time_offset_ms(),
+                &config,
+                preprocessing,
+            );
+
+            assert!(
+                survival >= minimum,
+                "{:?} peaks: only {:.1}% of hashes survived {}, expected {:.1}%",
+                peak_method,
+                100. * survival,
+                distortion.label(),
+                100. * minimum
+            );
+        }
+    }
+}
+
+// Apply `function` to every sample (given its index), then round and clip the result as a
+// 16-bit recording would.
+fn map_samples(samples: &[f64], mut function: impl FnMut(usize, f64) -> f64) -> Vec<f64> {
+    samples
+        .iter()
+        .enumerate()
+        .map(|(idx, value)| {
+            function(idx, *value)
+                .round()
+                .clamp(i16::MIN as f64, i16::MAX as f64)
+        })
+        .collect()
+}
+
+fn rms(samples: &[f64]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+
+    let sum_of_squares: f64 = samples.iter().map(|value| value * value).sum();
+
+    (sum_of_squares / samples.len() as f64).sqrt()
+}
+
+// A normally distributed value with mean 0 and variance 1 (Box-Muller transform).
+fn standard_normal(rng: &mut StdRng) -> f64 {
+    let uniform_1: f64 = rng.gen_range(f64::MIN_POSITIVE, 1.0);
+    let uniform_2: f64 = rng.gen_range(0.0, 1.0);
+
+    (-2.0 * uniform_1.ln()).sqrt() * (2.0 * PI * uniform_2).cos()
+}
+
+// The recording played `speed` times as fast, by linear interpolation between its samples.
+fn resample(samples: &[f64], speed: f64) -> Vec<f64> {
+    let len = ((samples.len() - 1) as f64 / speed) as usize;
+
+    (0..len)
+        .map(|idx| {
+            let position = idx as f64 * speed;
+            let (before, fraction) = (position.floor() as usize, position.fract());
+
+            samples[before] * (1.0 - fraction)
+                + samples[(before + 1).min(samples.len() - 1)] * fraction
+        })
+        .collect()
+}
+
+// The minimums are about 80% of the shares measured when the tests were written, so they only
+// fail when a change makes the hashes markedly more fragile.
+
+#[test]
+fn hashes_survive_noise() {
+    check_survival(
+        &Preprocessing::default(),
+        &[
+            (Distortion::Noise { snr_db: 30.0 }, [0.85, 0.7]),
+            (Distortion::Noise { snr_db: 20.0 }, [0.8, 0.65]),
+            (Distortion::Noise { snr_db: 10.0 }, [0.75, 0.55]),
+        ],
+    );
+}
+
+#[test]
+fn hashes_survive_gain_and_equalisation() {
+    check_survival(
+        &Preprocessing::default(),
+        &[
+            (Distortion::Gain { db: -12.0 }, [0.9, 0.9]),
+            (Distortion::Gain { db: 6.0 }, [0.9, 0.9]),
+            (
+                Distortion::Equaliser {
+                    bass_db: 6.0,
+                    treble_db: -6.0,
+                },
+                [0.5, 0.85],
+            ),
+            (
+                Distortion::Equaliser {
+                    bass_db: -6.0,
+                    treble_db: 3.0,
+                },
+                [0.45, 0.85],
+            ),
+        ],
+    );
+}
+
+#[test]
+fn hashes_survive_time_shifts() {
+    check_survival(
+        &Preprocessing::default(),
+        &[
+            (Distortion::Shift { ms: 1.0 }, [0.85, 0.75]),
+            (Distortion::Shift { ms: 5.0 }, [0.75, 0.6]),
+            (Distortion::Shift { ms: 12.0 }, [0.65, 0.45]),
+            (Distortion::Shift { ms: 23.0 }, [0.6, 0.35]),
+        ],
+    );
+}
+
+#[test]
+fn preprocessing_recovers_hashes_of_a_worn_record() {
+    // Without preprocessing, a worn record keeps under 2% of its `bands` hashes
+    let vinyl = Preprocessing {
+        dc_removal: true,
+        rumble_filter: true,
+        declick: true,
+        ..Default::default()
+    };
+
+    check_survival(
+        &vinyl,
+        &[
+            (
+                Distortion::Clicks {
+                    per_second: 5.0,
+                    size: 20.0,
+                },
+                [0.8, 0.6],
+            ),
+            (Distortion::Rumble { db: 12.0 }, [0.6, 0.7]),
+            (Distortion::DcOffset { fraction: 0.1 }, [0.8, 0.7]),
+            (Distortion::WornRecord, [0.55, 0.3]),
+        ],
+    );
+}
+
+#[test]
+fn speed_search_recovers_a_snippet_played_too_fast() {
+    let speed = 1.02;
+    let config = FingerprintConfig {
+        peak_method: PeakMethod::Constellation,
+        ..Default::default()
+    };
+    let preprocessing = Preprocessing::default();
+
+    let song: Vec<f64> = test_audio::synthetic_song(SONG_DURATION_SEC, SONG_SEED);
+    let song_points = test_audio::fingerprint_samples(&song, 1, &config, &preprocessing).pairs;
+    let index = MemoryIndex::from_points(song_points.into_iter().collect(), Vec::new(), 1);
+
+    let snippet_peaks = test_audio::sample_peaks(&resample(&song, speed), &config, &preprocessing);
+    let score_at = |playback_speed: f64| -> f64 {
+        let fingerprint: HashMap<u32, u32> =
+            fingerprint_peaks(&snippet_peaks, 0, &config, playback_speed)
+                .pairs
+                .into_iter()
+                .map(|(hash, point)| (hash, point.anchor_time_ms as u32))
+                .collect();
+
+        shazam::find_hits(&index, &fingerprint)
+            .unwrap()
+            .get(&1)
+            .map_or(0.0, |hits| coherent_score(hits))
+    };
+
+    let (best_speed, best_score) = shazam::playback_speeds(0.03, false)
+        .into_iter()
+        .map(|playback_speed| (playback_speed, score_at(playback_speed)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap();
+
+    // At its own speed the snippet scores about 40 times what it does unchanged
+    assert!(
+        (best_speed - speed).abs() < 1e-9,
+        "matched at {}",
+        best_speed
+    );
+    assert!(best_score >= 10. * score_at(1.0));
+}
+
+// The triplet score and time scale a snippet cut from `recording` gets against an index of the
+// triplet hashes of the test song, where it starts `SNIPPET_START_SEC` in.
+fn triplet_match(recording: &[f64]) -> (f64, f64) {
+    let config = FingerprintConfig {
+        peak_method: PeakMethod::Constellation,
+        triplet_hashes: true,
+        ..Default::default()
+    };
+    let preprocessing = Preprocessing::default();
+
+    let song: Vec<f64> = test_audio::synthetic_song(SONG_DURATION_SEC, SONG_SEED);
+    let song_triplets = test_audio::fingerprint_samples(&song, 1, &config, &preprocessing).triplets;
+    let index = MemoryIndex::from_points(Vec::new(), song_triplets, 1);
+
+    let start = (SNIPPET_START_SEC * SAMPLE_RATE as f64) as usize;
+    let snippet = &recording[start..start + (SNIPPET_DURATION_SEC * SAMPLE_RATE as f64) as usize];
+    let snippet_triplets =
+        test_audio::fingerprint_samples(snippet, 0, &config, &preprocessing).triplets;
+
+    shazam::score_songs_by_triplets(&index, snippet_triplets)
+        .unwrap()
+        .get(&1)
+        .copied()
+        .unwrap_or((0.0, 1.0))
+}
+
+#[test]
+fn triplets_match_a_snippet_at_another_tempo() {
+    for tempo in [0.85, 0.94, 1.07, 1.2] {
+        let performance: Vec<f64> =
+            test_audio::synthetic_performance(SONG_DURATION_SEC, SONG_SEED, tempo, 1.0);
+        let (score, time_scale) = triplet_match(&performance);
+
+        assert!(
+            score >= MATCH_SCORE_THRESHOLD,
+            "scored {} at tempo {}",
+            score,
+            tempo
+        );
+        assert!(
+            (time_scale / tempo - 1.0).abs() <= TIME_SCALE_TOLERANCE,
+            "found tempo {} for {}",
+            time_scale,
+            tempo
+        );
+    }
+}
+
+#[test]
+fn triplets_match_a_snippet_at_another_pitch() {
+    for pitch in [0.89, 0.97, 1.06, 1.12] {
+        let performance: Vec<f64> =
+            test_audio::synthetic_performance(SONG_DURATION_SEC, SONG_SEED, 1.0, pitch);
+        let (score, time_scale) = triplet_match(&performance);
+
+        assert!(
+            score >= MATCH_SCORE_THRESHOLD,
+            "scored {} at pitch {}",
+            score,
+            pitch
+        );
+        assert!(
+            (time_scale - 1.0).abs() <= TIME_SCALE_TOLERANCE,
+            "found tempo {} at pitch {}",
+            time_scale,
+            pitch
+        );
+    }
+}
+
+#[test]
+fn triplets_do_not_match_an_unrelated_song() {
+    for seed in [SONG_SEED + 1, SONG_SEED + 2, SONG_SEED + 3] {
+        let (score, _) = triplet_match(&test_audio::synthetic_song(SONG_DURATION_SEC, seed));
+
+        assert!(
+            score < MATCH_SCORE_THRESHOLD,
+            "song {} scored {}",
+            seed,
+            score
+        );
+    }
+}
@@ -0,0 +1,216 @@
+// file: src/recognizer/segmentation.rs
+// purpose: splits a long recording, such as a whole vinyl side or a DJ set, into the songs it
+// plays. Overlapping windows of the recording are identified one at a time, and consecutive
+// windows matching the same song at the same offset are merged into one track segment.
+
+use crate::db::db_utils;
+use crate::db::index_utils::FingerprintIndex;
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::MATCH_SCORE_THRESHOLD;
+use crate::recognizer::fingerprint::{self, fingerprint_peaks};
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::shazam::{Hit, OffsetMatch, coherent_match, find_hits};
+use crate::recognizer::spectrogram::Peak;
+use crate::recognizer::wav::WavStream;
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Windows of one continuous play of a song agree on its offset to within this
+const OFFSET_TOLERANCE_MS: i32 = 500;
+
+// A segment carries on through this many windows in a row that do not match its song, such as a
+// quiet passage or a scratch
+const MAX_MISSED_WINDOWS: usize = 1;
+
+/// A stretch of a recording identified as one song.
+#[derive(Debug, Clone, Serialize)]
+pub struct Segment {
+    #[serde(skip)]
+    pub song_id: u32,
+    pub start_sec: f64,
+    pub end_sec: f64,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub reference_offset_sec: f64, // where in the song the segment starts
+    // The mean share of the best two songs' scores in the segment's windows that went to its
+    // song: near 1 when no other song came close, 0.5 when another scored the same
+    pub confidence: f64,
+}
+
+// The song a window of the recording matched best.
+struct WindowMatch {
+    song_id: u32,
+    offset_match: OffsetMatch, // hit times are times in the recording
+    confidence: f64,
+}
+
+// Consecutive windows matching one song at one offset.
+struct OpenSegment {
+    song_id: u32,
+    offset_ms: i32,
+    windows: Vec<WindowMatch>,
+    missed_windows: usize,
+}
+
+/// Identify every `window_sec` window of the recording at `file_path`, starting every `hop_sec`
+/// seconds, and merge the windows into the songs the recording plays, in order. A segment runs
+/// from the first to the last of its windows' hashes that matched its song.
+pub fn segment_recording(
+    file_path: &str,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+    index: &dyn FingerprintIndex,
+    window_sec: f64,
+    hop_sec: f64,
+) -> Result<Vec<Segment>, u8> {
+    let mut wav_stream = WavStream::open(file_path)?;
+    let duration_sec: f64 = wav_stream.info.duration_sec;
+
+    // The recording's peaks are found once, then fingerprinted a window at a time
+    let peaks: Vec<Vec<Peak>> = fingerprint::audio_peaks(&mut wav_stream, config, preprocessing)?;
+
+    let mut segments = Vec::<Segment>::new();
+    let mut open_segment: Option<OpenSegment> = None;
+    let mut window_start: f64 = 0.0;
+
+    loop {
+        let window_match: Option<WindowMatch> =
+            match_window(&peaks, window_start, window_sec, config, index)?;
+
+        open_segment = match (open_segment, window_match) {
+            (Some(mut segment), Some(window_match))
+                if window_match.song_id == segment.song_id
+                    && (window_match.offset_match.offset_ms - segment.offset_ms).abs()
+                        <= OFFSET_TOLERANCE_MS =>
+            {
+                segment.windows.push(window_match);
+                segment.missed_windows = 0;
+                Some(segment)
+            }
+            (Some(mut segment), None) if segment.missed_windows < MAX_MISSED_WINDOWS => {
+                segment.missed_windows += 1;
+                Some(segment)
+            }
+            (segment, window_match) => {
+                if let Some(segment) = segment {
+                    segments.push(close_segment(segment)?);
+                }
+
+                window_match.map(|window_match| OpenSegment {
+                    song_id: window_match.song_id,
+                    offset_ms: window_match.offset_match.offset_ms,
+                    windows: vec![window_match],
+                    missed_windows: 0,
+                })
+            }
+        };
+
+        if window_start + window_sec >= duration_sec {
+            break;
+        }
+        window_start += hop_sec;
+    }
+
+    if let Some(segment) = open_segment {
+        segments.push(close_segment(segment)?);
+    }
+
+    // Overlapping windows can let neighbouring segments overlap a little; split the difference
+    for idx in 1..segments.len() {
+        if segments[idx].start_sec < segments[idx - 1].end_sec {
+            let boundary = (segments[idx].start_sec + segments[idx - 1].end_sec) / 2.0;
+            let moved_by = boundary - segments[idx].start_sec;
+
+            segments[idx - 1].end_sec = boundary;
+            segments[idx].start_sec = boundary;
+            segments[idx].reference_offset_sec += moved_by;
+        }
+    }
+
+    Ok(segments)
+}
+
+// Identify the window of `window_sec` seconds from `start_sec`, returning the song that matched
+// it best if it scored at least `MATCH_SCORE_THRESHOLD`.
+fn match_window(
+    peaks: &[Vec<Peak>],
+    start_sec: f64,
+    window_sec: f64,
+    config: &FingerprintConfig,
+    index: &dyn FingerprintIndex,
+) -> Result<Option<WindowMatch>, u8> {
+    let window_peaks: Vec<Vec<Peak>> = peaks
+        .iter()
+        .map(|channel_peaks| {
+            channel_peaks
+                .iter()
+                .filter(|peak| peak.time_sec >= start_sec && peak.time_sec < start_sec + window_sec)
+                .copied()
+                .collect()
+        })
+        .collect();
+
+    let window_fingerprint: HashMap<u32, u32> = fingerprint_peaks(&window_peaks, 0, config, 1.0)
+        .pairs
+        .into_iter()
+        .map(|(hash, key_audio_point)| (hash, key_audio_point.anchor_time_ms as u32))
+        .collect();
+
+    let mut offset_matches: Vec<(u32, OffsetMatch)> = find_hits(index, &window_fingerprint)?
+        .into_iter()
+        .filter_map(|(song_id, hits): (u32, Vec<Hit>)| {
+            coherent_match(&hits).map(|offset_match| (song_id, offset_match))
+        })
+        .collect();
+    offset_matches.sort_by(|(_, a), (_, b)| b.score.total_cmp(&a.score));
+
+    let Some(&(song_id, offset_match)) = offset_matches.first() else {
+        return Ok(None);
+    };
+    if offset_match.score < MATCH_SCORE_THRESHOLD {
+        return Ok(None);
+    }
+
+    let runner_up_score: f64 = offset_matches.get(1).map_or(0.0, |(_, other)| other.score);
+
+    Ok(Some(WindowMatch {
+        song_id,
+        offset_match,
+        confidence: offset_match.score / (offset_match.score + runner_up_score),
+    }))
+}
+
+fn close_segment(segment: OpenSegment) -> Result<Segment, u8> {
+    let song = db_utils::get_song_by_id(segment.song_id)?;
+
+    let first_hit_ms: i32 = segment
+        .windows
+        .iter()
+        .map(|window| window.offset_match.first_hit_ms)
+        .min()
+        .unwrap_or(0);
+    let last_hit_ms: i32 = segment
+        .windows
+        .iter()
+        .map(|window| window.offset_match.last_hit_ms)
+        .max()
+        .unwrap_or(0);
+    let confidence: f64 = segment
+        .windows
+        .iter()
+        .map(|window| window.confidence)
+        .sum::<f64>()
+        / segment.windows.len() as f64;
+
+    Ok(Segment {
+        song_id: segment.song_id,
+        start_sec: first_hit_ms as f64 / 1000.,
+        end_sec: last_hit_ms as f64 / 1000.,
+        title: song.title,
+        artist: song.artist,
+        album: song.album,
+        reference_offset_sec: (first_hit_ms + segment.offset_ms) as f64 / 1000.,
+        confidence,
+    })
+}
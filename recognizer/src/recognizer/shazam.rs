@@ -2,16 +2,216 @@
 file: src/recognizer/shazam.rs
 */
 use crate::db::db_utils;
-use crate::recognizer::fingerprint::KeyAudioPoint;
-use std::collections::HashMap;
+use crate::db::index_utils::FingerprintIndex;
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::fingerprint::{AudioFingerprint, KeyAudioPoint, fingerprint_peaks};
+use crate::recognizer::spectrogram::Peak;
+use crate::recognizer::triplet::TripletPoint;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Match {
     pub name: String,
     pub artist: String,
     pub album: String,
     pub spotify_uri: Option<String>,
     pub score: f64,
+    pub speed_factor: f64, // how fast the snippet played compared to the song (1 = same speed)
+    pub time_scale: f64,   // how fast the snippet's tempo is compared to the song's (1 = same)
+}
+
+/// A hash of the snippet found in a song: when it occurs in each, and how much it counts
+/// towards the song's score.
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub sample_time_ms: i32,
+    pub db_time_ms: i32,
+    pub weight: f64,
+}
+
+/// Where a snippet lines up with a song: the score of the hits that agree, how many there are,
+/// the offset they agree on (the median of song time - snippet time, in ms) and the snippet times
+/// of the first and last of them.
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetMatch {
+    pub score: f64,
+    pub aligned_hits: usize,
+    pub offset_bin: i32, // the middle bin of the three the agreeing hits' offsets fall in
+    pub offset_ms: i32,
+    pub first_hit_ms: i32,
+    pub last_hit_ms: i32,
+}
+
+impl Hit {
+    pub(crate) fn offset_bin(&self) -> i32 {
+        (self.db_time_ms - self.sample_time_ms).div_euclid(OFFSET_BIN_MS)
+    }
+}
+
+// The best score of a song and the speed and tempo it was found at.
+struct SongScore {
+    score: f64,
+    speed_factor: f64,
+    time_scale: f64,
+}
+
+// Hits are binned by their offset (song time - snippet time) in bins this wide.
+pub(crate) const OFFSET_BIN_MS: i32 = 100;
+
+// A hit at the best offset counts in full only if another hit there is this close to it in the
+// snippet; an isolated one counts this share of its weight.
+const COHERENCE_WINDOW_MS: i32 = 1000;
+const ISOLATED_HIT_WEIGHT: f64 = 0.5;
+
+// Speeds a record is meant to be played at, in revolutions per minute
+const RPM_33: f64 = 100. / 3.;
+const RPM_45: f64 = 45.;
+
+/// Widest speed tolerance (a fraction) `playback_speeds` tries. Every speed tried is another
+/// lookup whose best score is held to the same `MATCH_SCORE_THRESHOLD`, so a wide tolerance
+/// takes longer and makes a false match more likely: ±20% is already 81 speeds.
+pub const MAX_SPEED_TOLERANCE: f64 = 0.2;
+
+// Difference between consecutive speeds tried. At 0.5%, a peak in the top FFT bin (511) is off
+// by at most 1.3 bins at the nearest speed tried, and most peaks by less than half a bin.
+const SPEED_SEARCH_STEP: f64 = 0.005;
+
+// Triplet matches are scored at time scales this far apart (in natural logarithm, so about
+// 0.5%): at the nearest one, the offsets of a 10 s snippet's matches drift apart by at most 25 ms.
+const TIME_SCALE_STEP: f64 = 0.005;
+
+// A triplet match counts towards the time scales within this of its own (in natural logarithm):
+// spans are whole frames, so the shortest can be a frame out of three, which is ln(4/3).
+const VOTE_TIME_SCALE_TOLERANCE: f64 = 0.29;
+
+// Triplet matches only vote for time scales up to this far from 1 (in natural logarithm, so a
+// tempo change of about ±30%)
+const MAX_LN_TIME_SCALE: f64 = 0.3;
+
+// Triplet matches at the time scale found agree on where the snippet starts in the song if
+// their offsets are no more than this far apart.
+const TRIPLET_OFFSET_TOLERANCE_MS: f64 = 100.;
+
+/// The playback speeds to try when identifying a snippet: every `SPEED_SEARCH_STEP` within
+/// `tolerance` (a fraction, e.g. 0.08 for ±8%) of the right speed, and, with `rpm_conversion`,
+/// within `tolerance` of a 33⅓ RPM record played at 45 RPM and vice versa. Speeds nearer their
+/// nominal speed come first, so they win ties. A `tolerance` above `MAX_SPEED_TOLERANCE` is
+/// limited to it.
+pub fn playback_speeds(tolerance: f64, rpm_conversion: bool) -> Vec<f64> {
+    let mut nominal_speeds = vec![1.0];
+    if rpm_conversion {
+        nominal_speeds.extend([RPM_45 / RPM_33, RPM_33 / RPM_45]);
+    }
+
+    // NaN is no tolerance at all
+    let tolerance = if tolerance > 0.0 {
+        tolerance.min(MAX_SPEED_TOLERANCE)
+    } else {
+        0.0
+    };
+    let steps = (tolerance / SPEED_SEARCH_STEP).round() as i32;
+
+    let mut speeds = Vec::<f64>::new();
+    for step in 0..=steps {
+        for nominal_speed in &nominal_speeds {
+            speeds.push(nominal_speed * (1.0 + step as f64 * SPEED_SEARCH_STEP));
+
+            if step > 0 {
+                speeds.push(nominal_speed * (1.0 - step as f64 * SPEED_SEARCH_STEP));
+            }
+        }
+    }
+
+    speeds
+}
+
+/// Identify a snippet from its peaks (see `fingerprint::audio_peaks`), trying each playback
+/// speed in turn. Each song is scored at the speed it matched best, which is reported as the
+/// match's `speed_factor`. If the profile enables `triplet_hashes`, each song is also scored
+/// by its triplet hashes under a linear time-scaling model, and the better of the two scores is
+/// kept; a triplet match reports the tempo it found as its `time_scale` and a `speed_factor`
+/// of 1. Pair hashes are looked up in `index`.
+pub fn find_matches_at_speeds(
+    channel_peaks: &[Vec<Peak>],
+    config: &FingerprintConfig,
+    playback_speeds: &[f64],
+    index: &dyn FingerprintIndex,
+) -> Result<Vec<Match>, u8> {
+    let mut best_scores = HashMap::<u32, SongScore>::new();
+
+    for &speed in playback_speeds {
+        let fingerprint: HashMap<u32, u32> = fingerprint_peaks(channel_peaks, 0, config, speed)
+            .pairs
+            .into_iter()
+            .map(|(hash, key_audio_point)| (hash, key_audio_point.anchor_time_ms as u32))
+            .collect();
+
+        for (song_id, score) in score_songs(index, fingerprint)? {
+            keep_best_score(&mut best_scores, song_id, score, speed, speed);
+        }
+    }
+
+    if config.triplet_hashes {
+        // Triplet hashes do not change with the speed, so the snippet's are made only once
+        let triplets = fingerprint_peaks(channel_peaks, 0, config, 1.0).triplets;
+
+        for (song_id, (score, time_scale)) in score_songs_by_triplets(index, triplets)? {
+            keep_best_score(&mut best_scores, song_id, score, 1.0, time_scale);
+        }
+    }
+
+    matches_from_scores(best_scores)
+}
+
+/// Identify a snippet from a fingerprint made beforehand, e.g. one read from a fingerprint file,
+/// as `find_matches_at_speeds` does at the snippet's own speed: only its peaks can be
+/// fingerprinted again at other speeds.
+pub fn find_matches_in_fingerprint(
+    fingerprint: AudioFingerprint,
+    index: &dyn FingerprintIndex,
+) -> Result<Vec<Match>, u8> {
+    let mut best_scores = HashMap::<u32, SongScore>::new();
+
+    let pairs: HashMap<u32, u32> = fingerprint
+        .pairs
+        .into_iter()
+        .map(|(hash, key_audio_point)| (hash, key_audio_point.anchor_time_ms as u32))
+        .collect();
+
+    for (song_id, score) in score_songs(index, pairs)? {
+        keep_best_score(&mut best_scores, song_id, score, 1.0, 1.0);
+    }
+
+    if !fingerprint.triplets.is_empty() {
+        for (song_id, (score, time_scale)) in score_songs_by_triplets(index, fingerprint.triplets)?
+        {
+            keep_best_score(&mut best_scores, song_id, score, 1.0, time_scale);
+        }
+    }
+
+    matches_from_scores(best_scores)
+}
+
+fn keep_best_score(
+    best_scores: &mut HashMap<u32, SongScore>,
+    song_id: u32,
+    score: f64,
+    speed_factor: f64,
+    time_scale: f64,
+) {
+    let song_score = SongScore {
+        score,
+        speed_factor,
+        time_scale,
+    };
+
+    match best_scores.get(&song_id) {
+        Some(best_score) if best_score.score >= score => {}
+        _ => {
+            best_scores.insert(song_id, song_score);
+        }
+    }
 }
 
 /*
@@ -35,63 +235,143 @@ pub(crate) fn find_match (sample: Vec<f64>, sample_duration: f64, sample_rate: u
     Ok(matches[0].clone())
 }*/
 
-pub fn find_matches_from_fingerprint(fingerprint: HashMap<u32, u32>) -> Result<Vec<Match>, u8> {
-    let hashes: Vec<i32> = fingerprint.keys().cloned().map(|x: u32| x as i32).collect();
-
-    let matched_fingerprints: HashMap<u32, Vec<KeyAudioPoint>> =
-        db_utils::get_key_audio_points(hashes)?;
-
-    // A HashMap mapping unsigned integers to fixed-sized arrays of size 2.
-    // songID -> [(sampleTime, dbTime)
-    let mut matches = HashMap::<u32, Vec<[u32; 2]>>::new();
-
-    // songID -> the earliest anchor timestamp
-    let mut timestamps = HashMap::<u32, u32>::new();
-
-    // A histogram, songID -> timestamp -> count
-    let mut target_zones = HashMap::<u32, HashMap<u32, i32>>::new();
-
-    for (hash, key_audio_point_vec) in matched_fingerprints {
-        for key_audio_point in key_audio_point_vec {
-            let song_id: u32 = key_audio_point.song_id as u32;
-            let anchor_time_ms: u32 = key_audio_point.anchor_time_ms as u32;
-
-            matches
-                .entry(song_id)
-                .and_modify(|x: &mut Vec<[u32; 2]>| x.push([fingerprint[&hash], anchor_time_ms]))
-                .or_insert(Vec::new());
-
-            // If there is already a timestamp for this hash, see if the new timestamp we
-            // encountered is closer. If not, insert the current anchor time
-            timestamps
-                .entry(song_id)
-                .and_modify(|timestamp: &mut u32| {
-                    if anchor_time_ms < *timestamp {
-                        *timestamp = anchor_time_ms
-                    }
-                })
-                .or_insert(anchor_time_ms);
-
-            // For a song ID, get a Map that represents the count of "matches" for a specific
-            // anchor time, either incrementing that count or initializing it to 0.
-            target_zones
-                .entry(song_id)
-                .and_modify(|zone_map: &mut HashMap<u32, i32>| {
-                    zone_map
-                        .entry(anchor_time_ms)
-                        .and_modify(|count: &mut i32| {
-                            *count += 1;
-                        })
-                        .or_insert(0);
+// Score every song sharing a hash with the fingerprint (hash -> anchor time in ms).
+fn score_songs(
+    index: &dyn FingerprintIndex,
+    fingerprint: HashMap<u32, u32>,
+) -> Result<HashMap<u32, f64>, u8> {
+    Ok(find_hits(index, &fingerprint)?
+        .into_iter()
+        .map(|(song_id, hits)| (song_id, coherent_score(&hits)))
+        .collect())
+}
+
+/// Every song sharing a hash with the fingerprint (hash -> anchor time in ms), with all of its
+/// hits. A hash found in fewer songs says more about which song the snippet is, so a hit on a
+/// hash in `n` of the database's `N` songs weighs `ln(1 + N/n) / ln(1 + N)`: 1 for a hash only
+/// one song has, less the more songs share it.
+pub fn find_hits(
+    index: &dyn FingerprintIndex,
+    fingerprint: &HashMap<u32, u32>,
+) -> Result<HashMap<u32, Vec<Hit>>, u8> {
+    let hashes: Vec<u32> = fingerprint.keys().cloned().collect();
+
+    let matched_fingerprints: HashMap<u32, Vec<KeyAudioPoint>> = index.key_audio_points(&hashes)?;
+    let song_count = index.song_count()?.max(1) as f64;
+
+    // songID -> every hit on one of its hashes
+    let mut hits = HashMap::<u32, Vec<Hit>>::new();
+
+    for (hash, key_audio_points) in matched_fingerprints {
+        let songs_with_hash = key_audio_points
+            .iter()
+            .map(|key_audio_point| key_audio_point.song_id)
+            .collect::<HashSet<i32>>()
+            .len()
+            .max(1) as f64;
+        let weight = (1.0 + song_count / songs_with_hash).ln() / (1.0 + song_count).ln();
+
+        for key_audio_point in key_audio_points {
+            hits.entry(key_audio_point.song_id as u32)
+                .or_default()
+                .push(Hit {
+                    sample_time_ms: fingerprint[&hash] as i32,
+                    db_time_ms: key_audio_point.anchor_time_ms,
+                    weight,
                 });
         }
     }
 
-    let scores: HashMap<u32, f64> = analyze_relative_timing(matches);
+    Ok(hits)
+}
+
+/// Score a song's hits: the weight of the hits whose offsets (song time - snippet time) fall in
+/// the heaviest window of three neighbouring `OFFSET_BIN_MS` bins, so offsets split by a bin
+/// boundary still agree. A true match's hits run through the snippet one after another, while
+/// chance hits at one offset are few and far apart, so a hit with no other hit in the window
+/// within `COHERENCE_WINDOW_MS` of it in the snippet counts only `ISOLATED_HIT_WEIGHT` of its
+/// weight.
+pub fn coherent_score(hits: &[Hit]) -> f64 {
+    coherent_match(hits).map_or(0.0, |offset_match| offset_match.score)
+}
+
+/// The offset a song's hits agree on best, scored as in `coherent_score`, or `None` without
+/// hits.
+pub fn coherent_match(hits: &[Hit]) -> Option<OffsetMatch> {
+    let mut bin_weights = HashMap::<i32, f64>::new();
+    for hit in hits {
+        *bin_weights.entry(hit.offset_bin()).or_default() += hit.weight;
+    }
+
+    let window_weight = |center: i32| -> f64 {
+        (center - 1..=center + 1)
+            .filter_map(|bin| bin_weights.get(&bin))
+            .sum()
+    };
+
+    // Of equally heavy windows, the earliest wins
+    let best_bin: i32 = bin_weights.keys().copied().max_by(|a, b| {
+        window_weight(*a)
+            .total_cmp(&window_weight(*b))
+            .then(b.cmp(a))
+    })?;
+
+    let mut aligned: Vec<&Hit> = hits
+        .iter()
+        .filter(|hit| (hit.offset_bin() - best_bin).abs() <= 1)
+        .collect();
+    aligned.sort_by_key(|hit| hit.sample_time_ms);
+
+    let is_near =
+        |a: &Hit, b: &Hit| (a.sample_time_ms - b.sample_time_ms).abs() <= COHERENCE_WINDOW_MS;
+
+    let score: f64 = aligned
+        .iter()
+        .enumerate()
+        .map(|(idx, hit)| {
+            let has_neighbour = (idx > 0 && is_near(hit, aligned[idx - 1]))
+                || aligned.get(idx + 1).is_some_and(|next| is_near(hit, next));
+
+            if has_neighbour {
+                hit.weight
+            } else {
+                hit.weight * ISOLATED_HIT_WEIGHT
+            }
+        })
+        .sum();
+
+    let mut offsets: Vec<i32> = aligned
+        .iter()
+        .map(|hit| hit.db_time_ms - hit.sample_time_ms)
+        .collect();
+    offsets.sort_unstable();
+
+    Some(OffsetMatch {
+        score,
+        aligned_hits: aligned.len(),
+        offset_bin: best_bin,
+        offset_ms: offsets[offsets.len() / 2],
+        first_hit_ms: aligned[0].sample_time_ms,
+        last_hit_ms: aligned[aligned.len() - 1].sample_time_ms,
+    })
+}
 
+/// The score songs had before `coherent_score`: the number of hits in the single busiest
+/// `OFFSET_BIN_MS` offset bin, every hit counting once. Kept so `evaluate` can compare the two.
+pub fn offset_bin_score(hits: &[Hit]) -> f64 {
+    let mut bin_counts = HashMap::<i32, usize>::new();
+    for hit in hits {
+        *bin_counts.entry(hit.offset_bin()).or_default() += 1;
+    }
+
+    bin_counts.into_values().max().unwrap_or(0) as f64
+}
+
+// Look up the songs of `scores`, best match first.
+fn matches_from_scores(scores: HashMap<u32, SongScore>) -> Result<Vec<Match>, u8> {
     let mut match_list = Vec::<Match>::new();
 
-    for (song_id, score) in scores {
+    for (song_id, song_score) in scores {
         match db_utils::get_song_by_id(song_id) {
             Ok(song) => {
                 match_list.push(Match {
@@ -99,7 +379,9 @@ pub fn find_matches_from_fingerprint(fingerprint: HashMap<u32, u32>) -> Result<V
                     artist: song.artist,
                     album: song.album,
                     spotify_uri: song.spotify_uri,
-                    score,
+                    score: song_score.score,
+                    speed_factor: song_score.speed_factor,
+                    time_scale: song_score.time_scale,
                 });
             }
             Err(error) => {
@@ -116,37 +398,229 @@ pub fn find_matches_from_fingerprint(fingerprint: HashMap<u32, u32>) -> Result<V
     Ok(match_list)
 }
 
-fn analyze_relative_timing(matches: HashMap<u32, Vec<[u32; 2]>>) -> HashMap<u32, f64> {
-    let mut scores = HashMap::<u32, f64>::new();
+// A snippet's triplet found in a song: the time scale their spans give (in natural logarithm),
+// the anchor times in the snippet and the song, and how much it counts towards the song's score.
+struct TripletVote {
+    ln_time_scale: f64,
+    sample_time_ms: f64,
+    db_time_ms: f64,
+    weight: f64,
+}
+
+// Score every song sharing a triplet hash with the snippet, returning songID -> (score, time
+// scale). Where a song's triplet occurs at `db_anchor` with span `db_span` and the snippet's at
+// `sample_anchor` with `sample_span`, the snippet plays about `db_span / sample_span` times as
+// fast as the song, from `db_anchor - time_scale * sample_anchor` in the song. A song's score is
+// the weight of its matches that agree on both, within the tolerances above, each weighed by how
+// rare its hash is as a hit is in `find_hits`.
+pub(crate) fn score_songs_by_triplets(
+    index: &dyn FingerprintIndex,
+    triplets: Vec<(u32, TripletPoint)>,
+) -> Result<HashMap<u32, (f64, f64)>, u8> {
+    let hashes: Vec<u32> = triplets
+        .iter()
+        .map(|(hash, _)| *hash)
+        .collect::<HashSet<u32>>()
+        .into_iter()
+        .collect();
+
+    let matched_triplets: HashMap<u32, Vec<TripletPoint>> = index.triplet_points(&hashes)?;
+    let song_count = index.song_count()?.max(1) as f64;
+
+    let weights: HashMap<u32, f64> = matched_triplets
+        .iter()
+        .map(|(hash, db_points)| {
+            let songs_with_hash = db_points
+                .iter()
+                .map(|db_point| db_point.song_id)
+                .collect::<HashSet<i32>>()
+                .len()
+                .max(1) as f64;
+
+            (
+                *hash,
+                (1.0 + song_count / songs_with_hash).ln() / (1.0 + song_count).ln(),
+            )
+        })
+        .collect();
+
+    // songID -> every vote of one of its triplets
+    let mut votes = HashMap::<u32, Vec<TripletVote>>::new();
+
+    for (hash, sample_point) in &triplets {
+        let Some(db_points) = matched_triplets.get(hash) else {
+            continue;
+        };
+
+        for db_point in db_points {
+            let ln_time_scale = (db_point.span_ms as f64 / sample_point.span_ms as f64).ln();
+
+            if ln_time_scale.abs() <= MAX_LN_TIME_SCALE + VOTE_TIME_SCALE_TOLERANCE {
+                votes
+                    .entry(db_point.song_id as u32)
+                    .or_default()
+                    .push(TripletVote {
+                        ln_time_scale,
+                        sample_time_ms: sample_point.anchor_time_ms as f64,
+                        db_time_ms: db_point.anchor_time_ms as f64,
+                        weight: weights[hash],
+                    });
+            }
+        }
+    }
+
+    Ok(votes
+        .into_iter()
+        .map(|(song_id, song_votes)| (song_id, score_time_scaled_votes(&song_votes)))
+        .collect())
+}
+
+// Find the time scale at which the heaviest votes agree on where the snippet starts in the song,
+// returning their weight and the time scale. A few percent of tempo change often leaves a short
+// span the same, so the votes' own time scales would mostly say 1: instead, every time scale in
+// range is tried in `TIME_SCALE_STEP`s with the votes near it, and the best is refined by fitting
+// a line through the anchor times of the votes that agree there.
+fn score_time_scaled_votes(votes: &[TripletVote]) -> (f64, f64) {
+    let steps = (MAX_LN_TIME_SCALE / TIME_SCALE_STEP).round() as i32;
+    let total_weight =
+        |agreeing: &[&TripletVote]| -> f64 { agreeing.iter().map(|vote| vote.weight).sum() };
 
-    for (song_id, vector_of_times) in matches {
-        let mut offset_counts = HashMap::<i32, i32>::new();
+    // Of equal weights, the time scale nearest the same tempo wins
+    let Some((_, best_step)) = (-steps..=steps)
+        .map(|step| {
+            let agreeing = agreeing_votes(votes, step as f64 * TIME_SCALE_STEP);
+            (total_weight(&agreeing), step)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0).then(b.1.abs().cmp(&a.1.abs())))
+    else {
+        return (0.0, 1.0);
+    };
 
-        for time_array in vector_of_times {
-            // The time when the amplitudes that generated this unique hash occurred for the
-            // sample and when it occurred for the matching value found in the database
-            let (sample_time, db_time) = (time_array[0] as i32, time_array[1] as i32);
+    let ln_estimate = best_step as f64 * TIME_SCALE_STEP;
+    let agreeing = agreeing_votes(votes, ln_estimate);
+    let estimate = (total_weight(&agreeing), ln_estimate.exp());
 
-            let offset: i32 = db_time - sample_time;
+    match fit_time_scale(&agreeing) {
+        Some(time_scale) => {
+            let refined = total_weight(&agreeing_votes(votes, time_scale.ln()));
 
-            // Bin offsets in 100ms buckets to allow for small timing variations
-            let offset_key = offset / 100;
-            offset_counts
-                .entry(offset_key)
-                .and_modify(|count: &mut i32| {
-                    *count += 1;
-                })
-                .or_insert(0);
+            if refined >= estimate.0 {
+                (refined, time_scale)
+            } else {
+                estimate
+            }
         }
+        None => estimate,
+    }
+}
+
+// The heaviest set of `votes` near `ln_time_scale` whose offsets `song time - time_scale * snippet
+// time` are within the tolerance of one another, found with a sliding window.
+fn agreeing_votes(votes: &[TripletVote], ln_time_scale: f64) -> Vec<&TripletVote> {
+    let time_scale = ln_time_scale.exp();
 
-        if let Some(key_value_pair) = offset_counts.into_iter().max_by_key(|(_, count)| *count) {
-            let max_value = key_value_pair.1 as f64;
-            scores
-                .entry(song_id)
-                .and_modify(|x| *x = max_value)
-                .or_insert(max_value);
+    let mut offsets: Vec<(f64, &TripletVote)> = votes
+        .iter()
+        .filter(|vote| (vote.ln_time_scale - ln_time_scale).abs() <= VOTE_TIME_SCALE_TOLERANCE)
+        .map(|vote| (vote.db_time_ms - time_scale * vote.sample_time_ms, vote))
+        .collect();
+    offsets.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let (mut best_low, mut best_high, mut best_weight) = (0, 0, 0.0);
+    let (mut low, mut weight) = (0, 0.0);
+    for high in 0..offsets.len() {
+        weight += offsets[high].1.weight;
+        while offsets[low].0 < offsets[high].0 - TRIPLET_OFFSET_TOLERANCE_MS {
+            weight -= offsets[low].1.weight;
+            low += 1;
+        }
+        if weight > best_weight {
+            (best_low, best_high, best_weight) = (low, high + 1, weight);
         }
     }
 
-    scores
+    offsets[best_low..best_high]
+        .iter()
+        .map(|(_, vote)| *vote)
+        .collect()
+}
+
+// The slope of the least-squares line of song time against snippet time, or `None` if the
+// snippet times are too close together to fit one.
+fn fit_time_scale(votes: &[&TripletVote]) -> Option<f64> {
+    let count = votes.len() as f64;
+    let mean_sample = votes.iter().map(|vote| vote.sample_time_ms).sum::<f64>() / count;
+    let mean_db = votes.iter().map(|vote| vote.db_time_ms).sum::<f64>() / count;
+
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for vote in votes {
+        covariance += (vote.sample_time_ms - mean_sample) * (vote.db_time_ms - mean_db);
+        variance += (vote.sample_time_ms - mean_sample).powi(2);
+    }
+
+    // The snippet times are spread over less than about a second (their standard deviation)
+    if variance < count * 1000. * 1000. {
+        return None;
+    }
+
+    let time_scale = covariance / variance;
+    (time_scale.ln().abs() <= MAX_LN_TIME_SCALE).then_some(time_scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::index_utils::MemoryIndex;
+    use crate::recognizer::preprocess::Preprocessing;
+    use crate::recognizer::spectrogram::PeakMethod;
+    use crate::recognizer::test_audio::{self, SAMPLE_RATE};
+
+    // Fingerprint a clip cut from a synthetic song at `clip_start_sec`, and return where in the
+    // song its hits line up and how far that may be from the cut (one spectrogram frame).
+    fn align_clip(config: &FingerprintConfig, clip_start_sec: f64) -> (OffsetMatch, f64) {
+        let song: Vec<f64> = test_audio::synthetic_song(30.0, 33);
+        let clip_start = (clip_start_sec * SAMPLE_RATE as f64).round() as usize;
+        let clip: &[f64] = &song[clip_start..clip_start + 8 * SAMPLE_RATE as usize];
+
+        let preprocessing = Preprocessing::default();
+        let song_fingerprint = test_audio::fingerprint_samples(&song, 1, config, &preprocessing);
+        let clip_fingerprint: HashMap<u32, u32> =
+            test_audio::fingerprint_samples(clip, 0, config, &preprocessing)
+                .pairs
+                .into_iter()
+                .map(|(hash, point)| (hash, point.anchor_time_ms as u32))
+                .collect();
+
+        let index =
+            MemoryIndex::from_points(song_fingerprint.pairs.into_iter().collect(), Vec::new(), 1);
+        let hits = find_hits(&index, &clip_fingerprint).unwrap();
+        let offset_match = coherent_match(&hits[&1]).unwrap();
+
+        (offset_match, config.frame_duration(SAMPLE_RATE) * 1000.)
+    }
+
+    #[test]
+    fn clip_aligns_where_it_was_cut_from() {
+        for peak_method in [PeakMethod::Bands, PeakMethod::Constellation] {
+            let config = FingerprintConfig {
+                peak_method,
+                ..Default::default()
+            };
+
+            // Neither start falls on a frame boundary of the song
+            for clip_start_sec in [4.0123, 17.3456] {
+                let (offset_match, tolerance_ms) = align_clip(&config, clip_start_sec);
+                let error_ms = offset_match.offset_ms as f64 - clip_start_sec * 1000.;
+
+                assert!(
+                    error_ms.abs() <= tolerance_ms,
+                    "{:?} clip cut at {}s aligned {}ms away",
+                    peak_method,
+                    clip_start_sec,
+                    error_ms
+                );
+                assert!(offset_match.aligned_hits >= 100);
+            }
+        }
+    }
 }
@@ -1,233 +1,409 @@
 // file: src/recognizer/spectrogram.rs
 
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::decimator::Decimator;
 use crate::recognizer::declarations::SPECTROGRAM_GENERATION_FAILURE;
-use rustfft::{FftPlanner, num_complex::Complex};
+use crate::recognizer::loudness::TimeRange;
+use crate::recognizer::preprocess::{NoiseFloorSubtraction, Preprocessing, SamplePreprocessor};
+use realfft::{RealFftPlanner, RealToComplex};
+use rustfft::FftNum;
+use rustfft::num_complex::Complex;
+use rustfft::num_traits::Float;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::f64::consts::PI;
+use std::sync::{Arc, Mutex, OnceLock};
 
-const MAX_FREQUENCY: f64 = 5000.0; // 5 kHz
-const DSP_RATIO: u32 = 4;
-const WINDOW_SIZE: usize = 1024;
-const SCROLL_SIZE: usize = WINDOW_SIZE / 2; // allow overlap
-
+#[derive(Clone, Copy)]
 pub struct Peak {
     pub frequency: f64,
     pub time_sec: f64,
+    pub frequency_idx: usize, // FFT bin
+    pub frame_idx: usize,     // spectrogram frame
 }
 
-/// Given a digitized audio sample, produce the spectrogram as a map of floating point magnitudes.
-pub fn gen_spectrogram(sample: Vec<f64>, sample_rate: u32) -> Result<Vec<Vec<f64>>, u8> {
-    let mut spectrogram: Vec<Vec<f64>> = Vec::new();
+/// How the peaks of a spectrogram are chosen. Songs and snippets must be fingerprinted with the
+/// same method to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeakMethod {
+    /// The loudest frequency of each fixed frequency band in every frame (`get_peaks`)
+    Bands,
+    /// Local maxima over a time-frequency neighbourhood above an adaptive noise floor, limited
+    /// to a target number of peaks per second (`ConstellationPeakPicker`)
+    Constellation,
+}
 
-    if sample.len() == 0 {
-        return Ok(spectrogram);
-    }
+/// The floating point type FFTs are computed in. Single precision is roughly twice as fast and
+/// its rounding error is far below anything `get_peaks` can distinguish, but it can still tip a
+/// peak into the neighbouring bin, so songs and snippets must use the same precision to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FftPrecision {
+    Single,
+    Double,
+}
 
-    let filtered_sample = lowpass_filter(sample, MAX_FREQUENCY, sample_rate);
+/// Short-time Fourier transform planned once for a window and hop size and shared by every
+/// spectrogram (and every thread) using those sizes. Only the `window_size / 2` bins below the Nyquist
+/// frequency are produced, since the input is real.
+pub struct SpectrogramEngine {
+    window_size: usize,
+    hop_size: usize,
+    transform: Transform,
+}
 
-    // TODO: songs in the database have a sample rate of 44.1 kHz; consider checking if the
-    // sample rate of `sample` is 48 kHz and rejecting if it is. Calling downsample() twice did
-    // not work.
-    // TODO: maybe record 48 kHz versions of all the songs?
-    let downsampled: Vec<f64> = downsample(filtered_sample, sample_rate, sample_rate / DSP_RATIO)?;
+// Engines by (window size, hop size, precision)
+type EngineCache = HashMap<(usize, usize, FftPrecision), Arc<SpectrogramEngine>>;
 
-    let hanning_window: Vec<f64> = (0..WINDOW_SIZE)
-        .map(|idx| {
-            let theta = 2.0 * PI * idx as f64 / (WINDOW_SIZE - 1) as f64;
+enum Transform {
+    Single(RealStft<f32>),
+    Double(RealStft<f64>),
+}
 
-            0.5 - 0.5 * f64::cos(theta)
-        })
-        .collect();
+struct RealStft<T: FftNum> {
+    fft: Arc<dyn RealToComplex<T>>,
+    hanning_window: Vec<T>,
+}
 
-    for start in (0..downsampled.len())
-        .take_while(|idx| (idx + WINDOW_SIZE) < downsampled.len())
-        .step_by(SCROLL_SIZE)
-    {
-        let end = start + WINDOW_SIZE;
+impl SpectrogramEngine {
+    pub fn new(window_size: usize, hop_size: usize, precision: FftPrecision) -> SpectrogramEngine {
+        let transform = match precision {
+            FftPrecision::Single => Transform::Single(RealStft::new(window_size)),
+            FftPrecision::Double => Transform::Double(RealStft::new(window_size)),
+        };
+
+        SpectrogramEngine {
+            window_size,
+            hop_size,
+            transform,
+        }
+    }
 
-        // Apply the Hanning window to a section of the downsampled data
-        let mut frame: Vec<Complex<f64>> = downsampled[start..end]
-            .into_iter()
-            .enumerate()
-            .map(|(idx, value)| Complex {
-                re: value * hanning_window[idx],
-                im: 0.0,
-            })
-            .collect();
+    /// The engine for a window and hop size and a precision, planned the first time they are
+    /// used together.
+    pub fn shared(
+        window_size: usize,
+        hop_size: usize,
+        precision: FftPrecision,
+    ) -> Arc<SpectrogramEngine> {
+        static ENGINES: OnceLock<Mutex<EngineCache>> = OnceLock::new();
+
+        let mut engines = match ENGINES.get_or_init(Default::default).lock() {
+            Ok(engines) => engines,
+            // A panic while planning cannot leave the map half-updated
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        Arc::clone(
+            engines
+                .entry((window_size, hop_size, precision))
+                .or_insert_with(|| {
+                    Arc::new(SpectrogramEngine::new(window_size, hop_size, precision))
+                }),
+        )
+    }
 
-        let fft = FftPlanner::new().plan_fft_forward(frame.len());
+    /// Magnitudes of each Hanning-windowed frame of `samples`, one frame every `hop_size`
+    /// samples. A trailing partial frame is discarded. Frames for which `skip` (given the
+    /// frame's index in `samples`) is true are not transformed, and are all zero.
+    pub fn process(
+        &self,
+        samples: &[f64],
+        skip: impl Fn(usize) -> bool,
+    ) -> Result<Vec<Vec<f64>>, u8> {
+        match &self.transform {
+            Transform::Single(stft) => {
+                stft.magnitudes(samples, self.window_size, self.hop_size, skip)
+            }
+            Transform::Double(stft) => {
+                stft.magnitudes(samples, self.window_size, self.hop_size, skip)
+            }
+        }
+    }
+}
 
-        fft.process(&mut frame);
+impl<T: FftNum + Float> RealStft<T> {
+    fn new(window_size: usize) -> RealStft<T> {
+        let fft = RealFftPlanner::<T>::new().plan_fft_forward(window_size);
 
-        let magnitude: Vec<f64> = frame.into_iter().map(|val| val.norm() as f64).collect();
+        let hanning_window: Vec<T> = (0..window_size)
+            .map(|idx| {
+                let theta = 2.0 * PI * idx as f64 / (window_size - 1) as f64;
 
-        spectrogram.push(magnitude);
-    }
+                T::from_f64(0.5 - 0.5 * f64::cos(theta)).unwrap_or_else(T::zero)
+            })
+            .collect();
 
-    Ok(spectrogram)
-}
+        RealStft {
+            fft,
+            hanning_window,
+        }
+    }
 
-// Remove frequency values below a certain threshold.
-fn lowpass_filter(input: Vec<f64>, cutoff_frequency: f64, sample_rate: u32) -> Vec<f64> {
-    let time_constant = 1.0 / (2.0 * PI * cutoff_frequency);
-    let dt = 1.0 / sample_rate as f64;
-    let alpha = dt / (time_constant + dt);
+    fn magnitudes(
+        &self,
+        samples: &[f64],
+        window_size: usize,
+        hop_size: usize,
+        skip: impl Fn(usize) -> bool,
+    ) -> Result<Vec<Vec<f64>>, u8> {
+        let mut spectrogram: Vec<Vec<f64>> = Vec::new();
+
+        // One set of buffers is reused for every frame of this spectrogram.
+        let mut frame: Vec<T> = self.fft.make_input_vec();
+        let mut spectrum: Vec<Complex<T>> = self.fft.make_output_vec();
+        let mut scratch: Vec<Complex<T>> = self.fft.make_scratch_vec();
+
+        for (frame_idx, start) in (0..samples.len())
+            .take_while(|idx| (idx + window_size) < samples.len())
+            .step_by(hop_size)
+            .enumerate()
+        {
+            if skip(frame_idx) {
+                spectrogram.push(vec![0.0; window_size / 2]);
+                continue;
+            }
 
-    let mut previous_output: f64 = 0.0;
+            let end = start + window_size;
 
-    input
-        .into_iter()
-        .enumerate()
-        .map(|(index, value)| {
-            let new_value: f64;
+            // Apply the Hanning window to a section of the downsampled data
+            for ((windowed, value), weight) in frame
+                .iter_mut()
+                .zip(&samples[start..end])
+                .zip(&self.hanning_window)
+            {
+                *windowed = T::from_f64(*value).unwrap_or_else(T::zero) * *weight;
+            }
 
-            if index == 0 {
-                new_value = value * alpha;
-            } else {
-                new_value = value * alpha + (1.0 - alpha) * previous_output;
+            if self
+                .fft
+                .process_with_scratch(&mut frame, &mut spectrum, &mut scratch)
+                .is_err()
+            {
+                eprintln!("FFT buffers did not match the planned window size");
+                return Err(SPECTROGRAM_GENERATION_FAILURE);
             }
 
-            previous_output = new_value;
-            new_value
-        })
-        .collect::<Vec<f64>>()
+            let magnitude: Vec<f64> = spectrum[..window_size / 2]
+                .iter()
+                .map(|val| val.norm().to_f64().unwrap_or(0.0))
+                .collect();
+
+            spectrogram.push(magnitude);
+        }
+
+        Ok(spectrogram)
+    }
+}
+
+/// Turns a channel's samples into spectrogram frames as they arrive: preprocessing, anti-aliasing
+/// and decimation, and the STFT all keep their state between calls to `push`, so a
+/// recording can be processed in chunks with memory bounded by the chunk size rather than the
+/// recording length. Frames starting in a skipped range are not transformed, and are all zero.
+pub struct SpectrogramStream {
+    preprocessor: SamplePreprocessor,
+    noise_subtraction: Option<NoiseFloorSubtraction>,
+    decimator: Decimator,
+    downsampled: Vec<f64>, // samples not yet covered by a complete frame
+    engine: Arc<SpectrogramEngine>,
+    skipped_ranges: Vec<TimeRange>,
+    frame_duration: f64,
+    frame_idx: usize, // index of the next frame
 }
 
-// Reduce the number of samples in an audio input, compressing data to improve performance.
-fn downsample(input: Vec<f64>, sample_rate: u32, target_sample_rate: u32) -> Result<Vec<f64>, u8> {
-    if target_sample_rate > sample_rate {
-        eprintln!("Target sample rate must be less than or equal to original sample rate");
-        return Err(SPECTROGRAM_GENERATION_FAILURE);
+impl SpectrogramStream {
+    pub fn new(
+        sample_rate: u32,
+        config: &FingerprintConfig,
+        preprocessing: &Preprocessing,
+        skipped_ranges: &[TimeRange],
+    ) -> Result<SpectrogramStream, u8> {
+        // TODO: songs in the database have a sample rate of 44.1 kHz, and at 48 kHz each FFT bin
+        // covers a different band of frequencies, so a 48 kHz snippet's hashes do not match
+        // theirs. Consider rejecting 48 kHz audio, or resampling it to 44.1 kHz before the
+        // decimator (which only divides the sample rate by a whole number).
+        // TODO: maybe record 48 kHz versions of all the songs?
+        Ok(SpectrogramStream {
+            preprocessor: SamplePreprocessor::new(sample_rate, preprocessing),
+            noise_subtraction: preprocessing
+                .noise_subtraction
+                .then(NoiseFloorSubtraction::default),
+            decimator: Decimator::new(sample_rate, config)?,
+            downsampled: Vec::new(),
+            engine: SpectrogramEngine::shared(
+                config.window_size,
+                config.hop_size,
+                config.fft_precision,
+            ),
+            skipped_ranges: skipped_ranges.to_vec(),
+            frame_duration: config.frame_duration(sample_rate),
+            frame_idx: 0,
+        })
     }
 
-    // Check that integer division did not result in 0
-    let sample_ratio = sample_rate / target_sample_rate;
-    if sample_ratio <= 0 {
-        eprintln!("Invalid ratio calculated from sample rates");
-        return Err(SPECTROGRAM_GENERATION_FAILURE);
+    /// Feed the next samples of the channel, returning the spectrogram frames they completed.
+    pub fn push(&mut self, samples: &[f64]) -> Result<Vec<Vec<f64>>, u8> {
+        let samples: Vec<f64> = self.preprocessor.push(samples);
+        let decimated: Vec<f64> = self.decimator.push(&samples);
+        self.downsampled.extend(decimated);
+
+        self.process_complete_frames()
     }
 
-    let mut resampled: Vec<f64> = Vec::<f64>::new();
-    let input_length = input.len();
+    /// Signal the end of the channel, returning any frames completed by the final samples.
+    pub fn finish(&mut self) -> Result<Vec<Vec<f64>>, u8> {
+        let samples: Vec<f64> = self.preprocessor.finish();
+        let decimated: Vec<f64> = self.decimator.push(&samples);
+        self.downsampled.extend(decimated);
+        self.downsampled.extend(self.decimator.finish());
 
-    for i in (0..input_length).step_by(sample_ratio as usize) {
-        let mut end: usize = i + sample_ratio as usize;
+        self.process_complete_frames()
+    }
 
-        if end > input_length {
-            end = input_length
+    fn process_complete_frames(&mut self) -> Result<Vec<Vec<f64>>, u8> {
+        let is_skipped = |frame_idx: usize| {
+            let time_sec = frame_idx as f64 * self.frame_duration;
+            self.skipped_ranges
+                .iter()
+                .any(|range| range.contains(time_sec))
+        };
+
+        let first_frame_idx = self.frame_idx;
+        let mut frames: Vec<Vec<f64>> = self
+            .engine
+            .process(&self.downsampled, |idx| is_skipped(first_frame_idx + idx))?;
+
+        // Skipped frames would only drag the noise floor estimate down
+        if let Some(noise_subtraction) = &mut self.noise_subtraction {
+            frames
+                .iter_mut()
+                .enumerate()
+                .filter(|(idx, _)| !is_skipped(first_frame_idx + idx))
+                .for_each(|(_, frame)| noise_subtraction.apply(frame));
         }
+        self.frame_idx += frames.len();
 
-        let sum: f64 = input[i..end].iter().sum();
-        let average: f64 = sum / (end - i) as f64;
+        // Keep only the samples later frames still overlap
+        let consumed = (frames.len() * self.engine.hop_size).min(self.downsampled.len());
+        self.downsampled.drain(..consumed);
 
-        resampled.push(average);
+        Ok(frames)
     }
-
-    Ok(resampled)
 }
 
-/// Find the "characteristic" components of an audio-source spectrogram by finding the
-/// frequencies with the largest magnitude in the set of frequency ranges human ears perceive the
-/// best.
-pub fn get_peaks(spectrogram: Vec<Vec<f64>>, duration: f64, sample_rate: u32) -> Vec<Peak> {
+/// Find the "characteristic" components of one spectrogram frame by finding the frequencies with
+/// the largest magnitude in the set of frequency ranges human ears perceive the best.
+/// `frame_duration` is the time between the starts of consecutive frames, and the bands are the
+/// profile's `frequency_bands`.
+pub fn get_peaks(
+    frame: &[f64],
+    frame_idx: usize,
+    frame_duration: f64,
+    sample_rate: u32,
+    config: &FingerprintConfig,
+) -> Vec<Peak> {
     let mut peaks = Vec::<Peak>::new();
 
-    if spectrogram.len() < 1 {
-        return peaks;
-    }
-
     struct MaxMagnitude {
         magnitude: f64,
         frequency_idx: usize,
     }
 
-    struct FrequencyBand {
-        min_frequency: usize,
-        max_frequency: usize,
-    }
-
-    let frequency_bands = vec![
-        FrequencyBand {
-            min_frequency: 0,
-            max_frequency: 10,
-        },
-        FrequencyBand {
-            min_frequency: 10,
-            max_frequency: 20,
-        },
-        FrequencyBand {
-            min_frequency: 20,
-            max_frequency: 40,
-        },
-        FrequencyBand {
-            min_frequency: 40,
-            max_frequency: 80,
-        },
-        FrequencyBand {
-            min_frequency: 80,
-            max_frequency: 160,
-        },
-        FrequencyBand {
-            min_frequency: 160,
-            max_frequency: 512,
-        },
-    ];
-
-    let frame_duration: f64 = duration / (spectrogram.len() as f64);
-
-    let effective_sample_rate = sample_rate as f64 / DSP_RATIO as f64;
-    let frequency_resolution = effective_sample_rate / WINDOW_SIZE as f64;
-
-    // Iterate over every frame in the spectrogram. For each frame, find the maximum magnitudes
-    // in each frequency band. Then, take the average of all those maximums to serve as a
-    // threshold for values to retain.
-    for (frame_idx, frame) in spectrogram.into_iter().enumerate() {
-        let mut max_magnitudes = Vec::<f64>::new();
-        // let mut frequency_indices = Vec::<usize>::new();
-
-        let max_magnitudes_in_frame: Vec<MaxMagnitude> = (&frequency_bands)
-            .into_iter()
-            .map(|band: &FrequencyBand| {
-                let mut max_magnitude: f64 = frame[band.min_frequency];
-                let mut max_magnitude_idx: usize = band.min_frequency;
-
-                for (idx, val) in (&frame[band.min_frequency..band.max_frequency])
-                    .into_iter()
-                    .skip(1)
-                    .enumerate()
-                {
-                    if *val > max_magnitude {
-                        max_magnitude = *val;
-                        max_magnitude_idx = band.min_frequency + idx;
-                    }
+    let frequency_resolution = config.frequency_resolution(sample_rate);
+
+    // For the frame, find the maximum magnitudes in each frequency band. Then, take the average
+    // of all those maximums to serve as a threshold for values to retain.
+    let mut max_magnitudes = Vec::<f64>::new();
+
+    let max_magnitudes_in_frame: Vec<MaxMagnitude> = config
+        .frequency_bands
+        .iter()
+        .map(|&[min_frequency, max_frequency]| {
+            let mut max_magnitude: f64 = frame[min_frequency];
+            let mut max_magnitude_idx: usize = min_frequency;
+
+            for (idx, val) in frame[min_frequency..max_frequency]
+                .iter()
+                .skip(1)
+                .enumerate()
+            {
+                if *val > max_magnitude {
+                    max_magnitude = *val;
+                    max_magnitude_idx = min_frequency + 1 + idx;
                 }
+            }
 
-                max_magnitudes.push(max_magnitude);
+            max_magnitudes.push(max_magnitude);
 
-                MaxMagnitude {
-                    magnitude: max_magnitude,
-                    frequency_idx: max_magnitude_idx,
-                }
-            })
-            .collect();
+            MaxMagnitude {
+                magnitude: max_magnitude,
+                frequency_idx: max_magnitude_idx,
+            }
+        })
+        .collect();
 
-        let sum: f64 = (&max_magnitudes).iter().sum();
-        let average: f64 = sum / max_magnitudes.len() as f64;
+    let sum: f64 = max_magnitudes.iter().sum();
+    let average: f64 = sum / max_magnitudes.len() as f64;
 
-        // Only add peaks that exceed this average value
-        for max_mag_struct in max_magnitudes_in_frame {
-            let (magnitude, freq_idx) = (max_mag_struct.magnitude, max_mag_struct.frequency_idx);
+    // Only add peaks that exceed this average value
+    for max_mag_struct in max_magnitudes_in_frame {
+        let (magnitude, freq_idx) = (max_mag_struct.magnitude, max_mag_struct.frequency_idx);
 
-            if magnitude > average {
-                let peak_time = frame_duration * frame_idx as f64;
-                let peak_frequency = frequency_resolution * freq_idx as f64;
+        if magnitude > average {
+            let peak_time = frame_duration * frame_idx as f64;
+            let peak_frequency = frequency_resolution * freq_idx as f64;
 
-                peaks.push(Peak {
-                    frequency: peak_frequency,
-                    time_sec: peak_time,
-                });
-            }
+            peaks.push(Peak {
+                frequency: peak_frequency,
+                time_sec: peak_time,
+                frequency_idx: freq_idx,
+                frame_idx,
+            });
         }
     }
 
     peaks
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::test_audio::{self, SAMPLE_RATE};
+
+    // Bins of the default profile's bands, none of them at a band's edge, where the tone's leakage
+    // into the bin below would make a second peak in the band below
+    const TONE_BINS: [usize; 6] = [5, 15, 30, 41, 100, 300];
+
+    #[test]
+    fn band_peak_of_a_pure_tone_is_its_bin() {
+        let config = FingerprintConfig::default();
+        let frequency_resolution = config.frequency_resolution(SAMPLE_RATE);
+
+        for tone_bin in TONE_BINS {
+            let frequency = frequency_resolution * tone_bin as f64;
+            let tone: Vec<f64> = (0..2 * SAMPLE_RATE)
+                .map(|idx| 8000.0 * (2.0 * PI * frequency * idx as f64 / SAMPLE_RATE as f64).sin())
+                .collect();
+
+            let peaks: Vec<Peak> =
+                test_audio::sample_peaks(&tone, &config, &Preprocessing::default()).remove(0);
+            let last_frame_idx = peaks.iter().map(|peak| peak.frame_idx).max().unwrap_or(0);
+
+            // The first and last frames overlap the start and end of the tone
+            let bins: Vec<usize> = peaks
+                .iter()
+                .filter(|peak| (2..last_frame_idx - 1).contains(&peak.frame_idx))
+                .map(|peak| peak.frequency_idx)
+                .collect();
+
+            // One peak in each of those frames, on the tone's bin
+            assert_eq!(
+                bins,
+                vec![tone_bin; last_frame_idx - 3],
+                "peaks of a tone at bin {}",
+                tone_bin
+            );
+        }
+    }
+}
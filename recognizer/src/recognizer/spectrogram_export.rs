@@ -0,0 +1,327 @@
+// file: src/recognizer/spectrogram_export.rs
+// purpose: shows what the fingerprinter sees in a recording: its spectrogram as a PNG heatmap
+// with the peaks (and optionally the anchor-target pairs hashed from them) drawn over it, and
+// the raw magnitudes and peaks as CSV or NPY for analysis elsewhere.
+
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::{FILE_NOT_FOUND, SPECTROGRAM_GENERATION_FAILURE};
+use crate::recognizer::fingerprint::{channel_spectrogram, peak_pairs};
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::spectrogram::Peak;
+use crate::recognizer::wav::WavStream;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Magnitudes this far below the loudest are drawn as black
+const DYNAMIC_RANGE_DB: f64 = 80.0;
+
+// The heatmap's colours from quietest to loudest, interpolated between
+const COLOUR_STOPS: [[f64; 3]; 5] = [
+    [0., 0., 4.],
+    [87., 16., 110.],
+    [188., 55., 84.],
+    [249., 142., 9.],
+    [252., 255., 164.],
+];
+
+const PEAK_COLOUR: [u8; 3] = [0, 255, 255];
+const PAIR_COLOUR: [u8; 3] = [80, 255, 80];
+const PAIR_OPACITY: f64 = 0.35;
+
+/// How the magnitudes and peaks are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DataFormat {
+    /// Comma-separated values with a header row
+    Csv,
+    /// A NumPy array of 64-bit floats, for `numpy.load`
+    Npy,
+}
+
+/// One channel's spectrogram as the fingerprinter sees it, with its peaks and the pairs hashed
+/// from them.
+pub struct SpectrogramExport {
+    frames: Vec<Vec<f64>>, // magnitudes by frame, then FFT bin
+    peaks: Vec<Peak>,
+    pairs: Vec<(Peak, Peak)>,
+    frame_duration: f64,       // seconds between the starts of consecutive frames
+    frequency_resolution: f64, // Hz per FFT bin
+}
+
+impl SpectrogramExport {
+    /// The spectrogram of `channel` (0 is the left or only channel) of the recording at
+    /// `file_path`, preprocessed as a snippet being identified would be.
+    pub fn of_recording(
+        file_path: &str,
+        config: &FingerprintConfig,
+        preprocessing: &Preprocessing,
+        channel: usize,
+    ) -> Result<SpectrogramExport, u8> {
+        let mut wav_stream = WavStream::open(file_path)?;
+        let sample_rate: u32 = wav_stream.info.spec.sample_rate;
+
+        let (frames, peaks) = channel_spectrogram(&mut wav_stream, config, preprocessing, channel)?;
+
+        if frames.is_empty() {
+            eprintln!(
+                "`{}` is too short for a single spectrogram frame!",
+                file_path
+            );
+            return Err(SPECTROGRAM_GENERATION_FAILURE);
+        }
+
+        Ok(SpectrogramExport {
+            pairs: peak_pairs(&peaks, config),
+            frames,
+            peaks,
+            frame_duration: config.frame_duration(sample_rate),
+            frequency_resolution: config.frequency_resolution(sample_rate),
+        })
+    }
+
+    pub fn print_summary(&self) {
+        let duration_sec: f64 = self.frames.len() as f64 * self.frame_duration;
+
+        println!(
+            "Frames:  {} of {} bins, every {:.1} ms and {:.1} Hz",
+            self.frames.len(),
+            self.frames[0].len(),
+            self.frame_duration * 1000.,
+            self.frequency_resolution
+        );
+        println!(
+            "Peaks:   {} ({:.1} per second)",
+            self.peaks.len(),
+            self.peaks.len() as f64 / duration_sec
+        );
+        println!(
+            "Pairs:   {} ({:.1} per peak)",
+            self.pairs.len(),
+            self.pairs.len() as f64 / self.peaks.len().max(1) as f64
+        );
+    }
+
+    /// Draw the spectrogram as a heatmap, one pixel per frame across and per FFT bin up, with
+    /// each peak marked by a cross and, with `draw_pairs`, a line from each anchor to each of its
+    /// targets.
+    pub fn write_png(&self, file_path: &str, draw_pairs: bool) -> Result<(), u8> {
+        let (width, height) = (self.frames.len(), self.frames[0].len());
+        let mut image = Image {
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        };
+
+        let max_magnitude: f64 = self
+            .frames
+            .iter()
+            .flatten()
+            .copied()
+            .fold(f64::MIN_POSITIVE, f64::max);
+
+        for (x, frame) in self.frames.iter().enumerate() {
+            for (bin, magnitude) in frame.iter().enumerate() {
+                let level_db = 20. * (magnitude.max(f64::MIN_POSITIVE) / max_magnitude).log10();
+                let level = (1. + level_db / DYNAMIC_RANGE_DB).clamp(0., 1.);
+
+                image.set(x as i64, (height - 1 - bin) as i64, heat_colour(level), 1.);
+            }
+        }
+
+        let point = |peak: &Peak| {
+            (
+                peak.frame_idx as i64,
+                (height - 1) as i64 - peak.frequency_idx as i64,
+            )
+        };
+
+        if draw_pairs {
+            for (anchor, target) in &self.pairs {
+                image.line(point(anchor), point(target), PAIR_COLOUR, PAIR_OPACITY);
+            }
+        }
+
+        for peak in &self.peaks {
+            let (x, y) = point(peak);
+            for (dx, dy) in [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1)] {
+                image.set(x + dx, y + dy, PEAK_COLOUR, 1.);
+            }
+        }
+
+        let Ok(file) = File::create(file_path) else {
+            eprintln!("ERROR: Cannot write `{}`", file_path);
+            return Err(FILE_NOT_FOUND);
+        };
+        let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        match encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&image.pixels))
+        {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                eprintln!("ERROR: Cannot write `{}`", file_path);
+                Err(FILE_NOT_FOUND)
+            }
+        }
+    }
+
+    /// Write the magnitudes: as CSV, a row per frame holding its time and then its magnitude in
+    /// each bin, under a header of the bins' frequencies; as NPY, a frames by bins array.
+    pub fn write_magnitudes(&self, file_path: &str, format: DataFormat) -> Result<(), u8> {
+        let shape = [self.frames.len(), self.frames[0].len()];
+
+        write_file(file_path, |writer| match format {
+            DataFormat::Csv => {
+                write!(writer, "time_sec")?;
+                for bin in 0..shape[1] {
+                    write!(writer, ",{:.2}", bin as f64 * self.frequency_resolution)?;
+                }
+                writeln!(writer)?;
+
+                for (frame_idx, frame) in self.frames.iter().enumerate() {
+                    write!(writer, "{:.4}", frame_idx as f64 * self.frame_duration)?;
+                    for magnitude in frame {
+                        write!(writer, ",{}", magnitude)?;
+                    }
+                    writeln!(writer)?;
+                }
+
+                Ok(())
+            }
+            DataFormat::Npy => write_npy(writer, shape, self.frames.iter().flatten().copied()),
+        })
+    }
+
+    /// Write the peaks, a row each holding its time (s), frequency (Hz), frame and FFT bin.
+    pub fn write_peaks(&self, file_path: &str, format: DataFormat) -> Result<(), u8> {
+        let rows = self.peaks.iter().map(|peak| {
+            [
+                peak.time_sec,
+                peak.frequency,
+                peak.frame_idx as f64,
+                peak.frequency_idx as f64,
+            ]
+        });
+
+        write_file(file_path, |writer| match format {
+            DataFormat::Csv => {
+                writeln!(writer, "time_sec,frequency_hz,frame,bin")?;
+                for [time_sec, frequency, frame_idx, bin] in rows {
+                    writeln!(
+                        writer,
+                        "{:.4},{:.2},{},{}",
+                        time_sec, frequency, frame_idx, bin
+                    )?;
+                }
+
+                Ok(())
+            }
+            DataFormat::Npy => write_npy(writer, [self.peaks.len(), 4], rows.flatten()),
+        })
+    }
+}
+
+// An RGB image, row by row from the top.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    // Blend `colour` over the pixel at (x, y) with the given opacity, if it is in the image.
+    fn set(&mut self, x: i64, y: i64, colour: [u8; 3], opacity: f64) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+
+        let start = (y as usize * self.width + x as usize) * 3;
+        for (pixel, channel) in self.pixels[start..start + 3].iter_mut().zip(colour) {
+            *pixel = (*pixel as f64 * (1. - opacity) + channel as f64 * opacity).round() as u8;
+        }
+    }
+
+    // A line from `from` to `to` with Bresenham's algorithm.
+    fn line(&mut self, from: (i64, i64), to: (i64, i64), colour: [u8; 3], opacity: f64) {
+        let (mut x, mut y) = from;
+        let (dx, dy) = ((to.0 - x).abs(), -(to.1 - y).abs());
+        let (step_x, step_y) = ((to.0 - x).signum(), (to.1 - y).signum());
+        let mut error = dx + dy;
+
+        loop {
+            self.set(x, y, colour, opacity);
+            if (x, y) == to {
+                break;
+            }
+
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+}
+
+// The colour of a level from 0 (quietest) to 1 (loudest).
+fn heat_colour(level: f64) -> [u8; 3] {
+    let position = level * (COLOUR_STOPS.len() - 1) as f64;
+    let idx = (position.floor() as usize).min(COLOUR_STOPS.len() - 2);
+    let fraction = position - idx as f64;
+
+    let (low, high) = (COLOUR_STOPS[idx], COLOUR_STOPS[idx + 1]);
+    [0, 1, 2]
+        .map(|channel| (low[channel] + (high[channel] - low[channel]) * fraction).round() as u8)
+}
+
+// A 2-D NumPy array (format version 1.0) of little-endian 64-bit floats in row-major order.
+fn write_npy(
+    writer: &mut impl Write,
+    shape: [usize; 2],
+    values: impl Iterator<Item = f64>,
+) -> io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+        shape[0], shape[1]
+    );
+
+    // The magic string, version and header length take 10 bytes, and the header is padded with
+    // spaces and ended by a newline so the data starts at a multiple of 64 bytes
+    let padding = 63 - (10 + header.len()) % 64;
+    header += &" ".repeat(padding);
+    header += "\n";
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Create the file and write it with `write`, buffered.
+fn write_file(
+    file_path: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> Result<(), u8> {
+    let written = File::create(file_path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.flush()
+    });
+
+    if written.is_err() {
+        eprintln!("ERROR: Cannot write `{}`", file_path);
+        return Err(FILE_NOT_FOUND);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,309 @@
+// file: src/recognizer/split.rs
+// purpose: cuts a recording into one file per track found by `segment_recording`, named and
+// tagged from the Songs table. A segment only spans the hashes that matched its song, so its
+// boundaries are widened to where the song starts and ends by its offset and length, then moved
+// to the edges of any silence between tracks.
+
+use crate::db::db_utils;
+use crate::recognizer::declarations::{FILE_NOT_FOUND, INCOMPATIBLE_FILE_ERROR};
+use crate::recognizer::flac;
+use crate::recognizer::loudness::TimeRange;
+use crate::recognizer::segmentation::Segment;
+use crate::recognizer::tracklist::minutes_and_seconds;
+use crate::recognizer::wav::WavStream;
+use std::path::{Path, PathBuf};
+
+// A track boundary moves to the edge of a silence at most this far from it
+const SILENCE_SNAP_SEC: f64 = 2.0;
+
+// Quiet intros and fade-outs match few hashes, so a track may extend this far beyond its segment
+// towards the song's start and end; audio further out that matched nothing is not its song, even
+// if the song's length says it should be
+const MAX_EXTENSION_SEC: f64 = 5.0;
+
+/// The format each track is written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SplitFormat {
+    /// Uncompressed .wav, tagged with a RIFF INFO chunk
+    Wav,
+    /// Lossless .flac, tagged with Vorbis comments
+    Flac,
+}
+
+impl SplitFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            SplitFormat::Wav => "wav",
+            SplitFormat::Flac => "flac",
+        }
+    }
+}
+
+/// Write each of the `segments` found in the recording at `file_path` to its own file in
+/// `output_dir`, named `NN - Artist - Title`, returning the files written.
+pub fn split_recording(
+    file_path: &str,
+    segments: &[Segment],
+    output_dir: &Path,
+    format: SplitFormat,
+) -> Result<Vec<PathBuf>, u8> {
+    let mut wav_stream = WavStream::open(file_path)?;
+    let silent_ranges: Vec<TimeRange> = wav_stream.analyse_level()?.silent_ranges;
+    let duration_sec: f64 = wav_stream.info.duration_sec;
+
+    let song_durations_sec: Vec<f64> = segments
+        .iter()
+        .map(|segment| Ok(db_utils::song_duration_ms(segment.song_id)? as f64 / 1000.))
+        .collect::<Result<_, u8>>()?;
+    let boundaries: Vec<(f64, f64)> =
+        track_boundaries(segments, &song_durations_sec, &silent_ranges, duration_sec);
+
+    if std::fs::create_dir_all(output_dir).is_err() {
+        eprintln!("ERROR: Cannot create `{}`", output_dir.display());
+        return Err(FILE_NOT_FOUND);
+    }
+
+    let channels: u16 = wav_stream.info.spec.channels;
+    let sample_rate: u32 = wav_stream.info.spec.sample_rate;
+    let mut track_files = Vec::<PathBuf>::new();
+
+    for (idx, (segment, (start_sec, end_sec))) in segments.iter().zip(boundaries).enumerate() {
+        let track_number: String = (idx + 1).to_string();
+        let start_frame = (start_sec * sample_rate as f64).round() as u32;
+        let end_frame = (end_sec * sample_rate as f64).round() as u32;
+
+        let Some(samples) =
+            wav_stream.read_frames(start_frame, end_frame.saturating_sub(start_frame))
+        else {
+            eprintln!(
+                "ERROR: Cannot read `{}` from {}",
+                file_path,
+                minutes_and_seconds(start_sec)
+            );
+            return Err(INCOMPATIBLE_FILE_ERROR);
+        };
+
+        let tags: [(&str, &str); 4] = [
+            ("TITLE", &segment.title),
+            ("ARTIST", &segment.artist),
+            ("ALBUM", &segment.album),
+            ("TRACKNUMBER", &track_number),
+        ];
+        let track_file: Vec<u8> = match format {
+            SplitFormat::Wav => match wav_file(&samples, channels, sample_rate, &tags) {
+                Some(track_file) => track_file,
+                None => {
+                    eprintln!(
+                        "ERROR: Track {} of `{}` is too large for a .wav file",
+                        track_number, file_path
+                    );
+                    return Err(INCOMPATIBLE_FILE_ERROR);
+                }
+            },
+            SplitFormat::Flac => flac::encode_flac(&samples, channels, sample_rate, &tags)?,
+        };
+
+        let track_path: PathBuf = output_dir.join(format!(
+            "{:02} - {} - {}.{}",
+            idx + 1,
+            file_name_text(&segment.artist),
+            file_name_text(&segment.title),
+            format.extension()
+        ));
+
+        if std::fs::write(&track_path, track_file).is_err() {
+            eprintln!("ERROR: Cannot write `{}`", track_path.display());
+            return Err(FILE_NOT_FOUND);
+        }
+
+        println!(
+            "{} - {}  `{}`",
+            minutes_and_seconds(start_sec),
+            minutes_and_seconds(end_sec),
+            track_path.display()
+        );
+        track_files.push(track_path);
+    }
+
+    Ok(track_files)
+}
+
+// Where each segment's track starts and ends in the recording. The song started
+// `reference_offset_sec` before the segment and runs for its length, unless it was cut short:
+// a track never extends into the part of the recording matched to its neighbours, nor more than
+// `MAX_EXTENSION_SEC` beyond its own, nor shrinks inside it. Each boundary then moves to the
+// edge of any silence next to it, so tracks start where their sound does and the gaps between
+// them are left out.
+fn track_boundaries(
+    segments: &[Segment],
+    song_durations_sec: &[f64],
+    silent_ranges: &[TimeRange],
+    duration_sec: f64,
+) -> Vec<(f64, f64)> {
+    let mut boundaries: Vec<(f64, f64)> = segments
+        .iter()
+        .zip(song_durations_sec)
+        .enumerate()
+        .map(|(idx, (segment, song_duration_sec))| {
+            let song_start_sec: f64 = segment.start_sec - segment.reference_offset_sec;
+
+            let earliest_start: f64 = if idx == 0 {
+                0.0
+            } else {
+                segments[idx - 1].end_sec
+            }
+            .max(segment.start_sec - MAX_EXTENSION_SEC);
+            let latest_end: f64 = segments
+                .get(idx + 1)
+                .map_or(duration_sec, |next| next.start_sec)
+                .min(segment.end_sec + MAX_EXTENSION_SEC);
+
+            let start_sec = song_start_sec.max(earliest_start).min(segment.start_sec);
+            let end_sec = (song_start_sec + song_duration_sec)
+                .min(latest_end)
+                .max(segment.end_sec);
+
+            let start_sec = match silence_near(silent_ranges, start_sec) {
+                Some(silence) => silence.end_sec.max(earliest_start).min(segment.start_sec),
+                None => start_sec,
+            };
+            let end_sec = match silence_near(silent_ranges, end_sec) {
+                Some(silence) => silence.start_sec.min(latest_end).max(segment.end_sec),
+                None => end_sec,
+            };
+
+            (start_sec, end_sec)
+        })
+        .collect();
+
+    // Songs longer than the gap left for them make neighbouring tracks overlap; split the
+    // difference
+    for idx in 1..boundaries.len() {
+        if boundaries[idx].0 < boundaries[idx - 1].1 {
+            let boundary = (boundaries[idx].0 + boundaries[idx - 1].1) / 2.0;
+
+            boundaries[idx - 1].1 = boundary;
+            boundaries[idx].0 = boundary;
+        }
+    }
+
+    boundaries
+}
+
+// The silence closest to `time_sec`, if any is within `SILENCE_SNAP_SEC` of it.
+fn silence_near(silent_ranges: &[TimeRange], time_sec: f64) -> Option<&TimeRange> {
+    let distance = |silence: &TimeRange| {
+        (silence.start_sec - time_sec)
+            .max(time_sec - silence.end_sec)
+            .max(0.0)
+    };
+
+    silent_ranges
+        .iter()
+        .filter(|silence| distance(silence) <= SILENCE_SNAP_SEC)
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+}
+
+// The .wav file of the given interleaved 16-bit samples, with `tags` in a LIST INFO chunk, or
+// `None` if the format's 32-bit lengths and byte rate cannot hold it. `hound` cannot write
+// chunks of its own, so the file is laid out here.
+fn wav_file(
+    samples: &[i16],
+    channels: u16,
+    sample_rate: u32,
+    tags: &[(&str, &str)],
+) -> Option<Vec<u8>> {
+    let block_align: u16 = channels.checked_mul(2)?;
+    let byte_rate: u32 = sample_rate.checked_mul(block_align as u32)?;
+
+    let mut fmt_chunk = Vec::<u8>::new();
+    fmt_chunk.extend(1u16.to_le_bytes()); // PCM
+    fmt_chunk.extend(channels.to_le_bytes());
+    fmt_chunk.extend(sample_rate.to_le_bytes());
+    fmt_chunk.extend(byte_rate.to_le_bytes());
+    fmt_chunk.extend(block_align.to_le_bytes());
+    fmt_chunk.extend(16u16.to_le_bytes());
+
+    let mut info_chunk: Vec<u8> = b"INFO".to_vec();
+    for (field, value) in tags {
+        let mut text: Vec<u8> = value.as_bytes().to_vec();
+        text.push(0);
+        info_chunk.extend(riff_chunk(info_id(field), &text)?);
+    }
+
+    let data: Vec<u8> = samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect();
+
+    let mut body: Vec<u8> = b"WAVE".to_vec();
+    body.extend(riff_chunk(b"fmt ", &fmt_chunk)?);
+    body.extend(riff_chunk(b"LIST", &info_chunk)?);
+    body.extend(riff_chunk(b"data", &data)?);
+
+    riff_chunk(b"RIFF", &body)
+}
+
+// A RIFF chunk: its ID, its length and its contents, padded to an even length, or `None` if its
+// length does not fit the 32 bits it is stored in.
+fn riff_chunk(id: &[u8; 4], contents: &[u8]) -> Option<Vec<u8>> {
+    let length: u32 = u32::try_from(contents.len()).ok()?;
+
+    let mut chunk: Vec<u8> = id.to_vec();
+    chunk.extend(length.to_le_bytes());
+    chunk.extend(contents);
+    if contents.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    Some(chunk)
+}
+
+// The INFO chunk ID holding the Vorbis comment field of the same meaning.
+fn info_id(field: &str) -> &'static [u8; 4] {
+    match field {
+        "TITLE" => b"INAM",
+        "ARTIST" => b"IART",
+        "ALBUM" => b"IPRD",
+        "TRACKNUMBER" => b"ITRK",
+        _ => b"ICMT",
+    }
+}
+
+// Text with the characters file systems reject replaced.
+fn file_name_text(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wav_file_reads_back_its_samples() {
+        let samples: Vec<i16> = vec![0, -1, i16::MAX, i16::MIN, 1234, -4321];
+        let wav: Vec<u8> = wav_file(&samples, 2, 48000, &[("TITLE", "Odd")]).unwrap();
+
+        let reader = hound::WavReader::new(std::io::Cursor::new(wav)).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 48000);
+        let decoded: Vec<i16> = reader
+            .into_samples::<i16>()
+            .map(|sample| sample.unwrap())
+            .collect();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn wav_file_refuses_a_byte_rate_beyond_32_bits() {
+        assert!(wav_file(&[0; 4], 2, u32::MAX / 2, &[]).is_none());
+        assert!(wav_file(&[0; 4], u16::MAX, 44100, &[]).is_none());
+    }
+}
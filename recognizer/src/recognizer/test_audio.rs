@@ -0,0 +1,122 @@
+// file: src/recognizer/test_audio.rs
+// purpose: synthetic recordings for tests, so fingerprinting and matching can be checked without
+// audio files. Samples are on the scale of 16-bit audio, as `WavStream` decodes them.
+
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::fingerprint::{
+    AudioFingerprint, CHUNK_FRAMES, chunk_peaks, fingerprint_chunks,
+};
+use crate::recognizer::loudness::LoudnessMeter;
+use crate::recognizer::preprocess::{LevelAdjustment, Preprocessing};
+use crate::recognizer::spectrogram::Peak;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f64::consts::PI;
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Notes of a synthetic song start this many times a second on average, so several overlap
+const NOTES_PER_SECOND: f64 = 12.0;
+
+/// A mono "song" `duration_sec` long: notes of random pitch (60 Hz to 5 kHz), length and
+/// loudness, each with two harmonics and a quick attack and slow decay, over faint noise. The
+/// same `seed` always makes the same song.
+pub fn synthetic_song(duration_sec: f64, seed: u64) -> Vec<f64> {
+    synthetic_performance(duration_sec, seed, 1.0, 1.0)
+}
+
+/// The song `synthetic_song` makes from `seed`, played as a musician would play it `tempo`
+/// times as fast and with every note `pitch` times as high: unlike a recording played faster,
+/// changing one leaves the other as it was.
+pub fn synthetic_performance(duration_sec: f64, seed: u64, tempo: f64, pitch: f64) -> Vec<f64> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let len = (duration_sec * SAMPLE_RATE as f64) as usize;
+
+    let noise: Vec<f64> = (0..len).map(|_| rng.gen_range(-30.0, 30.0)).collect();
+    let mut samples: Vec<f64> = (0..(len as f64 / tempo) as usize)
+        .map(|idx| noise[((idx as f64 * tempo) as usize).min(len - 1)])
+        .collect();
+
+    let note_count = (duration_sec * NOTES_PER_SECOND) as usize;
+    for _ in 0..note_count {
+        let start = rng.gen_range(0, len);
+        let note_len = (rng.gen_range(0.1, 0.6) * SAMPLE_RATE as f64) as usize;
+        let frequency = 60.0 * (5000.0f64 / 60.0).powf(rng.gen_range(0.0, 1.0)) * pitch;
+        let amplitude = rng.gen_range(500.0, 4000.0);
+
+        for (idx, sample) in samples
+            .iter_mut()
+            .skip((start as f64 / tempo) as usize)
+            .take((note_len as f64 / tempo) as usize)
+            .enumerate()
+        {
+            let time_sec = idx as f64 / SAMPLE_RATE as f64;
+            // The note fades as it would in the song, only sooner or later
+            let song_time_sec = time_sec * tempo;
+            let envelope = (song_time_sec / 0.005).min(1.0) * (-4.0 * song_time_sec).exp();
+            let phase = 2.0 * PI * frequency * time_sec;
+
+            *sample += amplitude
+                * envelope
+                * (phase.sin() + 0.5 * (2.0 * phase).sin() + 0.25 * (3.0 * phase).sin());
+        }
+    }
+
+    samples
+        .into_iter()
+        .map(|value| value.round().clamp(i16::MIN as f64, i16::MAX as f64))
+        .collect()
+}
+
+/// Fingerprint mono `samples` at `SAMPLE_RATE` as `fingerprint_audio` would a file of them,
+/// attributing every hash to `song_id`.
+pub fn fingerprint_samples(
+    samples: &[f64],
+    song_id: u32,
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+) -> AudioFingerprint {
+    fingerprint_chunks(
+        chunks(samples),
+        SAMPLE_RATE,
+        song_id,
+        config,
+        preprocessing,
+        &level_adjustment(samples, preprocessing),
+    )
+    .expect("synthetic audio can always be fingerprinted")
+}
+
+/// The peaks of mono `samples` at `SAMPLE_RATE`, as `audio_peaks` would find them in a file of
+/// them, for `fingerprint_peaks` to fingerprint at any playback speed.
+pub fn sample_peaks(
+    samples: &[f64],
+    config: &FingerprintConfig,
+    preprocessing: &Preprocessing,
+) -> Vec<Vec<Peak>> {
+    chunk_peaks(
+        chunks(samples),
+        SAMPLE_RATE,
+        1,
+        config,
+        preprocessing,
+        &level_adjustment(samples, preprocessing),
+    )
+    .expect("synthetic audio can always be analysed")
+}
+
+fn chunks(samples: &[f64]) -> impl Iterator<Item = (Vec<f64>, Vec<f64>)> {
+    samples
+        .chunks(CHUNK_FRAMES)
+        .map(|chunk| (chunk.to_vec(), Vec::new()))
+}
+
+fn level_adjustment(samples: &[f64], preprocessing: &Preprocessing) -> LevelAdjustment {
+    if !preprocessing.needs_level() {
+        return LevelAdjustment::none();
+    }
+
+    let mut meter = LoudnessMeter::new(SAMPLE_RATE);
+    meter.push(samples, &[]);
+    LevelAdjustment::new(&meter.finish(), preprocessing)
+}
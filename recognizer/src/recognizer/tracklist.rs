@@ -0,0 +1,142 @@
+// file: src/recognizer/tracklist.rs
+// purpose: writes the segments `segment_recording` finds in a recording as a tracklist: a plain
+// text listing, a CUE sheet that splits the recording into its tracks, CSV or JSON.
+
+use crate::recognizer::segmentation::Segment;
+use serde::Serialize;
+use std::path::Path;
+
+/// How a tracklist is written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TracklistFormat {
+    /// One line per track, for reading
+    Text,
+    /// A CUE sheet indexing each track in the recording
+    Cue,
+    /// Comma-separated values with a header row
+    Csv,
+    /// A JSON object with the recording and a list of its tracks
+    Json,
+}
+
+// CUE sheets count time in frames of 1/75 s, as audio CDs do
+const CUE_FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Serialize)]
+struct JsonTracklist<'a> {
+    recording: &'a str,
+    tracks: &'a [Segment],
+}
+
+/// The tracklist of the recording at `recording_path` in the given format.
+pub fn format_tracklist(
+    segments: &[Segment],
+    recording_path: &str,
+    format: TracklistFormat,
+) -> String {
+    match format {
+        TracklistFormat::Text => text_tracklist(segments),
+        TracklistFormat::Cue => cue_sheet(segments, recording_path),
+        TracklistFormat::Csv => csv_tracklist(segments),
+        TracklistFormat::Json => {
+            let tracklist = JsonTracklist {
+                recording: recording_path,
+                tracks: segments,
+            };
+
+            // Segments hold only strings and finite numbers, which JSON can always represent
+            serde_json::to_string_pretty(&tracklist).unwrap_or_default() + "\n"
+        }
+    }
+}
+
+fn text_tracklist(segments: &[Segment]) -> String {
+    segments
+        .iter()
+        .enumerate()
+        .map(|(idx, segment)| {
+            format!(
+                "{:>2}. {} - {}  {} by {} ({}), from {} in the song, confidence {:.2}\n",
+                idx + 1,
+                minutes_and_seconds(segment.start_sec),
+                minutes_and_seconds(segment.end_sec),
+                segment.title,
+                segment.artist,
+                segment.album,
+                minutes_and_seconds(segment.reference_offset_sec),
+                segment.confidence
+            )
+        })
+        .collect()
+}
+
+// A CUE sheet for the recording, with each segment as a track starting at its start. The gaps
+// between segments belong to the track before them, as a CUE sheet has no room for untracked
+// audio.
+fn cue_sheet(segments: &[Segment], recording_path: &str) -> String {
+    let file_name: String = Path::new(recording_path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| recording_path.to_string());
+
+    let mut sheet = format!("FILE \"{}\" WAVE\n", cue_text(&file_name));
+
+    for (idx, segment) in segments.iter().enumerate() {
+        let frames = (segment.start_sec.max(0.0) * CUE_FRAMES_PER_SECOND).round() as u64;
+
+        sheet += &format!("  TRACK {:02} AUDIO\n", idx + 1);
+        sheet += &format!("    TITLE \"{}\"\n", cue_text(&segment.title));
+        sheet += &format!("    PERFORMER \"{}\"\n", cue_text(&segment.artist));
+        sheet += &format!(
+            "    INDEX 01 {:02}:{:02}:{:02}\n",
+            frames / (60 * CUE_FRAMES_PER_SECOND as u64),
+            frames / CUE_FRAMES_PER_SECOND as u64 % 60,
+            frames % CUE_FRAMES_PER_SECOND as u64
+        );
+    }
+
+    sheet
+}
+
+fn csv_tracklist(segments: &[Segment]) -> String {
+    let mut csv = String::from(
+        "track,start_sec,end_sec,title,artist,album,reference_offset_sec,confidence\n",
+    );
+
+    for (idx, segment) in segments.iter().enumerate() {
+        csv += &format!(
+            "{},{:.3},{:.3},{},{},{},{:.3},{:.3}\n",
+            idx + 1,
+            segment.start_sec,
+            segment.end_sec,
+            csv_field(&segment.title),
+            csv_field(&segment.artist),
+            csv_field(&segment.album),
+            segment.reference_offset_sec,
+            segment.confidence
+        );
+    }
+
+    csv
+}
+
+// A field quoted if it needs to be, with its quotes doubled.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// CUE sheets have no way of escaping a double quote inside a quoted string.
+fn cue_text(text: &str) -> String {
+    text.replace('"', "'")
+}
+
+// A time as minutes and seconds to a tenth of a second, e.g. `3:07.4`.
+pub(crate) fn minutes_and_seconds(seconds: f64) -> String {
+    let tenths = (seconds.max(0.0) * 10.0).round() as u64;
+
+    format!("{}:{:02}.{}", tenths / 600, tenths / 10 % 60, tenths % 10)
+}
@@ -0,0 +1,72 @@
+// file: src/recognizer/triplet.rs
+// purpose: a second fingerprint scheme, enabled by a profile's `triplet_hashes`, for DJ sets and
+// remixes whose pitch or tempo was changed. Each hash describes three peaks by the ratios of
+// their frequencies and of their time differences, which stay the same when every frequency or
+// every time difference is scaled by the same factor.
+
+use crate::recognizer::spectrogram::Peak;
+
+/// Where a triplet hash occurred: the anchor's time and the time from the anchor to the last
+/// peak of the triplet. Comparing a snippet's span with a song's gives the snippet's tempo.
+#[derive(Clone, Copy)]
+pub struct TripletPoint {
+    pub anchor_time_ms: i32,
+    pub span_ms: i32,
+    pub song_id: i32,
+}
+
+// Frequency ratios are quantised to quarter tones, and may be up to this many quarter tones
+// (about 5 octaves) above or below the anchor
+const RATIO_STEPS_PER_OCTAVE: f64 = 24.0;
+const FREQUENCY_RATIO_BITS: u32 = 8;
+
+// The first time difference as a fraction of the second, in steps of 1/63
+const TIME_RATIO_BITS: u32 = 6;
+
+// Shorter spans hold too few frames for their time ratio (or the tempo a match implies) to mean
+// much.
+const MIN_SPAN_FRAMES: usize = 3;
+
+/// Hash an anchor and two later peaks of its target zone, `first` no later than `second`.
+/// From the most significant bit down, the hash holds the pitch interval from the anchor to
+/// `first` and to `second` (`FREQUENCY_RATIO_BITS` each, in quarter tones) and the time from
+/// the anchor to `first` as a fraction of the time to `second` (`TIME_RATIO_BITS`). Returns
+/// `None` for triplets that are too short or span too wide an interval.
+pub fn triplet_hash(anchor: &Peak, first: &Peak, second: &Peak) -> Option<u32> {
+    let first_delay = first.frame_idx.checked_sub(anchor.frame_idx)?;
+    let second_delay = second.frame_idx.checked_sub(anchor.frame_idx)?;
+
+    if second_delay < MIN_SPAN_FRAMES || first_delay > second_delay {
+        return None;
+    }
+
+    let first_interval = pitch_interval(anchor, first)?;
+    let second_interval = pitch_interval(anchor, second)?;
+
+    let time_steps = ((1 << TIME_RATIO_BITS) - 1) as f64;
+    let time_ratio = (first_delay as f64 / second_delay as f64 * time_steps).round() as u32;
+
+    Some(
+        (first_interval << (FREQUENCY_RATIO_BITS + TIME_RATIO_BITS))
+            | (second_interval << TIME_RATIO_BITS)
+            | time_ratio,
+    )
+}
+
+// The interval from the anchor's frequency to the target's in quarter tones, offset so it is
+// never negative.
+fn pitch_interval(anchor: &Peak, target: &Peak) -> Option<u32> {
+    if anchor.frequency_idx == 0 || target.frequency_idx == 0 {
+        return None;
+    }
+
+    let octaves = (target.frequency_idx as f64 / anchor.frequency_idx as f64).log2();
+    let offset = 1 << (FREQUENCY_RATIO_BITS - 1);
+    let interval = (octaves * RATIO_STEPS_PER_OCTAVE).round() as i64 + offset;
+
+    if !(0..1 << FREQUENCY_RATIO_BITS).contains(&interval) {
+        return None;
+    }
+
+    Some(interval as u32)
+}
@@ -1,102 +1,143 @@
 // file: src/recognizer/wav.rs
 
-const STARTING_INDEX: i32 = 0;
-
 use crate::recognizer::declarations::{FILE_NOT_FOUND, INCOMPATIBLE_FILE_ERROR};
-use hound::WavSpec;
+use crate::recognizer::fingerprint::CHUNK_FRAMES;
+use crate::recognizer::loudness::{LevelAnalysis, LoudnessMeter};
+use hound::{WavReader, WavSpec};
 use std::fs::File;
+use std::io::BufReader;
 
 pub struct WavInfo {
     pub spec: WavSpec,
     pub duration_sec: f64,
-    pub left_channel_samples: Vec<f64>,
-    pub right_channel_samples: Vec<f64>,
+    pub level: Option<LevelAnalysis>, // once measured by `WavStream::analyse_level`
+}
+
+/// Reads a .wav file a fixed number of samples at a time, so memory use does not depend on the
+/// length of the recording.
+pub struct WavStream {
+    pub info: WavInfo,
+    wav_reader: WavReader<BufReader<File>>,
 }
 
-/// Returns metadata and L-R channel samples of a .wav file given a String path on success.
-pub fn get_wav_info(file_path: &String) -> Result<WavInfo, u8> {
-    // Check the file exists and is in the .wav format
-    match File::open(&file_path) {
-        Ok(mut file) => {
-            // If the function errors, then the file is definitely not a .wav
-            match hound::read_wave_header(&mut file) {
-                Ok(_size) => file,
-                Err(_) => {
-                    eprintln!("File `{}` was not in .wav format!", file_path);
-                    return Err(INCOMPATIBLE_FILE_ERROR);
+impl WavStream {
+    /// Opens a .wav file given a String path, checking it is a format this program can decode.
+    pub fn open(file_path: &str) -> Result<WavStream, u8> {
+        // Check the file exists and is in the .wav format
+        match File::open(file_path) {
+            Ok(mut file) => {
+                // If the function errors, then the file is definitely not a .wav
+                match hound::read_wave_header(&mut file) {
+                    Ok(_size) => file,
+                    Err(_) => {
+                        eprintln!("File `{}` was not in .wav format!", file_path);
+                        return Err(INCOMPATIBLE_FILE_ERROR);
+                    }
                 }
             }
+            Err(_) => {
+                return Err(FILE_NOT_FOUND);
+            }
+        };
+
+        let wav_reader: WavReader<_> = match WavReader::open(file_path) {
+            Ok(wav_reader) => wav_reader,
+            Err(_) => {
+                return Err(INCOMPATIBLE_FILE_ERROR);
+            }
+        };
+
+        let spec = wav_reader.spec();
+
+        if spec.bits_per_sample != 16 {
+            eprintln!("WAV bits_per_sample unsupported. Expected 16-bits");
+            return Err(INCOMPATIBLE_FILE_ERROR);
         }
-        Err(_) => {
-            return Err(FILE_NOT_FOUND);
-        }
-    };
 
-    let mut wav_reader: hound::WavReader<_> = match hound::WavReader::open(&file_path) {
-        Ok(wav_reader) => wav_reader,
-        Err(_) => {
+        if spec.channels == 0 || spec.channels > 2 {
+            eprintln!("WAV channels unsupported. Expected 1 or 2");
             return Err(INCOMPATIBLE_FILE_ERROR);
         }
-    };
 
-    let spec = wav_reader.spec();
+        let duration_sec: f64 = wav_reader.duration() as f64 / spec.sample_rate as f64;
 
-    if spec.bits_per_sample != 16 {
-        eprintln!("WAV bits_per_sample unsupported. Expected 16-bits");
-        return Err(INCOMPATIBLE_FILE_ERROR);
+        Ok(WavStream {
+            info: WavInfo {
+                spec,
+                duration_sec,
+                level: None,
+            },
+            wav_reader,
+        })
     }
 
-    if spec.channels > 2 {
-        eprintln!("WAV channels unsupported. Expected 1 or 2");
-        return Err(INCOMPATIBLE_FILE_ERROR);
-    }
+    /// Reads the whole file to measure its level, recording it in `info.level`, then rewinds so
+    /// the samples can be read again from the start.
+    pub fn analyse_level(&mut self) -> Result<LevelAnalysis, u8> {
+        let mut meter = LoudnessMeter::new(self.info.spec.sample_rate);
+
+        while let Some((left_samples, right_samples)) = self.next_chunk(CHUNK_FRAMES) {
+            meter.push(&left_samples, &right_samples);
+        }
+
+        if self.wav_reader.seek(0).is_err() {
+            eprintln!("Could not rewind the .wav file after measuring its level");
+            return Err(INCOMPATIBLE_FILE_ERROR);
+        }
 
-    let duration_sec: f64 = wav_reader.duration() as f64 / spec.sample_rate as f64;
+        let level: LevelAnalysis = meter.finish();
+        self.info.level = Some(level.clone());
 
-    let all_samples_iter = wav_reader
-        .samples::<i16>()
-        .filter_map(Result::ok)
-        .into_iter()
-        .map(|x: i16| x as f64);
+        Ok(level)
+    }
 
-    let left_channel_samples: Vec<f64>;
+    /// Returns the interleaved samples of (at most) `frame_count` frames from `start_frame`, or
+    /// `None` if the file cannot be read there.
+    pub fn read_frames(&mut self, start_frame: u32, frame_count: u32) -> Option<Vec<i16>> {
+        let channels = self.info.spec.channels as usize;
 
-    // If there is one channel, then let the left channel hold all the samples and right be an
-    // empty Vec.
-    if spec.channels == 1 {
-        left_channel_samples = all_samples_iter.collect();
+        self.wav_reader.seek(start_frame).ok()?;
 
-        return Ok(WavInfo {
-            spec,
-            duration_sec,
-            left_channel_samples,
-            right_channel_samples: Vec::new(),
-        });
+        self.wav_reader
+            .samples::<i16>()
+            .take(frame_count as usize * channels)
+            .collect::<Result<Vec<i16>, _>>()
+            .ok()
     }
 
-    if spec.channels == 2 {
-        let right_channel_samples: Vec<f64>;
+    /// Returns the next (at most) `max_frames` samples of the left and right channels, or `None`
+    /// once the file is exhausted. For a mono file, the left channel holds all the samples and
+    /// the right is empty.
+    pub fn next_chunk(&mut self, max_frames: usize) -> Option<(Vec<f64>, Vec<f64>)> {
+        let channels = self.info.spec.channels as usize;
+
+        let mut left_channel_samples: Vec<f64> = Vec::with_capacity(max_frames);
+        let mut right_channel_samples: Vec<f64> = Vec::with_capacity(max_frames * (channels - 1));
 
-        // Channel data is interleaved (e.g., Bytes 1 and 2 are for the left channel, Bytes 3 and 4
-        // are for the right channel. So, the first 16 bits (Bytes 1 & 2) will be for the left
+        // Channel data is interleaved (e.g., Bytes 1 and 2 are for the left channel, Bytes 3 and
+        // 4 are for the right channel. So, the first 16 bits (Bytes 1 & 2) will be for the left
         // channel, the next 16 for the right channel).
 
         // With zero-indexing, if `index` is even, then it belongs to the left channel, right
         // channel if odd.
-        let mut index: i32 = STARTING_INDEX;
-        (left_channel_samples, right_channel_samples) = all_samples_iter.partition(|_: &f64| {
-            let bool_return: bool = index % 2 == 0;
-            index += 1;
-            bool_return
-        });
-
-        return Ok(WavInfo {
-            spec,
-            duration_sec,
-            left_channel_samples,
-            right_channel_samples,
-        });
-    }
+        for (index, sample) in self
+            .wav_reader
+            .samples::<i16>()
+            .take(max_frames * channels)
+            .filter_map(Result::ok)
+            .enumerate()
+        {
+            if channels == 1 || index % 2 == 0 {
+                left_channel_samples.push(sample as f64);
+            } else {
+                right_channel_samples.push(sample as f64);
+            }
+        }
 
-    Err(INCOMPATIBLE_FILE_ERROR)
+        if left_channel_samples.is_empty() {
+            return None;
+        }
+
+        Some((left_channel_samples, right_channel_samples))
+    }
 }
@@ -0,0 +1,199 @@
+// file: src/remote/client_utils.rs
+// purpose: uses a library served by `serve` in place of the local database. Recordings are
+// fingerprinted here with the server's profile and only their fingerprints are sent, so adding a
+// song or identifying a snippet moves a few kilobytes rather than the audio.
+
+use crate::db::db_utils::Song;
+use crate::ingest::ingest_utils::{self, FileOutcome, IngestSummary};
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::{FILE_NOT_FOUND, SERVER_ERROR};
+use crate::recognizer::fingerprint_file::FingerprintFile;
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::shazam::Match;
+use crate::remote::server_utils::{ErrorReply, Identification};
+use reqwest::RequestBuilder;
+use serde::de::DeserializeOwned;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// A connection to the library served at a URL.
+pub struct LibraryClient {
+    http: reqwest::Client,
+    server_url: String,
+}
+
+impl LibraryClient {
+    pub fn new(server_url: &str) -> LibraryClient {
+        LibraryClient {
+            http: reqwest::Client::new(),
+            server_url: server_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// The profile the library's songs were fingerprinted with, which every fingerprint sent to
+    /// it must be made with.
+    pub async fn profile(&self) -> Result<FingerprintConfig, u8> {
+        let response = self.send(self.http.get(self.url("/profile"))).await?;
+
+        match response.text().await {
+            Ok(profile) => FingerprintConfig::from_saved_toml(&profile, &self.library_name()),
+            Err(_) => Err(self.bad_reply()),
+        }
+    }
+
+    pub async fn songs(&self) -> Result<Vec<Song>, u8> {
+        self.reply(self.http.get(self.url("/songs"))).await
+    }
+
+    /// Add the song of a fingerprint file to the library, returning whether it was added or why
+    /// it was skipped.
+    pub async fn add_song(&self, fingerprint_file: &FingerprintFile) -> Result<FileOutcome, u8> {
+        let request = self
+            .http
+            .post(self.url("/songs"))
+            .body(fingerprint_file.to_bytes());
+
+        self.reply(request).await
+    }
+
+    /// The library's matches for the snippet a fingerprint file was made from, best first.
+    pub async fn identify_fingerprint(
+        &self,
+        fingerprint_file: &FingerprintFile,
+    ) -> Result<Vec<Match>, u8> {
+        let request = self
+            .http
+            .post(self.url("/identify/fingerprint"))
+            .body(fingerprint_file.to_bytes());
+
+        Ok(self.reply::<Identification>(request).await?.matches)
+    }
+
+    /// The library's matches for the snippet in the audio file at `file_path`, searched for at
+    /// other playback speeds as `--speed-tolerance` and `--rpm-conversion` say. The audio itself
+    /// is sent, as a fingerprint is only made at one speed.
+    pub async fn identify_audio(
+        &self,
+        file_path: &str,
+        speed_tolerance: f64,
+        rpm_conversion: bool,
+    ) -> Result<Vec<Match>, u8> {
+        let Ok(audio) = std::fs::read(file_path) else {
+            eprintln!("ERROR: Cannot open file: `{}`", file_path);
+            return Err(FILE_NOT_FOUND);
+        };
+
+        let request = self
+            .http
+            .post(self.url("/identify/audio"))
+            .query(&[
+                ("speed_tolerance", speed_tolerance.to_string()),
+                ("rpm_conversion", rpm_conversion.to_string()),
+            ])
+            .body(audio);
+
+        Ok(self.reply::<Identification>(request).await?.matches)
+    }
+
+    /// Fingerprint each of `audio_files` with `config`, working on at most `worker_count` files
+    /// at a time, and add each one's song to the library as soon as it is fingerprinted, printing
+    /// a status line for it as `add` does.
+    pub async fn add_songs(
+        &self,
+        audio_files: Vec<PathBuf>,
+        worker_count: usize,
+        config: FingerprintConfig,
+        preprocessing: Preprocessing,
+    ) -> IngestSummary {
+        let mut summary = IngestSummary::default();
+        let start_time = Instant::now();
+
+        let (sender, mut receiver) =
+            mpsc::channel::<(PathBuf, Result<FingerprintFile, u8>)>(worker_count.max(1));
+        let fingerprinting = tokio::task::spawn_blocking(move || {
+            ingest_utils::fingerprint_files(
+                &audio_files,
+                worker_count,
+                &config,
+                &preprocessing,
+                |audio_file, fingerprinted| {
+                    sender
+                        .blocking_send((audio_file.to_path_buf(), fingerprinted))
+                        .is_ok()
+                },
+            )
+        });
+
+        while let Some((audio_file, fingerprinted)) = receiver.recv().await {
+            let outcome: FileOutcome = match fingerprinted {
+                Ok(fingerprint_file) if fingerprint_file.song.is_none() => {
+                    FileOutcome::Skipped("could not parse title, artist and album".to_string())
+                }
+                Ok(fingerprint_file) => match self.add_song(&fingerprint_file).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => FileOutcome::Failed("the server could not add it".to_string()),
+                },
+                Err(_) => FileOutcome::Failed("could not generate fingerprint".to_string()),
+            };
+
+            println!("{} {}", outcome.status(), audio_file.display());
+            summary.record(audio_file, outcome);
+        }
+
+        if !matches!(fingerprinting.await, Ok(Ok(_))) {
+            eprintln!("ERROR: Could not fingerprint the files to add");
+        }
+        summary.elapsed = start_time.elapsed();
+
+        summary
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server_url, path)
+    }
+
+    // Send a request, failing with the code the server replied with if it did not succeed.
+    async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response, u8> {
+        let Ok(response) = request.send().await else {
+            eprintln!("ERROR: Cannot reach the server at `{}`", self.server_url);
+            return Err(SERVER_ERROR);
+        };
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        match response.json::<ErrorReply>().await {
+            Ok(reply) => {
+                eprintln!("ERROR: {} ({})", reply.error, self.server_url);
+                Err(reply.code)
+            }
+            Err(_) => {
+                eprintln!("ERROR: `{}` replied {}", self.server_url, status);
+                Err(SERVER_ERROR)
+            }
+        }
+    }
+
+    // Send a request and read the JSON it is answered with.
+    async fn reply<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, u8> {
+        match self.send(request).await?.json::<T>().await {
+            Ok(reply) => Ok(reply),
+            Err(_) => Err(self.bad_reply()),
+        }
+    }
+
+    fn library_name(&self) -> String {
+        format!("The library at `{}`", self.server_url)
+    }
+
+    fn bad_reply(&self) -> u8 {
+        eprintln!(
+            "ERROR: `{}` sent a reply that cannot be read",
+            self.server_url
+        );
+        SERVER_ERROR
+    }
+}
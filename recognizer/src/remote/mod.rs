@@ -0,0 +1,3 @@
+// file: src/remote/mod.rs
+pub mod client_utils;
+pub mod server_utils;
@@ -0,0 +1,519 @@
+// file: src/remote/server_utils.rs
+// purpose: serves the library over HTTP, so snippets can be identified, songs added and the
+// library listed from other machines without a copy of the database. Clients fingerprint their
+// recordings with the library's profile (`GET /profile`) and send only the fingerprint; audio is
+// accepted too, for clients that cannot fingerprint it themselves.
+
+use crate::db::db_utils::{self, Song};
+use crate::db::index_file_utils;
+use crate::db::index_utils::{self, FingerprintIndex, IndexKind};
+use crate::db::profile_utils;
+use crate::ingest::ingest_utils::{self, FileOutcome};
+use crate::recognizer::config::FingerprintConfig;
+use crate::recognizer::declarations::{
+    DATABASE_INSERT_ERROR, FILE_NOT_FOUND, FINGERPRINT_FILE_ERROR, INCOMPATIBLE_FILE_ERROR,
+    INVALID_PROFILE_ERROR, INVALID_REQUEST_ERROR, MATCH_SCORE_THRESHOLD, NO_SONG_MATCH_ERROR,
+    PROFILE_MISMATCH_ERROR, SERVER_ERROR, SPECTROGRAM_GENERATION_FAILURE,
+};
+use crate::recognizer::fingerprint;
+use crate::recognizer::fingerprint_file::FingerprintFile;
+use crate::recognizer::preprocess::Preprocessing;
+use crate::recognizer::shazam::{self, MAX_SPEED_TOLERANCE, Match};
+use crate::recognizer::spectrogram::Peak;
+use crate::recognizer::wav::WavStream;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Query, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+use std::time::Instant;
+use tempfile::{NamedTempFile, TempDir};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+// An identification replies with at most this many matches, best first
+const MAX_MATCHES: usize = 10;
+
+// Uploads may be this large: about ten minutes of CD-quality stereo audio
+const MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
+/// The reply to an identification: the best matches, best first, and the best of them again if
+/// it is likely to be the snippet's song.
+#[derive(Serialize, Deserialize)]
+pub struct Identification {
+    pub matches: Vec<Match>,
+    pub best_match: Option<Match>,
+}
+
+/// The reply to a request that failed, with the code the command line would have exited with.
+#[derive(Serialize, Deserialize)]
+pub struct ErrorReply {
+    pub error: String,
+    pub code: u8,
+}
+
+/// How `POST /identify/audio` searches other playback speeds, as `--speed-tolerance` and
+/// `--rpm-conversion` do.
+#[derive(Deserialize)]
+struct SpeedSearch {
+    #[serde(default)]
+    speed_tolerance: f64,
+    #[serde(default)]
+    rpm_conversion: bool,
+}
+
+// How the library is fingerprinted and searched, shared by every request.
+struct Library {
+    config: FingerprintConfig,
+    preprocessing: Preprocessing,
+    index_kind: IndexKind,
+    index_file: PathBuf,
+    // Opened once and shared by every identification; replaced whenever a song is added
+    index: RwLock<Box<dyn FingerprintIndex>>,
+    // Uploaded audio is written here, where only this process's user can see it
+    upload_dir: TempDir,
+    // Songs are added one at a time, so two requests never update the index file at once
+    adding: Mutex<()>,
+}
+
+impl Library {
+    // Take `config` as the profile of an empty database, and open its index.
+    fn open(
+        config: FingerprintConfig,
+        preprocessing: Preprocessing,
+        index_kind: IndexKind,
+        index_file: PathBuf,
+    ) -> Result<Library, u8> {
+        profile_utils::record_profile(&config)?;
+
+        let index: Box<dyn FingerprintIndex> =
+            index_utils::open_index(index_kind, &index_file, &config)?;
+
+        let mut upload_dir_builder = tempfile::Builder::new();
+        upload_dir_builder.prefix("recognizer-uploads-");
+        #[cfg(unix)]
+        upload_dir_builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700));
+
+        let Ok(upload_dir) = upload_dir_builder.tempdir() else {
+            eprintln!("ERROR: Cannot create a directory for uploaded audio");
+            return Err(FILE_NOT_FOUND);
+        };
+
+        Ok(Library {
+            config,
+            preprocessing,
+            index_kind,
+            index_file,
+            index: RwLock::new(index),
+            upload_dir,
+            adding: Mutex::new(()),
+        })
+    }
+
+    // The index is only ever replaced whole, so one left behind by a panicking writer is intact.
+    fn index(&self) -> RwLockReadGuard<'_, Box<dyn FingerprintIndex>> {
+        self.index.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Open the index again, so it covers the songs added since it was opened.
+    fn refresh_index(&self) -> Result<(), u8> {
+        let index: Box<dyn FingerprintIndex> =
+            index_utils::open_index(self.index_kind, &self.index_file, &self.config)?;
+        *self.index.write().unwrap_or_else(PoisonError::into_inner) = index;
+
+        Ok(())
+    }
+}
+
+// A request that failed with one of the codes in `declarations`.
+struct RequestError(u8);
+
+impl From<u8> for RequestError {
+    fn from(code: u8) -> RequestError {
+        RequestError(code)
+    }
+}
+
+impl IntoResponse for RequestError {
+    fn into_response(self) -> Response {
+        let (status, error) = match self.0 {
+            INCOMPATIBLE_FILE_ERROR => (
+                StatusCode::BAD_REQUEST,
+                "the audio is not a WAV file that can be read",
+            ),
+            SPECTROGRAM_GENERATION_FAILURE => (
+                StatusCode::BAD_REQUEST,
+                "the audio could not be analysed; it may be too short",
+            ),
+            FINGERPRINT_FILE_ERROR => (
+                StatusCode::BAD_REQUEST,
+                "not a fingerprint file, or a truncated or corrupt one",
+            ),
+            INVALID_PROFILE_ERROR => (
+                StatusCode::BAD_REQUEST,
+                "the fingerprint's profile is invalid",
+            ),
+            PROFILE_MISMATCH_ERROR => (
+                StatusCode::CONFLICT,
+                "the fingerprint was made with another profile than the library's, which \
+                 `GET /profile` returns",
+            ),
+            INVALID_REQUEST_ERROR => (
+                StatusCode::BAD_REQUEST,
+                "`speed_tolerance` must be a percentage from 0 to 20",
+            ),
+            NO_SONG_MATCH_ERROR => (StatusCode::NOT_FOUND, "no song matched"),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "the server could not complete the request; see its log",
+            ),
+        };
+
+        let reply = ErrorReply {
+            error: error.to_string(),
+            code: self.0,
+        };
+        (status, Json(reply)).into_response()
+    }
+}
+
+/// Serve the library on `bind_address` until the process is stopped. Snippets sent as audio are
+/// fingerprinted with `config` after `preprocessing`, and every snippet's hashes are looked up in
+/// the index of kind `index_kind`, opened when the server starts and again whenever a song is
+/// added (a `File` index is read from `index_file`, which is kept up to date as songs are added).
+/// An empty database takes `config` as its profile, so every song added is fingerprinted the
+/// same way.
+pub async fn serve(
+    bind_address: &str,
+    config: FingerprintConfig,
+    preprocessing: Preprocessing,
+    index_kind: IndexKind,
+    index_file: PathBuf,
+) -> Result<(), u8> {
+    let library = Library::open(config, preprocessing, index_kind, index_file)?;
+
+    let Ok(listener) = TcpListener::bind(bind_address).await else {
+        eprintln!("ERROR: Cannot listen on `{}`", bind_address);
+        return Err(SERVER_ERROR);
+    };
+    match listener.local_addr() {
+        Ok(address) => println!("Serving the library at http://{}", address),
+        Err(_) => println!("Serving the library at http://{}", bind_address),
+    }
+
+    if axum::serve(listener, router(library)).await.is_err() {
+        eprintln!("ERROR: The server stopped unexpectedly");
+        return Err(SERVER_ERROR);
+    }
+
+    Ok(())
+}
+
+// The routes of the library's API.
+fn router(library: Library) -> Router {
+    Router::new()
+        .route("/profile", get(get_profile))
+        .route("/songs", get(list_songs).post(add_song))
+        .route("/identify/fingerprint", post(identify_fingerprint))
+        .route("/identify/audio", post(identify_audio))
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+        .layer(middleware::from_fn(log_request))
+        .with_state(Arc::new(library))
+}
+
+// A line for every request: what was asked for, the status replied with and how long it took.
+async fn log_request(request: Request, next: Next) -> Response {
+    let start_time = Instant::now();
+    let method = request.method().clone();
+    let path: String = request.uri().path().to_string();
+
+    let response: Response = next.run(request).await;
+    println!(
+        "{} {} {} ({} ms)",
+        method,
+        path,
+        response.status().as_u16(),
+        start_time.elapsed().as_millis()
+    );
+
+    response
+}
+
+// GET /profile: the TOML of the profile every fingerprint sent must be made with.
+async fn get_profile(State(library): State<Arc<Library>>) -> String {
+    library.config.to_toml()
+}
+
+// GET /songs: every song in the library.
+async fn list_songs() -> Result<Json<Vec<Song>>, RequestError> {
+    Ok(Json(db_utils::get_all_songs()?))
+}
+
+// POST /songs: add the song of the fingerprint file in the body, as `import` does. Replies 201
+// if it was added and 200 if it was skipped, e.g. because it is in the library already.
+async fn add_song(
+    State(library): State<Arc<Library>>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<FileOutcome>), RequestError> {
+    let fingerprint_file = FingerprintFile::from_bytes(&body, "uploaded fingerprint")?;
+
+    // `import_fingerprint` would only report a mismatch as a failure; checked here, the client
+    // is told which profile to use
+    profile_utils::check_profile(&fingerprint_file.profile)?;
+
+    // Uploads are journaled by song, as there is no path to key them by
+    let journal_key: String = match &fingerprint_file.song {
+        Some(song) => format!("upload:{}_{}_{}", song.title, song.artist, song.album),
+        None => "upload:".to_string(),
+    };
+
+    let _adding = library.adding.lock().await;
+
+    match ingest_utils::import_fingerprint(&journal_key, fingerprint_file, true).await {
+        FileOutcome::Failed(reason) => {
+            eprintln!("ERROR: Could not add `{}`: {}", journal_key, reason);
+            Err(RequestError(DATABASE_INSERT_ERROR))
+        }
+        outcome @ FileOutcome::Added(_) => {
+            // The song is in the database either way; until the index is rebuilt, the server
+            // goes on identifying against the index it has
+            if index_file_utils::update_index_file_after_adding(
+                &library.index_file,
+                &library.config,
+            ) && library.refresh_index().is_err()
+            {
+                eprintln!(
+                    "WARNING: Could not reload the index; `{}` is not identified yet",
+                    journal_key
+                );
+            }
+            Ok((StatusCode::CREATED, Json(outcome)))
+        }
+        outcome => Ok((StatusCode::OK, Json(outcome))),
+    }
+}
+
+// POST /identify/fingerprint: identify the snippet whose fingerprint file is in the body.
+async fn identify_fingerprint(
+    State(library): State<Arc<Library>>,
+    body: Bytes,
+) -> Result<Json<Identification>, RequestError> {
+    let fingerprint_file = FingerprintFile::from_bytes(&body, "uploaded fingerprint")?;
+    profile_utils::check_profile(&fingerprint_file.profile)?;
+
+    identify(move || {
+        shazam::find_matches_in_fingerprint(fingerprint_file.fingerprint, library.index().as_ref())
+    })
+    .await
+}
+
+// POST /identify/audio?speed_tolerance=PERCENT&rpm_conversion=BOOL: identify the snippet whose
+// WAV file is in the body, as `--id-song` does.
+async fn identify_audio(
+    State(library): State<Arc<Library>>,
+    Query(speed_search): Query<SpeedSearch>,
+    body: Bytes,
+) -> Result<Json<Identification>, RequestError> {
+    // As `--speed-tolerance` is; a wider search would mostly find false matches
+    if !(0. ..=100. * MAX_SPEED_TOLERANCE).contains(&speed_search.speed_tolerance) {
+        return Err(RequestError(INVALID_REQUEST_ERROR));
+    }
+
+    identify(move || {
+        // Recordings are read from files, so the upload is written to one first
+        let upload: NamedTempFile = write_upload(&library, &body)?;
+
+        let mut wav_stream = WavStream::open(&upload.path().to_string_lossy())?;
+        let peaks: Vec<Vec<Peak>> =
+            fingerprint::audio_peaks(&mut wav_stream, &library.config, &library.preprocessing)?;
+        let playback_speeds: Vec<f64> = shazam::playback_speeds(
+            speed_search.speed_tolerance / 100.,
+            speed_search.rpm_conversion,
+        );
+
+        shazam::find_matches_at_speeds(
+            &peaks,
+            &library.config,
+            &playback_speeds,
+            library.index().as_ref(),
+        )
+    })
+    .await
+}
+
+// Find a snippet's matches with `find_matches` on the blocking thread pool, as it is CPU-bound,
+// and reply with the best of them.
+async fn identify(
+    find_matches: impl FnOnce() -> Result<Vec<Match>, u8> + Send + 'static,
+) -> Result<Json<Identification>, RequestError> {
+    let mut matches: Vec<Match> = match tokio::task::spawn_blocking(find_matches).await {
+        Ok(found) => found?,
+        Err(_) => return Err(RequestError(SERVER_ERROR)),
+    };
+    matches.truncate(MAX_MATCHES);
+
+    let best_match: Option<Match> = matches
+        .first()
+        .filter(|best_match| best_match.score >= MATCH_SCORE_THRESHOLD)
+        .cloned();
+
+    Ok(Json(Identification {
+        matches,
+        best_match,
+    }))
+}
+
+// Write uploaded audio to a new file in the library's upload directory, which is removed when
+// it is dropped. The file's name is random and it is created only if nothing is there, so
+// nothing else can be written or read in its place.
+fn write_upload(library: &Library, bytes: &[u8]) -> Result<NamedTempFile, u8> {
+    let written = tempfile::Builder::new()
+        .prefix("upload-")
+        .suffix(".wav")
+        .tempfile_in(library.upload_dir.path())
+        .and_then(|mut upload| {
+            upload.write_all(bytes)?;
+            Ok(upload)
+        });
+
+    match written {
+        Ok(upload) => Ok(upload),
+        Err(_) => {
+            eprintln!(
+                "ERROR: Cannot write uploaded audio to `{}`",
+                library.upload_dir.path().display()
+            );
+            Err(FILE_NOT_FOUND)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::journal_utils;
+    use crate::recognizer::fingerprint_file::SongInfo;
+    use crate::recognizer::test_audio::{self, SAMPLE_RATE};
+    use crate::remote::client_utils::LibraryClient;
+
+    fn fingerprint_file(
+        samples: &[f64],
+        song: Option<SongInfo>,
+        config: &FingerprintConfig,
+    ) -> FingerprintFile {
+        FingerprintFile {
+            profile: config.clone(),
+            song,
+            duration_ms: (samples.len() as f64 * 1000. / SAMPLE_RATE as f64).round() as i32,
+            fingerprint: test_audio::fingerprint_samples(
+                samples,
+                0,
+                config,
+                &Preprocessing::default(),
+            ),
+        }
+    }
+
+    fn write_wav(file_path: &std::path::Path, samples: &[f64]) {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(file_path, spec).unwrap();
+        for sample in samples {
+            writer.write_sample(*sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    // The only test using a database, as the database is chosen by the process's environment
+    #[tokio::test]
+    async fn client_adds_lists_and_identifies_through_the_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let database: PathBuf = dir.path().join("library.db");
+        // SAFETY: no other test reads or writes the environment
+        unsafe { std::env::set_var("DATABASE_URL", &database) };
+
+        // An in-memory index starts out empty, so a match shows it was refreshed by the upload
+        let config = FingerprintConfig::default();
+        let library = Library::open(
+            config.clone(),
+            Preprocessing::default(),
+            IndexKind::Memory,
+            dir.path().join("library.db.idx"),
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(library)).await });
+        let client = LibraryClient::new(&format!("http://{}/", address));
+
+        assert_eq!(client.profile().await.unwrap(), config);
+
+        let song: Vec<f64> = test_audio::synthetic_song(20.0, 50);
+        let song_info = SongInfo {
+            title: "Blue Moon".to_string(),
+            artist: "Ella Fitzgerald".to_string(),
+            album: "Songbook".to_string(),
+        };
+
+        // Stored as an interrupted upload of the song would have left it, so adding it resumes
+        // rather than looking its track up on Spotify
+        journal_utils::store_song_for_import(
+            "upload:Blue Moon_Ella Fitzgerald_Songbook",
+            &song_info.title,
+            &song_info.artist,
+            &song_info.album,
+            None,
+        )
+        .unwrap();
+
+        let song_file = fingerprint_file(&song, Some(song_info), &config);
+        assert!(matches!(
+            client.add_song(&song_file).await.unwrap(),
+            FileOutcome::Added(_)
+        ));
+        assert!(matches!(
+            client.add_song(&song_file).await.unwrap(),
+            FileOutcome::Skipped(_)
+        ));
+
+        let songs: Vec<Song> = client.songs().await.unwrap();
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].title, "Blue Moon");
+
+        let clip: &[f64] = &song[7 * SAMPLE_RATE as usize..13 * SAMPLE_RATE as usize];
+        let matches: Vec<Match> = client
+            .identify_fingerprint(&fingerprint_file(clip, None, &config))
+            .await
+            .unwrap();
+        assert_eq!(matches[0].name, "Blue Moon");
+        assert!(matches[0].score >= MATCH_SCORE_THRESHOLD);
+
+        let clip_path: PathBuf = dir.path().join("clip.wav");
+        write_wav(&clip_path, clip);
+        let clip_path: &str = &clip_path.to_string_lossy();
+
+        let matches: Vec<Match> = client.identify_audio(clip_path, 0., false).await.unwrap();
+        assert_eq!(matches[0].name, "Blue Moon");
+
+        for speed_tolerance in [-1., 25., f64::NAN] {
+            assert_eq!(
+                client
+                    .identify_audio(clip_path, speed_tolerance, false)
+                    .await
+                    .err(),
+                Some(INVALID_REQUEST_ERROR)
+            );
+        }
+    }
+}
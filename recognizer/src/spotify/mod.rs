@@ -1,2 +1,2 @@
 // file: src/spotify/mod.rs
-pub mod spotify_utils;
\ No newline at end of file
+pub mod spotify_utils;
@@ -14,16 +14,15 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use webbrowser;
 
-/// Returns a SearchResult that may contain the top 5 matching Tracks on Spotify for a given query
-async fn search_tracks(spotify: &AuthCodeSpotify, track_query: &str) -> ClientResult<SearchResult> {
+/// Returns a SearchResult that may contain the top 5 matching Tracks on Spotify for a given query.
+/// Unless `interactive`, the search fails rather than asking the user to sign in.
+async fn search_tracks(
+    spotify: &AuthCodeSpotify,
+    track_query: &str,
+    interactive: bool,
+) -> ClientResult<SearchResult> {
     // Obtain a token before submitting a request
-    match authorize_client(&spotify).await {
-        Ok(()) => {}
-        Err(client_error) => {
-            eprintln!("{:?}", client_error);
-            return Err(client_error);
-        }
-    }
+    authorize_client(spotify, interactive).await?;
 
     let result: ClientResult<_> = spotify
         .search(
@@ -41,58 +40,72 @@ async fn search_tracks(spotify: &AuthCodeSpotify, track_query: &str) -> ClientRe
 
 //noinspection RsUnresolvedMethod
 /// Returns the unique Spotify URI for the top result of a search for a Track based on a name and
-/// artist if the track exists.
+/// artist if the track exists, asking the user to sign in if need be.
 pub async fn get_track_uri(track_name: String, artist: String, album: String) -> Option<String> {
-    let spotify: AuthCodeSpotify = get_spotify_client();
-
-    let query = format!("track:{} artist:{} album:{}", track_name, artist, album);
-
-    let result: ClientResult<_> = search_tracks(&spotify, query.as_str()).await;
-
-    let track_results: SearchResult = match result {
-        Ok(track_results) => track_results,
-        Err(err) => {
-            eprintln!("ERROR: Could not find URI for song `{}`!", track_name);
-            eprintln!("{:?}", err);
-            return None;
+    match search_track_uri(&track_name, &artist, &album, true).await {
+        Ok(track_uri) => {
+            println!("Found Track! Name: {}, URI: {}", track_name, track_uri);
+            Some(track_uri)
         }
-    };
+        Err(reason) => {
+            eprintln!(
+                "ERROR: Could not find URI for song `{}`: {}",
+                track_name, reason
+            );
+            None
+        }
+    }
+}
 
-    let desired_track: FullTrack = match track_results {
-        SearchResult::Tracks(page_of_tracks) => {
-            let found_tracks = page_of_tracks.items;
-            let mut desired_track: Option<FullTrack> = None;
+/// Returns the Spotify URI of a track as `get_track_uri` does, or why there is none, without
+/// printing anything or asking the user to sign in: without a token from `sign_in`, it fails.
+pub async fn find_track_uri(track_name: &str, artist: &str, album: &str) -> Result<String, String> {
+    search_track_uri(track_name, artist, album, false).await
+}
 
-            for full_track in found_tracks {
-                // HACK: this might not work if two tracks are different because of their case
-                // This was done because the result of "Bruises Off the Peach" was "Bruises [o]ff the
-                // Peach"
-                let found_name: String = full_track.name.to_lowercase();
+/// Sign the user in to Spotify if no token is cached, so later searches need not ask.
+pub async fn sign_in() -> Result<(), u8> {
+    authorize_client(&get_spotify_client(), true)
+        .await
+        .map_err(|e: ClientError| {
+            eprintln!("{:?}", e);
+            SPOTIFY_ERROR
+        })
+}
 
-                if found_name.contains(&track_name.to_lowercase()) {
-                    desired_track = Some(full_track);
-                    break;
-                }
-            }
+async fn search_track_uri(
+    track_name: &str,
+    artist: &str,
+    album: &str,
+    interactive: bool,
+) -> Result<String, String> {
+    let spotify: AuthCodeSpotify = get_spotify_client();
 
-            if desired_track.is_none() {
-                eprintln!("No Spotify tracks found for song: `{}`!", track_name);
-                return None;
-            }
+    let query = format!("track:{} artist:{} album:{}", track_name, artist, album);
 
-            desired_track.unwrap()
-        }
-        _ => return None,
-    };
+    let track_results: SearchResult = search_tracks(&spotify, query.as_str(), interactive)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
 
-    let track_uri = desired_track.id.unwrap();
-    println!(
-        "Found Track! Name: {}, URI: {}",
-        desired_track.name,
-        track_uri.to_string()
-    );
+    let SearchResult::Tracks(page_of_tracks) = track_results else {
+        return Err("Spotify did not reply with tracks".to_string());
+    };
 
-    Some(track_uri.to_string())
+    // HACK: this might not work if two tracks are different because of their case
+    // This was done because the result of "Bruises Off the Peach" was "Bruises [o]ff the
+    // Peach"
+    page_of_tracks
+        .items
+        .into_iter()
+        .find(|full_track| {
+            full_track
+                .name
+                .to_lowercase()
+                .contains(&track_name.to_lowercase())
+        })
+        .and_then(|full_track: FullTrack| full_track.id)
+        .map(|track_id| track_id.to_string())
+        .ok_or_else(|| "no Spotify tracks found".to_string())
 }
 
 /// Given a name and artist, play a track on Spotify. Returns the Spotify URI for that track or
@@ -161,10 +174,10 @@ fn get_spotify_client() -> AuthCodeSpotify {
 }
 
 /// Plays a song on Spotify with a User's active device given a Spotify URI.
-pub async fn play_song_from_uri(track_uri: &String) -> Result<(), u8> {
+pub async fn play_song_from_uri(track_uri: &str) -> Result<(), u8> {
     let spotify = get_spotify_client();
 
-    do_play_song(&spotify, track_uri.as_str())
+    do_play_song(&spotify, track_uri)
         .await
         .map_err(|e: ClientError| {
             eprintln!("{:?}", e);
@@ -174,7 +187,7 @@ pub async fn play_song_from_uri(track_uri: &String) -> Result<(), u8> {
 
 /// Performs request for play_song()
 async fn do_play_song(spotify: &AuthCodeSpotify, track_uri: &str) -> ClientResult<()> {
-    authorize_client(spotify).await?;
+    authorize_client(spotify, true).await?;
 
     // Before trying to play the song, ensure that there is an active device
     match spotify.device().await {
@@ -194,16 +207,14 @@ async fn do_play_song(spotify: &AuthCodeSpotify, track_uri: &str) -> ClientResul
 
     let uris = [PlayableId::Track(TrackId::from_uri(track_uri).unwrap())];
 
-    spotify
-        .start_uris_playback(uris.into_iter(), None, None, None)
-        .await
+    spotify.start_uris_playback(uris, None, None, None).await
 }
 
 //noinspection RsTypeCheck -> Linter incorrectly flags `spotify.parse_response_code(&input)`
 /// Redirect User to authentication page where they copy the URL and paste into terminal to
 /// authenticate the application.
 fn get_code_from_user(spotify: &AuthCodeSpotify, authorize_url: &str) -> ClientResult<String> {
-    match webbrowser::open(&authorize_url) {
+    match webbrowser::open(authorize_url) {
         Ok(_) => println!("Opened {} in your browser.", authorize_url),
         Err(why) => eprintln!(
             "Error when trying to open an URL in your browser: {:?}. \
@@ -223,7 +234,7 @@ fn get_code_from_user(spotify: &AuthCodeSpotify, authorize_url: &str) -> ClientR
         }
     }
 
-    match spotify.parse_response_code(&input).ok_or_else(|| 0) {
+    match spotify.parse_response_code(&input).ok_or(0) {
         Ok(code) => Ok(code),
         Err(_) => Err(ClientError::Cli(
             "Error when trying to parse the response code".to_string(),
@@ -232,8 +243,9 @@ fn get_code_from_user(spotify: &AuthCodeSpotify, authorize_url: &str) -> ClientR
 }
 
 //noinspection RsUnresolvedMethod -> Linter unnecessary flags spotify...lock()
-/// Authorize the Spotify client. Run before doing any task with the client.
-async fn authorize_client(spotify: &AuthCodeSpotify) -> ClientResult<()> {
+/// Authorize the Spotify client. Run before doing any task with the client. Without a usable
+/// cached token, the user is asked to sign in if `interactive`, and it fails otherwise.
+async fn authorize_client(spotify: &AuthCodeSpotify, interactive: bool) -> ClientResult<()> {
     let authorize_url = spotify.get_authorize_url(false)?;
 
     match spotify.read_token_cache(true).await {
@@ -251,10 +263,11 @@ async fn authorize_client(spotify: &AuthCodeSpotify) -> ClientResult<()> {
                         *spotify.get_token().lock().await.unwrap() = Some(refreshed_token);
                     }
                     // If not, prompt the user for it
+                    None if !interactive => return Err(not_signed_in()),
                     None => {
                         println!("Unable to refresh expired token from token cache");
                         println!("Trying normal way!");
-                        let code: String = get_code_from_user(&spotify, &authorize_url)?;
+                        let code: String = get_code_from_user(spotify, &authorize_url)?;
 
                         match spotify.request_token(&code).await {
                             Ok(_) => {}
@@ -268,12 +281,12 @@ async fn authorize_client(spotify: &AuthCodeSpotify) -> ClientResult<()> {
                 }
             }
         }
+        _ if !interactive => return Err(not_signed_in()),
         // Otherwise follow the usual procedure to get the token.
         _ => {
-            let code: String = get_code_from_user(&spotify, &authorize_url)?;
+            let code: String = get_code_from_user(spotify, &authorize_url)?;
 
-            if let Ok(_) = spotify.request_token(&code).await {
-            } else {
+            if spotify.request_token(&code).await.is_err() {
                 return Err(ClientError::Cli(
                     "Error when trying to retrieve the token".to_string(),
                 ));
@@ -283,3 +296,7 @@ async fn authorize_client(spotify: &AuthCodeSpotify) -> ClientResult<()> {
 
     spotify.write_token_cache().await
 }
+
+fn not_signed_in() -> ClientError {
+    ClientError::Cli("not signed in to Spotify".to_string())
+}